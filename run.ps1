@@ -1,5 +1,21 @@
 # run.ps1
 
+# Build the kernel and embed its symbol table for panic backtraces
+Write-Host "Running cargo build..."
+cargo build
+
+if ($LASTEXITCODE -ne 0) {
+    Write-Host "cargo build failed with exit code $LASTEXITCODE"
+    exit $LASTEXITCODE
+}
+
+& "$PSScriptRoot\tools\embed-symbols.ps1"
+
+if ($LASTEXITCODE -ne 0) {
+    Write-Host "embedding symbols failed with exit code $LASTEXITCODE"
+    exit $LASTEXITCODE
+}
+
 # Run `cargo bootimage`
 Write-Host "Running cargo bootimage..."
 cargo bootimage
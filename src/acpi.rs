@@ -0,0 +1,155 @@
+use core::{mem, ptr, slice};
+use spin::Mutex;
+use x86_64::PhysAddr;
+use crate::memory::phys_to_virt;
+
+pub mod fadt;
+pub mod madt;
+
+/// Root System Description Pointer, as laid out by ACPI 2.0. Version 1.0
+/// firmware only provides the fields up to `rsdt_address`.
+#[repr(C, packed)]
+#[derive(Clone, Copy)]
+struct Rsdp {
+    signature: [u8; 8],
+    checksum: u8,
+    oem_id: [u8; 6],
+    revision: u8,
+    rsdt_address: u32,
+    length: u32,
+    xsdt_address: u64,
+    extended_checksum: u8,
+    reserved: [u8; 3],
+}
+
+/// Header shared by every ACPI system description table.
+#[repr(C, packed)]
+#[derive(Clone, Copy)]
+pub struct SdtHeader {
+    pub signature: [u8; 4],
+    pub length: u32,
+    pub revision: u8,
+    pub checksum: u8,
+    pub oem_id: [u8; 6],
+    pub oem_table_id: [u8; 8],
+    pub oem_revision: u32,
+    pub creator_id: u32,
+    pub creator_revision: u32,
+}
+
+/// The RSDT or XSDT the firmware pointed us at.
+#[derive(Clone, Copy)]
+struct RootTable {
+    addr: PhysAddr,
+    /// XSDT entries are 64-bit, RSDT entries are 32-bit
+    extended: bool,
+}
+
+static ROOT_TABLE: Mutex<Option<RootTable>> = Mutex::new(None);
+
+/// Locates the RSDP and remembers the root table it points to. Returns
+/// `false` if the firmware doesn't provide ACPI tables.
+///
+/// Needs the physical memory mapping, so call it after `memory::init`.
+pub fn init() -> bool {
+    let rsdp = match find_rsdp() {
+        Some(rsdp) => rsdp,
+        None => return false,
+    };
+
+    let root = if rsdp.revision >= 2 && rsdp.xsdt_address != 0 {
+        RootTable { addr: PhysAddr::new(rsdp.xsdt_address), extended: true }
+    } else {
+        RootTable { addr: PhysAddr::new(rsdp.rsdt_address as u64), extended: false }
+    };
+
+    if !table_is_valid(root.addr) {
+        return false;
+    }
+    *ROOT_TABLE.lock() = Some(root);
+    true
+}
+
+/// Returns the physical address of the first table with the given
+/// signature, e.g. `b"APIC"` for the MADT.
+pub fn find_table(signature: &[u8; 4]) -> Option<PhysAddr> {
+    let root = (*ROOT_TABLE.lock())?;
+    let header = read_header(root.addr);
+    let entry_size = if root.extended { 8 } else { 4 };
+    let entries = (header.length as usize - mem::size_of::<SdtHeader>()) / entry_size;
+    let first_entry = phys_to_virt(root.addr).as_u64() as usize + mem::size_of::<SdtHeader>();
+
+    (0..entries)
+        .map(|i| {
+            let entry = first_entry + i * entry_size;
+            let addr = unsafe {
+                if root.extended {
+                    ptr::read_unaligned(entry as *const u64)
+                } else {
+                    ptr::read_unaligned(entry as *const u32) as u64
+                }
+            };
+            PhysAddr::new(addr)
+        })
+        .find(|&addr| read_header(addr).signature == *signature && table_is_valid(addr))
+}
+
+/// Reads the header of the table at `addr`.
+pub fn read_header(addr: PhysAddr) -> SdtHeader {
+    unsafe { ptr::read_unaligned(phys_to_virt(addr).as_ptr::<SdtHeader>()) }
+}
+
+/// Returns the whole table at `addr`, header included.
+pub fn table_bytes(addr: PhysAddr) -> &'static [u8] {
+    let length = read_header(addr).length as usize;
+    unsafe { slice::from_raw_parts(phys_to_virt(addr).as_ptr::<u8>(), length) }
+}
+
+fn table_is_valid(addr: PhysAddr) -> bool {
+    let length = read_header(addr).length as usize;
+    length >= mem::size_of::<SdtHeader>() && checksum_ok(table_bytes(addr))
+}
+
+fn checksum_ok(bytes: &[u8]) -> bool {
+    bytes.iter().fold(0u8, |sum, &b| sum.wrapping_add(b)) == 0
+}
+
+/// Searches the first KiB of the EBDA and the BIOS area below 1 MiB for the
+/// RSDP signature, as the ACPI spec tells us to.
+fn find_rsdp() -> Option<Rsdp> {
+    let ebda_segment = unsafe {
+        ptr::read_unaligned(phys_to_virt(PhysAddr::new(0x40E)).as_ptr::<u16>())
+    };
+    let ebda = (ebda_segment as u64) << 4;
+
+    let mut areas = [(0, 0), (0xE0000, 0x100000)];
+    if (0x80000..0xA0000).contains(&ebda) {
+        areas[0] = (ebda, ebda + 1024);
+    }
+
+    areas.iter()
+        .flat_map(|&(start, end)| (start..end).step_by(16))
+        .find_map(|addr| rsdp_at(PhysAddr::new(addr)))
+}
+
+fn rsdp_at(addr: PhysAddr) -> Option<Rsdp> {
+    let ptr = phys_to_virt(addr).as_ptr::<u8>();
+    let v1 = unsafe { slice::from_raw_parts(ptr, 20) };
+    if &v1[..8] != b"RSD PTR " || !checksum_ok(v1) {
+        return None;
+    }
+
+    let mut rsdp: Rsdp = unsafe { mem::zeroed() };
+    unsafe { ptr::copy_nonoverlapping(ptr, &mut rsdp as *mut Rsdp as *mut u8, 20) };
+
+    if rsdp.revision >= 2 {
+        rsdp = unsafe { ptr::read_unaligned(ptr as *const Rsdp) };
+        let length = rsdp.length as usize;
+        let full = unsafe { slice::from_raw_parts(ptr, length) };
+        if length < mem::size_of::<Rsdp>() || !checksum_ok(full) {
+            return None;
+        }
+    }
+
+    Some(rsdp)
+}
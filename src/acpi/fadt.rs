@@ -0,0 +1,165 @@
+use core::mem;
+use x86_64::PhysAddr;
+use super::SdtHeader;
+
+/// Address space IDs used by [`GenericAddress`].
+pub const SPACE_SYSTEM_MEMORY: u8 = 0;
+pub const SPACE_SYSTEM_IO: u8 = 1;
+pub const SPACE_PCI_CONFIG: u8 = 2;
+
+/// Set in the FADT flags if `reset_register` can be used to reboot.
+const FLAG_RESET_REG_SUP: u32 = 1 << 10;
+
+/// ACPI Generic Address Structure: where a register lives and how wide it is.
+#[derive(Debug, Clone, Copy)]
+pub struct GenericAddress {
+    pub space: u8,
+    pub bit_width: u8,
+    pub bit_offset: u8,
+    pub access_size: u8,
+    pub address: u64,
+}
+
+impl GenericAddress {
+    fn parse(bytes: &[u8]) -> Self {
+        GenericAddress {
+            space: bytes[0],
+            bit_width: bytes[1],
+            bit_offset: bytes[2],
+            access_size: bytes[3],
+            address: read_u64(bytes, 4),
+        }
+    }
+}
+
+/// The parts of the Fixed ACPI Description Table needed for power control.
+#[derive(Debug, Clone, Copy)]
+pub struct Fadt {
+    pub dsdt: PhysAddr,
+    pub smi_command_port: u32,
+    pub acpi_enable: u8,
+    pub pm1a_control_block: u32,
+    pub pm1b_control_block: u32,
+    /// Only set if the firmware says the reset register may be used
+    pub reset_register: Option<GenericAddress>,
+    pub reset_value: u8,
+}
+
+// field offsets from the start of the table, header included
+const DSDT: usize = 40;
+const SMI_CMD: usize = 48;
+const ACPI_ENABLE: usize = 52;
+const PM1A_CNT_BLK: usize = 64;
+const PM1B_CNT_BLK: usize = 68;
+const FLAGS: usize = 112;
+const RESET_REG: usize = 116;
+const RESET_VALUE: usize = 128;
+const X_DSDT: usize = 140;
+const X_PM1A_CNT_BLK: usize = 172;
+const X_PM1B_CNT_BLK: usize = 184;
+
+/// Finds and parses the FADT (signature `FACP`).
+pub fn parse() -> Option<Fadt> {
+    let table = super::table_bytes(super::find_table(b"FACP")?);
+    if table.len() < PM1B_CNT_BLK + 4 {
+        return None;
+    }
+
+    let mut fadt = Fadt {
+        dsdt: PhysAddr::new(read_u32(table, DSDT) as u64),
+        smi_command_port: read_u32(table, SMI_CMD),
+        acpi_enable: table[ACPI_ENABLE],
+        pm1a_control_block: read_u32(table, PM1A_CNT_BLK),
+        pm1b_control_block: read_u32(table, PM1B_CNT_BLK),
+        reset_register: None,
+        reset_value: 0,
+    };
+
+    if table.len() > RESET_VALUE {
+        let flags = read_u32(table, FLAGS);
+        let reset = GenericAddress::parse(&table[RESET_REG..RESET_REG + 12]);
+        if flags & FLAG_RESET_REG_SUP != 0 && reset.address != 0 {
+            fadt.reset_register = Some(reset);
+            fadt.reset_value = table[RESET_VALUE];
+        }
+    }
+
+    // ACPI 2.0+ tables carry 64-bit versions that win when they are set
+    if table.len() >= X_PM1B_CNT_BLK + 12 {
+        let x_dsdt = read_u64(table, X_DSDT);
+        if x_dsdt != 0 {
+            fadt.dsdt = PhysAddr::new(x_dsdt);
+        }
+        let x_pm1a = GenericAddress::parse(&table[X_PM1A_CNT_BLK..X_PM1A_CNT_BLK + 12]);
+        if x_pm1a.space == SPACE_SYSTEM_IO && x_pm1a.address != 0 {
+            fadt.pm1a_control_block = x_pm1a.address as u32;
+        }
+        let x_pm1b = GenericAddress::parse(&table[X_PM1B_CNT_BLK..X_PM1B_CNT_BLK + 12]);
+        if x_pm1b.space == SPACE_SYSTEM_IO && x_pm1b.address != 0 {
+            fadt.pm1b_control_block = x_pm1b.address as u32;
+        }
+    }
+
+    Some(fadt)
+}
+
+impl Fadt {
+    /// Reads the SLP_TYPa and SLP_TYPb values for the S5 (soft off) sleep
+    /// state from the `\_S5_` package in the DSDT.
+    ///
+    /// This is not an AML interpreter: it looks for the package by name,
+    /// which is how every hobby OS (and most firmware) gets away with it.
+    pub fn s5_sleep_types(&self) -> Option<(u8, u8)> {
+        let dsdt = super::table_bytes(self.dsdt);
+        let aml = &dsdt[mem::size_of::<SdtHeader>()..];
+
+        let position = aml.windows(4).position(|w| w == b"_S5_")?;
+        // must be `NameOp _S5_` or `NameOp \_S5_`
+        let is_name = (position >= 1 && aml[position - 1] == AML_NAME_OP)
+            || (position >= 2 && aml[position - 2] == AML_NAME_OP && aml[position - 1] == b'\\');
+        if !is_name {
+            return None;
+        }
+
+        let mut rest = aml.get(position + 4..)?;
+        if *rest.first()? != AML_PACKAGE_OP {
+            return None;
+        }
+        // skip PackageOp, PkgLength and NumElements
+        let pkg_length_bytes = ((rest.get(1)? >> 6) & 0b11) as usize + 1;
+        rest = rest.get(1 + pkg_length_bytes + 1..)?;
+
+        let (slp_typ_a, rest) = read_aml_integer(rest)?;
+        let (slp_typ_b, _) = read_aml_integer(rest)?;
+        Some((slp_typ_a, slp_typ_b))
+    }
+}
+
+const AML_ZERO_OP: u8 = 0x00;
+const AML_ONE_OP: u8 = 0x01;
+const AML_BYTE_PREFIX: u8 = 0x0A;
+const AML_NAME_OP: u8 = 0x08;
+const AML_PACKAGE_OP: u8 = 0x12;
+
+/// Reads one small integer package element.
+fn read_aml_integer(aml: &[u8]) -> Option<(u8, &[u8])> {
+    match *aml.first()? {
+        AML_ZERO_OP => Some((0, &aml[1..])),
+        AML_ONE_OP => Some((1, &aml[1..])),
+        AML_BYTE_PREFIX => Some((*aml.get(1)?, aml.get(2..)?)),
+        // some firmware stores bare bytes
+        value => Some((value, &aml[1..])),
+    }
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+    let mut buf = [0; 4];
+    buf.copy_from_slice(&bytes[offset..offset + 4]);
+    u32::from_le_bytes(buf)
+}
+
+fn read_u64(bytes: &[u8], offset: usize) -> u64 {
+    let mut buf = [0; 8];
+    buf.copy_from_slice(&bytes[offset..offset + 8]);
+    u64::from_le_bytes(buf)
+}
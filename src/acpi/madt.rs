@@ -0,0 +1,131 @@
+use alloc::vec::Vec;
+use core::mem;
+use x86_64::PhysAddr;
+use super::SdtHeader;
+
+/// A processor's local APIC, one per core.
+#[derive(Debug, Clone, Copy)]
+pub struct LocalApic {
+    pub processor_id: u8,
+    pub apic_id: u8,
+    pub enabled: bool,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct IoApic {
+    pub id: u8,
+    pub address: PhysAddr,
+    /// First global system interrupt this IOAPIC handles
+    pub gsi_base: u32,
+}
+
+/// Says that legacy ISA IRQ `source` is wired to `gsi` instead of the
+/// identically numbered GSI.
+#[derive(Debug, Clone, Copy)]
+pub struct InterruptOverride {
+    pub source: u8,
+    pub gsi: u32,
+    pub flags: u16,
+}
+
+impl InterruptOverride {
+    /// Whether the line is active low. "Conforming" means ISA, active high.
+    pub fn active_low(&self) -> bool {
+        self.flags & 0b11 == 0b11
+    }
+
+    /// Whether the line is level triggered. "Conforming" means ISA, edge.
+    pub fn level_triggered(&self) -> bool {
+        (self.flags >> 2) & 0b11 == 0b11
+    }
+}
+
+/// The parts of the Multiple APIC Description Table we care about.
+#[derive(Debug)]
+pub struct Madt {
+    pub local_apic_address: PhysAddr,
+    /// Set if the machine also has 8259 PICs that must be masked
+    pub pic_compatible: bool,
+    pub local_apics: Vec<LocalApic>,
+    pub io_apics: Vec<IoApic>,
+    pub overrides: Vec<InterruptOverride>,
+}
+
+impl Madt {
+    /// Finds the GSI and override (if any) a legacy ISA IRQ is routed to.
+    pub fn isa_irq(&self, irq: u8) -> (u32, Option<InterruptOverride>) {
+        match self.overrides.iter().find(|o| o.source == irq) {
+            Some(o) => (o.gsi, Some(*o)),
+            None => (irq as u32, None),
+        }
+    }
+}
+
+const ENTRY_LOCAL_APIC: u8 = 0;
+const ENTRY_IO_APIC: u8 = 1;
+const ENTRY_INTERRUPT_OVERRIDE: u8 = 2;
+const ENTRY_LOCAL_APIC_ADDRESS_OVERRIDE: u8 = 5;
+
+/// Finds and parses the MADT. Returns `None` if ACPI is unavailable or the
+/// firmware doesn't describe any APICs.
+pub fn parse() -> Option<Madt> {
+    let table = super::table_bytes(super::find_table(b"APIC")?);
+    let body = &table[mem::size_of::<SdtHeader>()..];
+    if body.len() < 8 {
+        return None;
+    }
+
+    let mut madt = Madt {
+        local_apic_address: PhysAddr::new(read_u32(body, 0) as u64),
+        pic_compatible: read_u32(body, 4) & 1 != 0,
+        local_apics: Vec::new(),
+        io_apics: Vec::new(),
+        overrides: Vec::new(),
+    };
+
+    let mut entries = &body[8..];
+    while entries.len() >= 2 {
+        let (kind, length) = (entries[0], entries[1] as usize);
+        if length < 2 || length > entries.len() {
+            break;
+        }
+        let entry = &entries[..length];
+
+        match kind {
+            ENTRY_LOCAL_APIC if length >= 8 => madt.local_apics.push(LocalApic {
+                processor_id: entry[2],
+                apic_id: entry[3],
+                enabled: read_u32(entry, 4) & 1 != 0,
+            }),
+            ENTRY_IO_APIC if length >= 12 => madt.io_apics.push(IoApic {
+                id: entry[2],
+                address: PhysAddr::new(read_u32(entry, 4) as u64),
+                gsi_base: read_u32(entry, 8),
+            }),
+            ENTRY_INTERRUPT_OVERRIDE if length >= 10 => madt.overrides.push(InterruptOverride {
+                source: entry[3],
+                gsi: read_u32(entry, 4),
+                flags: u16::from_le_bytes([entry[8], entry[9]]),
+            }),
+            ENTRY_LOCAL_APIC_ADDRESS_OVERRIDE if length >= 12 => {
+                let mut bytes = [0; 8];
+                bytes.copy_from_slice(&entry[4..12]);
+                madt.local_apic_address = PhysAddr::new(u64::from_le_bytes(bytes));
+            }
+            _ => {}
+        }
+
+        entries = &entries[length..];
+    }
+
+    if madt.io_apics.is_empty() {
+        return None;
+    }
+    Some(madt)
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+    let mut buf = [0; 4];
+    buf.copy_from_slice(&bytes[offset..offset + 4]);
+    u32::from_le_bytes(buf)
+}
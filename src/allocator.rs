@@ -1,27 +1,48 @@
 use alloc::alloc::{GlobalAlloc, Layout};
 use core::ptr::null_mut;
-// use linked_list_allocator::LockedHeap;
-use bump::BumpAllocator;
-use fixed_size_block::FixedSizeBlockAllocator;
-use linked_list::LinkedListAllocator;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use dispatch::DispatchAllocator;
+use crate::{cmdline, println};
 
+pub mod bench;
 pub mod bump;
+#[cfg(any(feature = "alloc-debug", test))]
+pub mod debug;
+pub mod dispatch;
 pub mod fixed_size_block;
 pub mod linked_list;
+pub mod stats;
 
-// #[global_allocator]
-// static ALLOCATOR: Locked<LinkedListAllocator> =
-//     Locked::new(LinkedListAllocator::new());
+pub use dispatch::Backend;
+pub use linked_list::FitStrategy;
+pub use stats::HeapStats;
+
+/// Runs on the backend picked by the `allocator=` command line option, or
+/// the cargo feature default (see `Backend::DEFAULT`).
+#[cfg_attr(not(feature = "alloc-debug"), global_allocator)]
+static ALLOCATOR: DispatchAllocator = DispatchAllocator::new();
+/// With the `alloc-debug` feature every allocation is checked for heap
+/// corruption on its way to `ALLOCATOR`.
+#[cfg(feature = "alloc-debug")]
 #[global_allocator]
-static ALLOCATOR: Locked<FixedSizeBlockAllocator> = Locked::new(FixedSizeBlockAllocator::new());
+static DEBUG_ALLOCATOR: debug::DebugAllocator<DispatchAllocator> =
+    debug::DebugAllocator::new(&ALLOCATOR);
 pub const HEAP_START: usize = 0x_4444_4444_0000;
 pub const HEAP_SIZE: usize = 1024 * 1024; // 1024 KiB / 1 MiB
+/// The heap grows on demand up to this size.
+pub const HEAP_MAX_SIZE: usize = 64 * 1024 * 1024; // 64 MiB
+/// Smallest step the heap grows by, so small allocations don't map a page
+/// at a time.
+const HEAP_GROW_STEP: usize = 64 * 1024;
+
+/// Current end of the mapped heap.
+static HEAP_END: AtomicUsize = AtomicUsize::new(HEAP_START);
 
 pub struct Dummy;
 
 use x86_64::{
     structures::paging::{
-        mapper::MapToError, FrameAllocator, Mapper, Page, PageTableFlags, Size4KiB,
+        mapper::MapToError, FrameAllocator, Mapper, Page, PageSize, PageTableFlags, Size4KiB,
     },
     VirtAddr,
 };
@@ -46,13 +67,141 @@ pub fn init_heap(
         unsafe { mapper.map_to(page, frame, flags, frame_allocator)?.flush() };
     }
 
+    let backend = match cmdline::get("allocator") {
+        Some(name) => Backend::from_name(name).unwrap_or_else(|| {
+            println!("Unknown allocator \"{}\", using {}.", name, Backend::DEFAULT.name());
+            Backend::DEFAULT
+        }),
+        None => Backend::DEFAULT,
+    };
+
+    if let Some(name) = cmdline::get("fit") {
+        match FitStrategy::from_name(name) {
+            Some(strategy) => ALLOCATOR.set_fit_strategy(strategy),
+            None => println!("Unknown fit strategy \"{}\", using first fit.", name),
+        }
+    }
+
+    HEAP_END.store(HEAP_START + HEAP_SIZE, Ordering::SeqCst);
     unsafe {
-        ALLOCATOR.lock().init(HEAP_START, HEAP_SIZE);
+        ALLOCATOR.init(backend, HEAP_START, HEAP_SIZE);
     }
 
     Ok(())
 }
 
+/// Called when an allocation that can't fail does, like `Box::new` with the
+/// heap at `HEAP_MAX_SIZE` and no frames left. Code that can cope uses
+/// `try_reserve` and friends instead; here all that's left is to show what
+/// the heap looked like and halt.
+#[alloc_error_handler]
+fn alloc_error(layout: Layout) -> ! {
+    let stats = heap_stats();
+    println!(
+        "Out of memory: {} bytes (align {}) requested from the {} heap.",
+        layout.size(),
+        layout.align(),
+        backend().name()
+    );
+    println!(
+        "Heap: {} KiB mapped, {} bytes in use by {} allocations, {} bytes free, largest block {} bytes",
+        heap_size() / 1024,
+        stats.counters.bytes_in_use,
+        stats.counters.allocations - stats.counters.frees,
+        stats.free_bytes,
+        stats.largest_free_block
+    );
+    panic!("allocation of {} bytes failed", layout.size());
+}
+
+/// Returns a snapshot of the kernel heap's counters and free memory.
+pub fn heap_stats() -> HeapStats {
+    ALLOCATOR.stats()
+}
+
+/// The debug wrapper around the kernel heap.
+#[cfg(feature = "alloc-debug")]
+pub fn debug_allocator() -> &'static debug::DebugAllocator<DispatchAllocator> {
+    &DEBUG_ALLOCATOR
+}
+
+/// Whether an allocation or free is under way, so allocating now would
+/// deadlock. For fault handlers deciding whether they can carry on.
+pub fn is_locked() -> bool {
+    #[cfg(feature = "alloc-debug")]
+    if DEBUG_ALLOCATOR.is_locked() {
+        return true;
+    }
+    ALLOCATOR.is_locked()
+}
+
+/// The backend the kernel heap runs on.
+pub fn backend() -> Backend {
+    ALLOCATOR.backend()
+}
+
+/// How the linked-list backend picks a free region.
+pub fn fit_strategy() -> FitStrategy {
+    ALLOCATOR.fit_strategy()
+}
+
+/// Bytes of virtual memory currently mapped for the heap.
+pub fn heap_size() -> usize {
+    HEAP_END.load(Ordering::SeqCst) - HEAP_START
+}
+
+/// Maps at least `min_size` more bytes at the end of the heap and returns
+/// how many were added. Allocators call this when they run out, passing the
+/// end of the memory they manage; nothing is mapped unless that is the end of
+/// the kernel heap. Returns 0 if the heap is at `HEAP_MAX_SIZE`, the page
+/// tables haven't been handed over with `memory::install` yet, or there are
+/// no frames left.
+///
+/// Runs with the allocator locked, so it must not allocate itself.
+fn grow_heap(heap_end: usize, min_size: usize) -> usize {
+    use crate::memory::{FRAME_ALLOCATOR, MAPPER};
+
+    if heap_end != HEAP_END.load(Ordering::SeqCst) {
+        return 0;
+    }
+
+    let room = HEAP_START + HEAP_MAX_SIZE - heap_end;
+    let size = align_up(min_size.max(HEAP_GROW_STEP), Size4KiB::SIZE as usize).min(room);
+    if size < min_size {
+        return 0;
+    }
+
+    // whoever holds these may be allocating right now, don't deadlock on them
+    let (mut mapper, mut frame_allocator) = match (MAPPER.try_lock(), FRAME_ALLOCATOR.try_lock()) {
+        (Some(mapper), Some(frame_allocator)) => (mapper, frame_allocator),
+        _ => return 0,
+    };
+    let (mapper, frame_allocator) = match (mapper.as_mut(), frame_allocator.as_mut()) {
+        (Some(mapper), Some(frame_allocator)) => (mapper, frame_allocator),
+        _ => return 0,
+    };
+
+    let mut grown = 0;
+    while grown < size {
+        let page = Page::<Size4KiB>::containing_address(VirtAddr::new((heap_end + grown) as u64));
+        let frame = match frame_allocator.allocate_frame() {
+            Some(frame) => frame,
+            None => break,
+        };
+        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+        match unsafe { mapper.map_to(page, frame, flags, frame_allocator) } {
+            Ok(flush) => flush.flush(),
+            Err(_) => break,
+        }
+        grown += Size4KiB::SIZE as usize;
+    }
+
+    // keep whatever got mapped even if it fell short, the allocator can
+    // still use it for smaller requests
+    HEAP_END.store(heap_end + grown, Ordering::SeqCst);
+    grown
+}
+
 unsafe impl GlobalAlloc for Dummy {
     unsafe fn alloc(&self, _layout: Layout) -> *mut u8 {
         null_mut()
@@ -77,8 +226,54 @@ impl<A> Locked<A> {
     pub fn lock(&self) -> spin::MutexGuard<A> {
         self.inner.lock()
     }
+
+    pub fn is_locked(&self) -> bool {
+        self.inner.try_lock().is_none()
+    }
 }
 
 fn align_up(addr: usize, align: usize) -> usize {
     (addr + align - 1) & !(align - 1)
 }
+
+/// A piece of the kernel heap for a private allocator instance to manage,
+/// used by `allocbench` and the allocator tests.
+pub(crate) struct Arena {
+    memory: alloc::vec::Vec<u64>,
+}
+
+impl Arena {
+    pub fn new(size: usize) -> Self {
+        // one spare word at the end, so the arena never ends where the heap
+        // does and a private allocator can't take over `grow_heap`
+        Arena { memory: alloc::vec![0; size / 8 + 1] }
+    }
+
+    pub fn start(&mut self) -> usize {
+        self.memory.as_mut_ptr() as usize
+    }
+
+    pub fn size(&self) -> usize {
+        (self.memory.len() - 1) * 8
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec::Vec;
+
+    #[test_case]
+    fn heap_grows_past_its_initial_size() {
+        let before = heap_size();
+        let big: Vec<u8> = alloc::vec![1; HEAP_SIZE + HEAP_SIZE / 2];
+        assert!(heap_size() > before);
+        assert!(heap_size() <= HEAP_MAX_SIZE);
+        assert!(big.iter().all(|&b| b == 1));
+    }
+
+    #[test_case]
+    fn growth_only_happens_at_the_end_of_the_heap() {
+        assert_eq!(grow_heap(HEAP_START, 4096), 0);
+    }
+}
@@ -0,0 +1,116 @@
+use alloc::alloc::{GlobalAlloc, Layout};
+use core::arch::x86_64::_rdtsc;
+use super::dispatch::{Backend, DispatchAllocator};
+use super::stats::HeapStats;
+use super::Arena;
+
+/// Memory each backend gets for the benchmark, taken from the kernel heap.
+pub const ARENA_SIZE: usize = 256 * 1024;
+/// Allocations and frees the workload performs.
+pub const OPERATIONS: usize = 20_000;
+/// How many allocations the workload keeps alive at most.
+const SLOTS: usize = 128;
+
+/// What `run` measured for one backend.
+pub struct BenchResult {
+    pub backend: Backend,
+    pub cycles_per_op: u64,
+    /// Allocations the backend couldn't serve.
+    pub failed: usize,
+    /// State at the end of the workload, with up to `SLOTS` allocations alive.
+    pub stats: HeapStats,
+}
+
+/// Small deterministic PRNG, so every backend sees the same workload.
+struct XorShift(u64);
+
+impl XorShift {
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+}
+
+/// Picks an allocation size: mostly small objects, some medium buffers and
+/// a few page sized ones.
+fn workload_layout(random: u64) -> Layout {
+    let size = match random % 16 {
+        0 => 4096,
+        1..=3 => 256 + (random >> 8) as usize % 1792,
+        _ => 8 + (random >> 8) as usize % 120,
+    };
+    let align = 1 << ((random >> 20) % 4 + 3);
+    Layout::from_size_align(size, align).unwrap()
+}
+
+/// Runs the workload on a fresh instance of `backend` and measures it. The
+/// kernel heap itself isn't touched apart from lending the arena.
+pub fn run(backend: Backend) -> BenchResult {
+    let mut arena = Arena::new(ARENA_SIZE);
+    let allocator = DispatchAllocator::new();
+    unsafe { allocator.init(backend, arena.start(), arena.size()) };
+
+    let mut live: [Option<(*mut u8, Layout)>; SLOTS] = [None; SLOTS];
+    let mut random = XorShift(0x2545_f491_4f6c_dd1d);
+    let mut failed = 0;
+
+    let start = unsafe { _rdtsc() };
+    for _ in 0..OPERATIONS {
+        let value = random.next();
+        let slot = &mut live[value as usize % SLOTS];
+        match slot.take() {
+            Some((ptr, layout)) => unsafe { allocator.dealloc(ptr, layout) },
+            None => {
+                let layout = workload_layout(value >> 8);
+                let ptr = unsafe { allocator.alloc(layout) };
+                if ptr.is_null() {
+                    failed += 1;
+                } else {
+                    *slot = Some((ptr, layout));
+                }
+            }
+        }
+    }
+    let cycles = unsafe { _rdtsc() } - start;
+
+    let result = BenchResult {
+        backend,
+        cycles_per_op: cycles / OPERATIONS as u64,
+        failed,
+        stats: allocator.stats(),
+    };
+
+    for (ptr, layout) in live.iter().flatten() {
+        unsafe { allocator.dealloc(*ptr, *layout) };
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn workload_is_the_same_for_every_backend() {
+        let mut a = XorShift(1);
+        let mut b = XorShift(1);
+        for _ in 0..100 {
+            let value = a.next();
+            assert_eq!(value, b.next());
+            let layout = workload_layout(value);
+            assert!(layout.size() >= 8 && layout.size() <= 4096);
+            assert!(layout.align() >= 8 && layout.align() <= 64);
+        }
+    }
+
+    #[test_case]
+    fn every_backend_runs_the_workload() {
+        for backend in Backend::ALL {
+            let result = run(backend);
+            assert_eq!(result.backend, backend);
+            assert!(result.stats.counters.allocations > 0);
+        }
+    }
+}
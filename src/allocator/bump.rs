@@ -1,5 +1,6 @@
 use alloc::alloc::{GlobalAlloc, Layout};
-use super::{align_up, Locked};
+use super::{align_up, grow_heap, Locked};
+use super::stats::{AllocCounters, HeapStats};
 use core::ptr;
 
 pub struct BumpAllocator {
@@ -7,6 +8,7 @@ pub struct BumpAllocator {
     heap_end: usize,
     next: usize,
     allocations: usize,
+    counters: AllocCounters,
 }
 
 impl BumpAllocator {
@@ -17,6 +19,7 @@ impl BumpAllocator {
             heap_end: 0,
             next: 0,
             allocations: 0,
+            counters: AllocCounters::new(),
         }
     }
 
@@ -29,6 +32,16 @@ impl BumpAllocator {
         self.heap_end = heap_start + heap_size;
         self.next = heap_start;
     }
+
+    /// Everything past `next` is free and in one piece.
+    pub fn stats(&self) -> HeapStats {
+        HeapStats {
+            counters: self.counters,
+            free_bytes: self.heap_end - self.next,
+            largest_free_block: self.heap_end - self.next,
+            free_blocks: None,
+        }
+    }
 }
 
 unsafe impl GlobalAlloc for Locked<BumpAllocator> {
@@ -41,21 +54,87 @@ unsafe impl GlobalAlloc for Locked<BumpAllocator> {
             None => return ptr::null_mut(),
         };
 
+        if alloc_end > bump.heap_end {
+            let heap_end = bump.heap_end;
+            bump.heap_end += grow_heap(heap_end, alloc_end - heap_end);
+        }
+
         if alloc_end > bump.heap_end {
             ptr::null_mut() // out of memory
         } else {
             bump.next = alloc_end;
             bump.allocations += 1;
+            bump.counters.record_alloc(layout.size());
             alloc_start as *mut u8
         }
     }
 
-    unsafe fn dealloc(&self, _ptr: *mut u8, _layout: Layout) {
+    unsafe fn dealloc(&self, _ptr: *mut u8, layout: Layout) {
         let mut bump = self.lock(); // get a mutable reference
 
+        bump.counters.record_free(layout.size());
         bump.allocations -= 1;
         if bump.allocations == 0 {
             bump.next = bump.heap_start;
         }
     }
 }
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::allocator::Arena;
+
+    fn bump_over(arena: &mut Arena) -> Locked<BumpAllocator> {
+        let allocator = Locked::new(BumpAllocator::new());
+        unsafe { allocator.lock().init(arena.start(), arena.size()) };
+        allocator
+    }
+
+    #[test_case]
+    fn allocations_follow_each_other() {
+        let mut arena = Arena::new(4096);
+        let allocator = bump_over(&mut arena);
+        let layout = Layout::from_size_align(24, 8).unwrap();
+
+        let a = unsafe { allocator.alloc(layout) } as usize;
+        let b = unsafe { allocator.alloc(layout) } as usize;
+        assert_eq!(a, arena.start());
+        assert_eq!(b, a + 24);
+    }
+
+    #[test_case]
+    fn alignment_is_respected() {
+        let mut arena = Arena::new(4096);
+        let allocator = bump_over(&mut arena);
+
+        unsafe { allocator.alloc(Layout::from_size_align(1, 1).unwrap()) };
+        let aligned = unsafe { allocator.alloc(Layout::from_size_align(8, 64).unwrap()) };
+        assert_eq!(aligned as usize % 64, 0);
+    }
+
+    #[test_case]
+    fn out_of_memory_returns_null() {
+        let mut arena = Arena::new(4096);
+        let allocator = bump_over(&mut arena);
+
+        let too_big = Layout::from_size_align(arena.size() + 1, 8).unwrap();
+        assert!(unsafe { allocator.alloc(too_big) }.is_null());
+    }
+
+    #[test_case]
+    fn memory_is_reused_once_everything_is_freed() {
+        let mut arena = Arena::new(4096);
+        let allocator = bump_over(&mut arena);
+        let layout = Layout::from_size_align(64, 8).unwrap();
+
+        let a = unsafe { allocator.alloc(layout) };
+        let b = unsafe { allocator.alloc(layout) };
+        unsafe {
+            allocator.dealloc(a, layout);
+            allocator.dealloc(b, layout);
+        }
+        assert_eq!(unsafe { allocator.alloc(layout) }, a);
+    }
+}
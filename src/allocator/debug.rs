@@ -0,0 +1,329 @@
+use alloc::alloc::{GlobalAlloc, Layout};
+use core::{fmt, mem, ptr, slice};
+use spin::Mutex;
+
+/// Guard bytes before and after every allocation.
+const RED_ZONE: usize = 16;
+const RED_ZONE_BYTE: u8 = 0xFD;
+/// Written over fresh allocations, so reads of memory nobody wrote stand out.
+const ALLOC_POISON: u8 = 0xCD;
+/// Written over freed memory, so use-after-free reads stand out.
+const FREE_POISON: u8 = 0xDD;
+
+const LIVE_MAGIC: u64 = 0xA110_CA7E_D000_0001;
+const FREED_MAGIC: u64 = 0xF4EE_D000_DEAD_0002;
+
+/// Sits right before the front red zone of every allocation. Live
+/// allocations are linked through it, so tracking them needs no memory of
+/// its own.
+///
+/// `magic` comes last: the inner allocators write their free list nodes
+/// over the start of freed memory, and it should survive that to catch
+/// double frees.
+#[repr(C)]
+struct Header {
+    prev: *mut Header,
+    next: *mut Header,
+    size: usize,
+    align: usize,
+    id: u64,
+    magic: u64,
+}
+
+const HEADER_SIZE: usize = mem::size_of::<Header>();
+
+/// What went wrong with an allocation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Corruption {
+    /// The header says this was freed already.
+    DoubleFree,
+    /// No header found, so the pointer didn't come from this allocator or
+    /// something overwrote it.
+    UnknownPointer,
+    /// Something wrote right before the allocation.
+    Underflow,
+    /// Something wrote past the end of the allocation.
+    Overflow,
+    /// Freed with a different layout than it was allocated with.
+    LayoutMismatch { allocated: (usize, usize), freed: (usize, usize) },
+}
+
+impl fmt::Display for Corruption {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Corruption::DoubleFree => write!(f, "double free"),
+            Corruption::UnknownPointer => write!(f, "free of an unknown pointer"),
+            Corruption::Underflow => write!(f, "write before the start of an allocation"),
+            Corruption::Overflow => write!(f, "write past the end of an allocation"),
+            Corruption::LayoutMismatch { allocated, freed } => write!(
+                f,
+                "allocated with size {} align {}, freed with size {} align {}",
+                allocated.0, allocated.1, freed.0, freed.1
+            ),
+        }
+    }
+}
+
+/// A live allocation, as listed by [`DebugAllocator::for_each_live`].
+#[derive(Debug, Clone, Copy)]
+pub struct LiveAllocation {
+    /// Counts up from 1 in allocation order.
+    pub id: u64,
+    pub addr: usize,
+    pub size: usize,
+    pub align: usize,
+    /// `Err` if the red zones were overwritten.
+    pub state: Result<(), Corruption>,
+}
+
+struct LiveList {
+    head: *mut Header,
+    count: usize,
+    bytes: usize,
+    next_id: u64,
+}
+
+// the headers live in memory owned by the allocator
+unsafe impl Send for LiveList {}
+
+/// Wraps another allocator and checks every allocation for heap
+/// corruption: red zones around each allocation are checked on free, freed
+/// memory is poisoned, and double frees and frees with the wrong layout
+/// panic. Costs `HEADER_SIZE + 2 * RED_ZONE` bytes and a lock per allocation.
+pub struct DebugAllocator<A: 'static> {
+    inner: &'static A,
+    live: Mutex<LiveList>,
+}
+
+/// Offset of the user data in the inner allocation: room for the header and
+/// the front red zone, rounded up to the alignment.
+fn front_size(align: usize) -> usize {
+    (HEADER_SIZE + RED_ZONE).next_multiple_of(align)
+}
+
+/// The layout to ask the inner allocator for, and the offset of the user
+/// data in it.
+fn inner_layout(size: usize, align: usize) -> Option<(Layout, usize)> {
+    let align = align.max(mem::align_of::<Header>());
+    let front = front_size(align);
+    let size = front.checked_add(size)?.checked_add(RED_ZONE)?;
+    Layout::from_size_align(size, align).ok().map(|layout| (layout, front))
+}
+
+fn header_of(ptr: *mut u8) -> *mut Header {
+    ptr.wrapping_sub(RED_ZONE + HEADER_SIZE) as *mut Header
+}
+
+/// Checks the header and red zones of the allocation at `ptr`.
+///
+/// # Safety
+///
+/// `ptr` must point at least `HEADER_SIZE + RED_ZONE` bytes into readable
+/// memory.
+unsafe fn verify(ptr: *mut u8) -> Result<(), Corruption> {
+    let header = unsafe { &*header_of(ptr) };
+    match header.magic {
+        LIVE_MAGIC => {}
+        FREED_MAGIC => return Err(Corruption::DoubleFree),
+        _ => return Err(Corruption::UnknownPointer),
+    }
+
+    let intact = |start: *const u8| {
+        unsafe { slice::from_raw_parts(start, RED_ZONE) }.iter().all(|&byte| byte == RED_ZONE_BYTE)
+    };
+    if !intact(ptr.wrapping_sub(RED_ZONE)) {
+        return Err(Corruption::Underflow);
+    }
+    if !intact(ptr.wrapping_add(header.size)) {
+        return Err(Corruption::Overflow);
+    }
+    Ok(())
+}
+
+impl<A: GlobalAlloc> DebugAllocator<A> {
+    pub const fn new(inner: &'static A) -> Self {
+        DebugAllocator {
+            inner,
+            live: Mutex::new(LiveList {
+                head: ptr::null_mut(),
+                count: 0,
+                bytes: 0,
+                next_id: 0,
+            }),
+        }
+    }
+
+    pub fn is_locked(&self) -> bool {
+        self.live.try_lock().is_none()
+    }
+
+    /// Number of live allocations and the bytes they asked for.
+    pub fn live(&self) -> (usize, usize) {
+        let live = self.live.lock();
+        (live.count, live.bytes)
+    }
+
+    /// Calls `f` for every live allocation, newest first, with its red zones
+    /// checked. Runs with the allocator locked, so `f` must not allocate.
+    pub fn for_each_live(&self, mut f: impl FnMut(LiveAllocation)) {
+        let live = self.live.lock();
+        let mut current = live.head;
+        while let Some(header) = unsafe { current.as_ref() } {
+            let addr = current as usize + HEADER_SIZE + RED_ZONE;
+            f(LiveAllocation {
+                id: header.id,
+                addr,
+                size: header.size,
+                align: header.align,
+                state: unsafe { verify(addr as *mut u8) },
+            });
+            current = header.next;
+        }
+    }
+}
+
+unsafe impl<A: GlobalAlloc> GlobalAlloc for DebugAllocator<A> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let Some((inner_layout, front)) = inner_layout(layout.size(), layout.align()) else {
+            return ptr::null_mut();
+        };
+        let base = unsafe { self.inner.alloc(inner_layout) };
+        if base.is_null() {
+            return base;
+        }
+
+        let ptr = unsafe { base.add(front) };
+        unsafe {
+            ptr::write_bytes(ptr.sub(RED_ZONE), RED_ZONE_BYTE, RED_ZONE);
+            ptr::write_bytes(ptr, ALLOC_POISON, layout.size());
+            ptr::write_bytes(ptr.add(layout.size()), RED_ZONE_BYTE, RED_ZONE);
+        }
+
+        let header = header_of(ptr);
+        let mut live = self.live.lock();
+        live.next_id += 1;
+        unsafe {
+            header.write(Header {
+                prev: ptr::null_mut(),
+                next: live.head,
+                size: layout.size(),
+                align: layout.align(),
+                id: live.next_id,
+                magic: LIVE_MAGIC,
+            });
+            if let Some(next) = live.head.as_mut() {
+                next.prev = header;
+            }
+        }
+        live.head = header;
+        live.count += 1;
+        live.bytes += layout.size();
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        let mut live = self.live.lock();
+        let header = unsafe { &mut *header_of(ptr) };
+
+        let mut result = unsafe { verify(ptr) };
+        if result.is_ok() && (header.size, header.align) != (layout.size(), layout.align()) {
+            result = Err(Corruption::LayoutMismatch {
+                allocated: (header.size, header.align),
+                freed: (layout.size(), layout.align()),
+            });
+        }
+        if let Err(corruption) = result {
+            // the panic handler might want the heap
+            drop(live);
+            panic!("heap corruption at {:p}: {}", ptr, corruption);
+        }
+
+        unsafe {
+            match header.prev.as_mut() {
+                Some(prev) => prev.next = header.next,
+                None => live.head = header.next,
+            }
+            if let Some(next) = header.next.as_mut() {
+                next.prev = header.prev;
+            }
+        }
+        live.count -= 1;
+        live.bytes -= layout.size();
+        drop(live);
+
+        header.magic = FREED_MAGIC;
+        unsafe { ptr::write_bytes(ptr, FREE_POISON, layout.size() + RED_ZONE) };
+
+        let (inner_layout, front) = inner_layout(layout.size(), layout.align()).unwrap();
+        unsafe { self.inner.dealloc(ptr.sub(front), inner_layout) };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::allocator::dispatch::{Backend, DispatchAllocator};
+    use crate::allocator::Arena;
+    use alloc::boxed::Box;
+
+    /// A debug allocator over a fresh linked-list allocator. Both are leaked,
+    /// along with the arena.
+    fn debug_allocator() -> DebugAllocator<DispatchAllocator> {
+        let arena = Box::leak(Box::new(Arena::new(16 * 1024)));
+        let inner = Box::leak(Box::new(DispatchAllocator::new()));
+        unsafe { inner.init(Backend::LinkedList, arena.start(), arena.size()) };
+        DebugAllocator::new(inner)
+    }
+
+    #[test_case]
+    fn allocations_are_aligned_poisoned_and_tracked() {
+        let allocator = debug_allocator();
+        let layout = Layout::from_size_align(40, 64).unwrap();
+
+        let ptr = unsafe { allocator.alloc(layout) };
+        assert_eq!(ptr as usize % 64, 0);
+        assert!(unsafe { slice::from_raw_parts(ptr, 40) }.iter().all(|&byte| byte == ALLOC_POISON));
+        assert_eq!(unsafe { verify(ptr) }, Ok(()));
+        assert_eq!(allocator.live(), (1, 40));
+
+        let mut listed = 0;
+        allocator.for_each_live(|allocation| {
+            assert_eq!((allocation.addr, allocation.size, allocation.align), (ptr as usize, 40, 64));
+            listed += 1;
+        });
+        assert_eq!(listed, 1);
+
+        unsafe { allocator.dealloc(ptr, layout) };
+        assert_eq!(allocator.live(), (0, 0));
+    }
+
+    #[test_case]
+    fn overflows_and_underflows_are_caught() {
+        let allocator = debug_allocator();
+        let layout = Layout::from_size_align(24, 8).unwrap();
+        let ptr = unsafe { allocator.alloc(layout) };
+
+        unsafe {
+            ptr.add(24).write(0);
+            assert_eq!(verify(ptr), Err(Corruption::Overflow));
+            ptr.add(24).write(RED_ZONE_BYTE);
+
+            ptr.sub(1).write(0);
+            assert_eq!(verify(ptr), Err(Corruption::Underflow));
+            ptr.sub(1).write(RED_ZONE_BYTE);
+
+            allocator.dealloc(ptr, layout);
+        }
+    }
+
+    #[test_case]
+    fn freed_memory_is_poisoned_and_marked() {
+        let allocator = debug_allocator();
+        let layout = Layout::from_size_align(32, 8).unwrap();
+        let ptr = unsafe { allocator.alloc(layout) };
+        unsafe { allocator.dealloc(ptr, layout) };
+
+        assert!(unsafe { slice::from_raw_parts(ptr, 32) }.iter().all(|&byte| byte == FREE_POISON));
+        assert_eq!(unsafe { verify(ptr) }, Err(Corruption::DoubleFree));
+    }
+}
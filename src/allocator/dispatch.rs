@@ -0,0 +1,184 @@
+use alloc::alloc::{GlobalAlloc, Layout};
+use core::sync::atomic::{AtomicU8, Ordering};
+use super::bump::BumpAllocator;
+use super::fixed_size_block::FixedSizeBlockAllocator;
+use super::linked_list::{FitStrategy, LinkedListAllocator};
+use super::stats::HeapStats;
+use super::Locked;
+
+#[cfg(all(feature = "alloc-bump", feature = "alloc-linked-list"))]
+compile_error!("features `alloc-bump` and `alloc-linked-list` are mutually exclusive");
+
+/// The allocator implementations the heap can run on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Backend {
+    Bump,
+    LinkedList,
+    FixedSizeBlock,
+}
+
+impl Backend {
+    pub const ALL: [Backend; 3] = [Backend::Bump, Backend::LinkedList, Backend::FixedSizeBlock];
+
+    /// The backend picked by cargo features, used unless the kernel command
+    /// line says otherwise.
+    pub const DEFAULT: Backend = if cfg!(feature = "alloc-bump") {
+        Backend::Bump
+    } else if cfg!(feature = "alloc-linked-list") {
+        Backend::LinkedList
+    } else {
+        Backend::FixedSizeBlock
+    };
+
+    /// Name used on the kernel command line (`allocator=<name>`).
+    pub fn name(self) -> &'static str {
+        match self {
+            Backend::Bump => "bump",
+            Backend::LinkedList => "linked_list",
+            Backend::FixedSizeBlock => "fixed_size_block",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Backend> {
+        Backend::ALL.iter().copied().find(|backend| backend.name() == name)
+    }
+
+    fn from_u8(value: u8) -> Backend {
+        match value {
+            0 => Backend::Bump,
+            1 => Backend::LinkedList,
+            _ => Backend::FixedSizeBlock,
+        }
+    }
+}
+
+/// Global allocator that forwards to one of the backends, chosen once in
+/// `init` before anything is allocated.
+pub struct DispatchAllocator {
+    backend: AtomicU8,
+    bump: Locked<BumpAllocator>,
+    linked_list: Locked<LinkedListAllocator>,
+    fixed_size_block: Locked<FixedSizeBlockAllocator>,
+}
+
+impl DispatchAllocator {
+    pub const fn new() -> Self {
+        DispatchAllocator {
+            backend: AtomicU8::new(Backend::DEFAULT as u8),
+            bump: Locked::new(BumpAllocator::new()),
+            linked_list: Locked::new(LinkedListAllocator::new()),
+            fixed_size_block: Locked::new(FixedSizeBlockAllocator::new()),
+        }
+    }
+
+    /// Selects `backend` and gives it the heap.
+    ///
+    /// # Safety
+    ///
+    /// Same as the backends' `init`: the memory must be valid and unused, and
+    /// this must be called only once, before the first allocation.
+    pub unsafe fn init(&self, backend: Backend, heap_start: usize, heap_size: usize) {
+        self.backend.store(backend as u8, Ordering::SeqCst);
+        unsafe {
+            match backend {
+                Backend::Bump => self.bump.lock().init(heap_start, heap_size),
+                Backend::LinkedList => self.linked_list.lock().init(heap_start, heap_size),
+                Backend::FixedSizeBlock => self.fixed_size_block.lock().init(heap_start, heap_size),
+            }
+        }
+    }
+
+    pub fn backend(&self) -> Backend {
+        Backend::from_u8(self.backend.load(Ordering::Relaxed))
+    }
+
+    /// Sets the fit strategy of the linked-list backend. Takes effect on the
+    /// next allocation, so it's fine to change at any time.
+    pub fn set_fit_strategy(&self, strategy: FitStrategy) {
+        self.linked_list.lock().set_strategy(strategy);
+    }
+
+    pub fn fit_strategy(&self) -> FitStrategy {
+        self.linked_list.lock().strategy()
+    }
+
+    /// Whether the current backend is locked.
+    pub fn is_locked(&self) -> bool {
+        match self.backend() {
+            Backend::Bump => self.bump.is_locked(),
+            Backend::LinkedList => self.linked_list.is_locked(),
+            Backend::FixedSizeBlock => self.fixed_size_block.is_locked(),
+        }
+    }
+
+    pub fn stats(&self) -> HeapStats {
+        match self.backend() {
+            Backend::Bump => self.bump.lock().stats(),
+            Backend::LinkedList => self.linked_list.lock().stats(),
+            Backend::FixedSizeBlock => self.fixed_size_block.lock().stats(),
+        }
+    }
+}
+
+impl Default for DispatchAllocator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+unsafe impl GlobalAlloc for DispatchAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        unsafe {
+            match self.backend() {
+                Backend::Bump => self.bump.alloc(layout),
+                Backend::LinkedList => self.linked_list.alloc(layout),
+                Backend::FixedSizeBlock => self.fixed_size_block.alloc(layout),
+            }
+        }
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        unsafe {
+            match self.backend() {
+                Backend::Bump => self.bump.dealloc(ptr, layout),
+                Backend::LinkedList => self.linked_list.dealloc(ptr, layout),
+                Backend::FixedSizeBlock => self.fixed_size_block.dealloc(ptr, layout),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::allocator::Arena;
+
+    #[test_case]
+    fn names_round_trip() {
+        for backend in Backend::ALL {
+            assert_eq!(Backend::from_name(backend.name()), Some(backend));
+            assert_eq!(Backend::from_u8(backend as u8), backend);
+        }
+        assert_eq!(Backend::from_name("slab"), None);
+    }
+
+    #[test_case]
+    fn forwards_to_the_selected_backend() {
+        for backend in Backend::ALL {
+            let mut arena = Arena::new(8192);
+            let allocator = DispatchAllocator::new();
+            unsafe { allocator.init(backend, arena.start(), arena.size()) };
+            assert_eq!(allocator.backend(), backend);
+
+            let layout = Layout::from_size_align(100, 8).unwrap();
+            let ptr = unsafe { allocator.alloc(layout) };
+            assert!(!ptr.is_null());
+            assert!((arena.start()..arena.start() + arena.size()).contains(&(ptr as usize)));
+            assert_eq!(allocator.stats().counters.bytes_in_use, 100);
+
+            unsafe { allocator.dealloc(ptr, layout) };
+            assert_eq!(allocator.stats().counters.frees, 1);
+        }
+    }
+}
@@ -1,6 +1,7 @@
 use alloc::alloc::{Layout, GlobalAlloc};
 use core::{ptr::{self, NonNull}, mem};
-use super::Locked;
+use super::{grow_heap, Locked};
+use super::stats::{AllocCounters, HeapStats};
 
 fn list_index(layout: &Layout) -> Option<usize> {
     let required_block_size = layout.size().max(layout.align());
@@ -11,30 +12,191 @@ struct ListNode {
     next: Option<&'static mut ListNode>,
 }
 
-const BLOCK_SIZES: &[usize] = &[8, 16, 32, 64, 128, 256, 512, 1024, 2048];
+pub const BLOCK_SIZES: &[usize] = &[8, 16, 32, 64, 128, 256, 512, 1024, 2048];
+
+/// Blocks are carved from slabs of at least a page, taken from the fallback
+/// heap. A slab is handed back once all its blocks are free again, so a burst
+/// of small allocations doesn't pin memory to one size class forever.
+const SLAB_SIZE: usize = 4096;
+/// The larger classes get bigger slabs, or a page would only hold a few
+/// blocks next to the header.
+const MIN_BLOCKS_PER_SLAB: usize = 8;
+
+/// Header at the start of every slab. Slabs are aligned to their size, so a
+/// block finds its slab by rounding its address down.
+struct Slab {
+    free_list: Option<&'static mut ListNode>,
+    free_blocks: usize,
+    /// Neighbours in the list of slabs of the same class with free blocks.
+    prev: *mut Slab,
+    next: *mut Slab,
+}
+
+fn slab_layout(index: usize) -> Layout {
+    let size = (BLOCK_SIZES[index] * MIN_BLOCKS_PER_SLAB).max(SLAB_SIZE);
+    Layout::from_size_align(size, size).unwrap()
+}
+
+/// Offset of the first block in a slab, past the header and rounded up so
+/// every block is aligned to its size.
+fn first_block_offset(index: usize) -> usize {
+    mem::size_of::<Slab>().next_multiple_of(BLOCK_SIZES[index])
+}
+
+fn blocks_per_slab(index: usize) -> usize {
+    (slab_layout(index).size() - first_block_offset(index)) / BLOCK_SIZES[index]
+}
 
 pub struct FixedSizeBlockAllocator {
-    list_heads: [Option<&'static mut ListNode>; BLOCK_SIZES.len()],
+    /// Slabs of each size class that have at least one free block.
+    partial_slabs: [*mut Slab; BLOCK_SIZES.len()],
     fallback_allocator: linked_list_allocator::Heap,
+    counters: AllocCounters,
 }
 
+// the slab pointers point into the heap this allocator owns
+unsafe impl Send for FixedSizeBlockAllocator {}
+
 impl FixedSizeBlockAllocator {
     /// Creates an empty FixedSizeBlockAllocator.
     pub const fn new() -> Self {
-        const EMPTY: Option<&'static mut ListNode> = None;
         FixedSizeBlockAllocator {
-            list_heads: [EMPTY; BLOCK_SIZES.len()],
+            partial_slabs: [ptr::null_mut(); BLOCK_SIZES.len()],
             fallback_allocator: linked_list_allocator::Heap::empty(),
+            counters: AllocCounters::new(),
         }
     }
 
     fn fallback_alloc(&mut self, layout: Layout) -> *mut u8 {
+        if let Ok(ptr) = self.fallback_allocator.allocate_first_fit(layout) {
+            return ptr.as_ptr();
+        }
+
+        // out of memory, grow the heap and try again. a fresh region may need
+        // padding for the alignment.
+        let grown = grow_heap(self.fallback_allocator.top(), layout.size() + layout.align());
+        if grown == 0 {
+            return ptr::null_mut();
+        }
+        unsafe { self.fallback_allocator.extend(grown) };
+
         match self.fallback_allocator.allocate_first_fit(layout) {
             Ok(ptr) => ptr.as_ptr(),
             Err(_) => ptr::null_mut(),
         }
     }
 
+    /// Takes a block of class `index`, carving a new slab if none has a free
+    /// block left.
+    fn alloc_block(&mut self, index: usize) -> *mut u8 {
+        if self.partial_slabs[index].is_null() {
+            let slab = self.new_slab(index);
+            if slab.is_null() {
+                return ptr::null_mut();
+            }
+            self.push_slab(index, slab);
+        }
+
+        let slab_ptr = self.partial_slabs[index];
+        let slab = unsafe { &mut *slab_ptr };
+        let node = slab.free_list.take().expect("partial slab without free blocks");
+        slab.free_list = node.next.take();
+        slab.free_blocks -= 1;
+        if slab.free_blocks == 0 {
+            self.unlink_slab(index, slab_ptr);
+        }
+        node as *mut ListNode as *mut u8
+    }
+
+    /// Puts a block of class `index` back into its slab, and the slab back
+    /// into the fallback heap if that was its last block in use.
+    ///
+    /// # Safety
+    ///
+    /// `ptr` must have come from `alloc_block` with the same `index`.
+    unsafe fn dealloc_block(&mut self, ptr: *mut u8, index: usize) {
+        let layout = slab_layout(index);
+        let slab_ptr = (ptr as usize & !(layout.size() - 1)) as *mut Slab;
+        let slab = unsafe { &mut *slab_ptr };
+
+        let node_ptr = ptr as *mut ListNode;
+        unsafe {
+            node_ptr.write(ListNode { next: slab.free_list.take() });
+            slab.free_list = Some(&mut *node_ptr);
+        }
+        slab.free_blocks += 1;
+
+        let last_partial = slab.prev.is_null() && slab.next.is_null();
+        if slab.free_blocks == 1 {
+            // the slab was full, so it wasn't on the list
+            self.push_slab(index, slab_ptr);
+        } else if slab.free_blocks == blocks_per_slab(index) && !last_partial {
+            // keep one slab around per class, so a single block being
+            // allocated and freed in a loop doesn't carve a slab every time
+            self.unlink_slab(index, slab_ptr);
+            unsafe {
+                self.fallback_allocator.deallocate(NonNull::new_unchecked(slab_ptr as *mut u8), layout);
+            }
+        }
+    }
+
+    /// Allocates a slab for class `index` with all its blocks free, or
+    /// returns null if the fallback heap is out of memory.
+    fn new_slab(&mut self, index: usize) -> *mut Slab {
+        let slab = self.fallback_alloc(slab_layout(index)) as *mut Slab;
+        if slab.is_null() {
+            return slab;
+        }
+
+        let block_size = BLOCK_SIZES[index];
+        let first_block = slab as usize + first_block_offset(index);
+        // thread the blocks back to front, so they're handed out in address order
+        let mut free_list = None;
+        for block in (0..blocks_per_slab(index)).rev() {
+            let node_ptr = (first_block + block * block_size) as *mut ListNode;
+            unsafe {
+                node_ptr.write(ListNode { next: free_list.take() });
+                free_list = Some(&mut *node_ptr);
+            }
+        }
+
+        unsafe {
+            slab.write(Slab {
+                free_list,
+                free_blocks: blocks_per_slab(index),
+                prev: ptr::null_mut(),
+                next: ptr::null_mut(),
+            });
+        }
+        slab
+    }
+
+    fn push_slab(&mut self, index: usize, slab: *mut Slab) {
+        unsafe {
+            (*slab).prev = ptr::null_mut();
+            (*slab).next = self.partial_slabs[index];
+            if let Some(next) = (*slab).next.as_mut() {
+                next.prev = slab;
+            }
+        }
+        self.partial_slabs[index] = slab;
+    }
+
+    fn unlink_slab(&mut self, index: usize, slab: *mut Slab) {
+        unsafe {
+            let (prev, next) = ((*slab).prev, (*slab).next);
+            match prev.as_mut() {
+                Some(prev) => prev.next = next,
+                None => self.partial_slabs[index] = next,
+            }
+            if let Some(next) = next.as_mut() {
+                next.prev = prev;
+            }
+            (*slab).prev = ptr::null_mut();
+            (*slab).next = ptr::null_mut();
+        }
+    }
+
     /// Initialize the allocator with the given heap bounds.
     ///
     /// This function is unsafe because the caller must guarantee that the given
@@ -43,49 +205,70 @@ impl FixedSizeBlockAllocator {
     pub unsafe fn init(&mut self, heap_start: usize, heap_size: usize) {
         unsafe { self.fallback_allocator.init(heap_start, heap_size); }
     }
+
+    /// Counts the free blocks in each class's slabs and measures the fallback
+    /// heap. Takes `&mut self` because measuring means trying allocations.
+    pub fn stats(&mut self) -> HeapStats {
+        let mut free_blocks = [0; BLOCK_SIZES.len()];
+        for (count, &head) in free_blocks.iter_mut().zip(self.partial_slabs.iter()) {
+            let mut current = head;
+            while let Some(slab) = unsafe { current.as_ref() } {
+                *count += slab.free_blocks;
+                current = slab.next;
+            }
+        }
+
+        HeapStats {
+            counters: self.counters,
+            free_bytes: self.fallback_allocator.free(),
+            largest_free_block: self.largest_fallback_block(),
+            free_blocks: Some(free_blocks),
+        }
+    }
+
+    /// Size of the largest allocation the fallback heap can serve right now.
+    /// The heap doesn't expose its holes, so this binary searches with real
+    /// allocations that are handed back straight away.
+    fn largest_fallback_block(&mut self) -> usize {
+        let (mut low, mut high) = (0, self.fallback_allocator.free());
+        while low < high {
+            let size = low + (high - low).div_ceil(2);
+            let layout = Layout::from_size_align(size, mem::align_of::<usize>()).unwrap();
+            match self.fallback_allocator.allocate_first_fit(layout) {
+                Ok(ptr) => {
+                    unsafe { self.fallback_allocator.deallocate(ptr, layout) };
+                    low = size;
+                }
+                Err(()) => high = size - 1,
+            }
+        }
+        low
+    }
 }
 
 unsafe impl GlobalAlloc for Locked<FixedSizeBlockAllocator> {
     unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
         let mut allocator = self.lock();
-        match list_index(&layout) {
-            Some(index) => {
-                match allocator.list_heads[index].take() {
-                    Some(node) => {
-                        allocator.list_heads[index] = node.next.take();
-                        node as *mut ListNode as *mut u8
-                    }
-                    None => {
-                        // no block exists in list => allocate new block
-                        let block_size = BLOCK_SIZES[index];
-                        // only works if all block sizes are a power of 2
-                        let block_align = block_size;
-                        let layout = Layout::from_size_align(block_size, block_align)
-                            .unwrap();
-                        allocator.fallback_alloc(layout)
-                    }
-                }
-            }
+        let ptr = match list_index(&layout) {
+            Some(index) => allocator.alloc_block(index),
             None => allocator.fallback_alloc(layout),
+        };
+
+        if !ptr.is_null() {
+            allocator.counters.record_alloc(layout.size());
         }
+        ptr
     }
 
-
     unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
         let mut allocator = self.lock();
+        allocator.counters.record_free(layout.size());
         match list_index(&layout) {
             Some(index) => {
-                let new_node = ListNode {
-                    next: allocator.list_heads[index].take(),
-                };
                 // verify that block has size and alignment required for storing node
                 assert!(mem::size_of::<ListNode>() <= BLOCK_SIZES[index]);
                 assert!(mem::align_of::<ListNode>() <= BLOCK_SIZES[index]);
-                let new_node_ptr = ptr as *mut ListNode;
-                unsafe {
-                    new_node_ptr.write(new_node);
-                    allocator.list_heads[index] = Some(&mut *new_node_ptr);
-                }
+                unsafe { allocator.dealloc_block(ptr, index) }
             }
             None => {
                 let ptr = NonNull::new(ptr).unwrap();
@@ -95,4 +278,118 @@ unsafe impl GlobalAlloc for Locked<FixedSizeBlockAllocator> {
             }
         }
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::allocator::Arena;
+
+    fn fixed_size_block_over(arena: &mut Arena) -> Locked<FixedSizeBlockAllocator> {
+        let allocator = Locked::new(FixedSizeBlockAllocator::new());
+        unsafe { allocator.lock().init(arena.start(), arena.size()) };
+        allocator
+    }
+
+    #[test_case]
+    fn layouts_map_to_the_smallest_fitting_block() {
+        assert_eq!(list_index(&Layout::from_size_align(1, 1).unwrap()), Some(0));
+        assert_eq!(list_index(&Layout::from_size_align(8, 8).unwrap()), Some(0));
+        assert_eq!(list_index(&Layout::from_size_align(9, 8).unwrap()), Some(1));
+        assert_eq!(list_index(&Layout::from_size_align(4, 64).unwrap()), Some(3));
+        assert_eq!(list_index(&Layout::from_size_align(2048, 8).unwrap()), Some(8));
+        assert_eq!(list_index(&Layout::from_size_align(2049, 8).unwrap()), None);
+    }
+
+    #[test_case]
+    fn freed_blocks_are_reused() {
+        let mut arena = Arena::new(16 * 1024);
+        let allocator = fixed_size_block_over(&mut arena);
+        let layout = Layout::from_size_align(48, 8).unwrap();
+
+        let a = unsafe { allocator.alloc(layout) };
+        assert!(!a.is_null());
+        assert_eq!(a as usize % 64, 0);
+        unsafe { allocator.dealloc(a, layout) };
+        assert_eq!(unsafe { allocator.alloc(layout) }, a);
+    }
+
+    #[test_case]
+    fn stats_track_counters_and_free_lists() {
+        let mut arena = Arena::new(16 * 1024);
+        let allocator = fixed_size_block_over(&mut arena);
+        let small = Layout::from_size_align(10, 8).unwrap();
+        let large = Layout::from_size_align(4096, 8).unwrap();
+
+        let a = unsafe { allocator.alloc(small) };
+        let b = unsafe { allocator.alloc(large) };
+        let stats = allocator.lock().stats();
+        assert_eq!(stats.counters.allocations, 2);
+        assert_eq!(stats.counters.bytes_in_use, 10 + 4096);
+
+        unsafe {
+            allocator.dealloc(a, small);
+            allocator.dealloc(b, large);
+        }
+        let stats = allocator.lock().stats();
+        assert_eq!(stats.counters.frees, 2);
+        assert_eq!(stats.counters.bytes_in_use, 0);
+        assert_eq!(stats.counters.peak_bytes_in_use, 10 + 4096);
+        assert_eq!(stats.free_blocks.unwrap()[1], blocks_per_slab(1));
+        assert!(stats.largest_free_block >= 4096);
+        assert!(stats.largest_free_block <= stats.free_bytes);
+    }
+
+    #[test_case]
+    fn large_allocations_use_the_fallback() {
+        let mut arena = Arena::new(16 * 1024);
+        let allocator = fixed_size_block_over(&mut arena);
+        let layout = Layout::from_size_align(4096, 8).unwrap();
+
+        let a = unsafe { allocator.alloc(layout) };
+        assert!(!a.is_null());
+        unsafe { allocator.dealloc(a, layout) };
+        assert!(allocator.lock().partial_slabs.iter().all(|slab| slab.is_null()));
+        assert_eq!(unsafe { allocator.alloc(layout) }, a);
+    }
+
+    #[test_case]
+    fn empty_slabs_go_back_to_the_fallback_heap() {
+        let mut arena = Arena::new(64 * 1024);
+        let allocator = fixed_size_block_over(&mut arena);
+        let small = Layout::from_size_align(64, 8).unwrap();
+
+        // like `memtest` creating lots of nodes, spread over many slabs
+        let mut ptrs = [ptr::null_mut(); 512];
+        for ptr in ptrs.iter_mut() {
+            *ptr = unsafe { allocator.alloc(small) };
+            assert!(!ptr.is_null());
+        }
+        for ptr in ptrs {
+            unsafe { allocator.dealloc(ptr, small) };
+        }
+
+        // only the one slab kept around for the class is missing
+        let stats = allocator.lock().stats();
+        assert_eq!(stats.free_blocks.unwrap()[3], blocks_per_slab(3));
+        assert_eq!(stats.free_bytes, arena.size() - slab_layout(3).size());
+
+        // more than was left after the slabs, but less than they took
+        let large = Layout::from_size_align(30 * 1024, 8).unwrap();
+        let ptr = unsafe { allocator.alloc(large) };
+        assert!(!ptr.is_null());
+        unsafe { allocator.dealloc(ptr, large) };
+    }
+
+    #[test_case]
+    fn blocks_fit_in_their_slab() {
+        for (index, &block_size) in BLOCK_SIZES.iter().enumerate() {
+            let layout = slab_layout(index);
+            assert!(layout.size() >= SLAB_SIZE);
+            assert!(blocks_per_slab(index) >= MIN_BLOCKS_PER_SLAB - 1);
+            assert!(first_block_offset(index) >= mem::size_of::<Slab>());
+            assert_eq!(first_block_offset(index) % block_size, 0);
+            assert!(first_block_offset(index) + blocks_per_slab(index) * block_size <= layout.size());
+        }
+    }
+}
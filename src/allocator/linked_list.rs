@@ -1,4 +1,5 @@
-use super::{align_up, Locked};
+use super::{align_up, grow_heap, Locked};
+use super::stats::{AllocCounters, HeapStats};
 use core::{mem, ptr};
 use alloc::alloc::{GlobalAlloc, Layout};
 
@@ -21,8 +22,43 @@ impl ListNode {
     }
 }
 
+/// How `find_region` picks among the free regions an allocation fits in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FitStrategy {
+    /// The lowest address. Simple, and keeps the top of the heap free.
+    FirstFit,
+    /// The smallest region, leaving big regions for big requests.
+    BestFit,
+    /// The first one after the previous allocation, wrapping around.
+    /// Spreads allocations over the heap instead of piling them up in front.
+    NextFit,
+}
+
+impl FitStrategy {
+    /// Name used on the kernel command line (`fit=<name>`).
+    pub fn name(self) -> &'static str {
+        match self {
+            FitStrategy::FirstFit => "first",
+            FitStrategy::BestFit => "best",
+            FitStrategy::NextFit => "next",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<FitStrategy> {
+        [FitStrategy::FirstFit, FitStrategy::BestFit, FitStrategy::NextFit]
+            .iter()
+            .copied()
+            .find(|strategy| strategy.name() == name)
+    }
+}
+
 pub struct LinkedListAllocator {
     head: ListNode,
+    heap_end: usize,
+    counters: AllocCounters,
+    strategy: FitStrategy,
+    /// Where the last allocation ended, for `FitStrategy::NextFit`.
+    next_fit: usize,
 }
 
 impl LinkedListAllocator {
@@ -30,6 +66,10 @@ impl LinkedListAllocator {
     pub const fn new() -> Self {
         Self {
             head: ListNode::new(0),
+            heap_end: 0,
+            counters: AllocCounters::new(),
+            strategy: FitStrategy::FirstFit,
+            next_fit: 0,
         }
     }
 
@@ -42,29 +82,86 @@ impl LinkedListAllocator {
         unsafe {
             self.add_free_region(heap_start, heap_size);
         }
+        self.heap_end = heap_start + heap_size;
     }
 
-    /// Adds the given memory region to the front of the list.
+    /// Grows the heap by at least `min_size` bytes and adds the new memory to
+    /// the free list. Returns `false` if the heap couldn't grow.
+    fn grow(&mut self, min_size: usize) -> bool {
+        let grown = grow_heap(self.heap_end, min_size);
+        if grown == 0 {
+            return false;
+        }
+
+        unsafe { self.add_free_region(self.heap_end, grown) };
+        self.heap_end += grown;
+        true
+    }
 
+    /// Adds the given memory region to the list, which is kept sorted by
+    /// address, and merges it with the free regions right before and after.
     unsafe fn add_free_region(&mut self, addr: usize, size: usize) {
         // ensure that the freed region is capable of holding ListNode
         assert_eq!(align_up(addr, mem::align_of::<ListNode>()), addr);
         assert!(size >= mem::size_of::<ListNode>());
 
-        // create a new list node and append it at the start of the list
+        // find the last region that starts before the new one
+        let mut current = &mut self.head;
+        let mut at_head = true;
+        while current.next.as_ref().is_some_and(|next| next.start_addr() < addr) {
+            current = current.next.as_mut().unwrap();
+            at_head = false;
+        }
+
+        // swallow the region right after
+        let mut size = size;
+        let mut next = current.next.take();
+        if let Some(successor) = next.take() {
+            if addr + size == successor.start_addr() {
+                size += successor.size;
+                next = successor.next.take();
+            } else {
+                next = Some(successor);
+            }
+        }
+
+        // or grow the region right before
+        if !at_head && current.end_addr() == addr {
+            current.size += size;
+            current.next = next;
+            return;
+        }
+
         let mut node = ListNode::new(size);
-        node.next = self.head.next.take();
+        node.next = next;
         let node_ptr = addr as *mut ListNode;
         unsafe {
             node_ptr.write(node);
-            self.head.next = Some(&mut *node_ptr)
+            current.next = Some(&mut *node_ptr)
         }
     }
 
+    /// Changes how `find_region` picks among the regions that fit.
+    pub fn set_strategy(&mut self, strategy: FitStrategy) {
+        self.strategy = strategy;
+    }
+
+    pub fn strategy(&self) -> FitStrategy {
+        self.strategy
+    }
+
+    /// Returns where an allocation would start in `region`, or an error if
+    /// it doesn't fit.
     fn alloc_from_region(region: &ListNode, size: usize, align: usize)
                          -> Result<usize, ()>
     {
-        let alloc_start = align_up(region.start_addr(), align);
+        let mut alloc_start = align_up(region.start_addr(), align);
+        let front_size = alloc_start - region.start_addr();
+        if front_size > 0 && front_size < mem::size_of::<ListNode>() {
+            // the padding in front goes back on the list, so it has to be
+            // able to hold a ListNode too
+            alloc_start = align_up(region.start_addr() + mem::size_of::<ListNode>(), align);
+        }
         let alloc_end = alloc_start.checked_add(size).ok_or(())?;
 
         if alloc_end > region.end_addr() {
@@ -83,27 +180,80 @@ impl LinkedListAllocator {
         Ok(alloc_start)
     }
 
+    /// Picks a region for the allocation according to the fit strategy and
+    /// returns its start address and where the allocation would start.
+    fn choose_region(&self, size: usize, align: usize) -> Option<(usize, usize)> {
+        let mut first = None;
+        let mut best: Option<(usize, usize, usize)> = None;
+
+        let mut current = self.head.next.as_deref();
+        while let Some(region) = current {
+            if let Ok(alloc_start) = Self::alloc_from_region(region, size, align) {
+                let candidate = (region.start_addr(), alloc_start);
+                match self.strategy {
+                    FitStrategy::FirstFit => return Some(candidate),
+                    FitStrategy::NextFit if region.end_addr() > self.next_fit => {
+                        return Some(candidate);
+                    }
+                    FitStrategy::NextFit => {
+                        // nothing past the cursor yet, remember where to wrap to
+                        first = first.or(Some(candidate));
+                    }
+                    FitStrategy::BestFit => {
+                        if best.is_none_or(|(_, _, best_size)| region.size < best_size) {
+                            best = Some((candidate.0, candidate.1, region.size));
+                        }
+                    }
+                }
+            }
+            current = region.next.as_deref();
+        }
+
+        match self.strategy {
+            FitStrategy::BestFit => best.map(|(start, alloc_start, _)| (start, alloc_start)),
+            _ => first,
+        }
+    }
+
+    /// Looks for a free region with the given size and alignment and removes
+    /// it from the list.
+    ///
+    /// Returns a tuple of the list node and the start address of the
+    /// allocation.
     fn find_region(&mut self, size: usize, align: usize)
                    -> Option<(&'static mut ListNode, usize)>
     {
+        let (region_start, alloc_start) = self.choose_region(size, align)?;
+
         // reference to current list node, updated for each iteration
         let mut current = &mut self.head;
-        // look for a large enough memory region in linked list
-        while let Some(ref mut region) = current.next {
-            if let Ok(alloc_start) = Self::alloc_from_region(&region, size, align) {
-                // region suitable for allocation -> remove node from list
-                let next = region.next.take();
-                let ret = Some((current.next.take().unwrap(), alloc_start));
-                current.next = next;
-                return ret;
-            } else {
-                // region not suitable -> continue with next region
-                current = current.next.as_mut().unwrap();
-            }
+        while current.next.as_ref().is_some_and(|next| next.start_addr() != region_start) {
+            current = current.next.as_mut().unwrap();
+        }
+
+        // remove the chosen node from the list
+        let region = current.next.take().unwrap();
+        current.next = region.next.take();
+        Some((region, alloc_start))
+    }
+
+    /// Walks the free list to sum up free memory and find the largest region.
+    pub fn stats(&self) -> HeapStats {
+        let mut free_bytes = 0;
+        let mut largest_free_block = 0;
+        let mut current = self.head.next.as_deref();
+        while let Some(region) = current {
+            free_bytes += region.size;
+            largest_free_block = largest_free_block.max(region.size);
+            current = region.next.as_deref();
         }
 
-        // no suitable region found
-        None
+        HeapStats {
+            counters: self.counters,
+            free_bytes,
+            largest_free_block,
+            free_blocks: None,
+        }
     }
 
     fn size_align(layout: Layout) -> (usize, usize) {
@@ -122,7 +272,14 @@ unsafe impl GlobalAlloc for Locked<LinkedListAllocator> {
         let (size, align) = LinkedListAllocator::size_align(layout);
         let mut allocator = self.lock();
 
-        if let Some((region, alloc_start)) = allocator.find_region(size, align) {
+        let mut found = allocator.find_region(size, align);
+        // a fresh region may need padding for the alignment
+        if found.is_none() && allocator.grow(size + align) {
+            found = allocator.find_region(size, align);
+        }
+
+        if let Some((region, alloc_start)) = found {
+            let region_start = region.start_addr();
             let alloc_end = alloc_start.checked_add(size).expect("overflow");
             let excess_size = region.end_addr() - alloc_end;
             if excess_size > 0 {
@@ -130,6 +287,13 @@ unsafe impl GlobalAlloc for Locked<LinkedListAllocator> {
                     allocator.add_free_region(alloc_end, excess_size);
                 }
             }
+            if alloc_start > region_start {
+                unsafe {
+                    allocator.add_free_region(region_start, alloc_start - region_start);
+                }
+            }
+            allocator.next_fit = alloc_end;
+            allocator.counters.record_alloc(layout.size());
             alloc_start as *mut u8
         } else {
             ptr::null_mut()
@@ -140,6 +304,168 @@ unsafe impl GlobalAlloc for Locked<LinkedListAllocator> {
         // perform layout adjustments
         let (size, _) = LinkedListAllocator::size_align(layout);
 
-        unsafe { self.lock().add_free_region(ptr as usize, size) }
+        let mut allocator = self.lock();
+        allocator.counters.record_free(layout.size());
+        unsafe { allocator.add_free_region(ptr as usize, size) }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::allocator::Arena;
+
+    fn linked_list_over(arena: &mut Arena) -> Locked<LinkedListAllocator> {
+        let allocator = Locked::new(LinkedListAllocator::new());
+        unsafe { allocator.lock().init(arena.start(), arena.size()) };
+        allocator
+    }
+
+    #[test_case]
+    fn allocations_do_not_overlap() {
+        let mut arena = Arena::new(4096);
+        let allocator = linked_list_over(&mut arena);
+        let layout = Layout::from_size_align(100, 8).unwrap();
+
+        let a = unsafe { allocator.alloc(layout) } as usize;
+        let b = unsafe { allocator.alloc(layout) } as usize;
+        assert!(a != 0 && b != 0);
+        assert!(a + 100 <= b || b + 100 <= a);
+    }
+
+    #[test_case]
+    fn alignment_is_respected() {
+        let mut arena = Arena::new(4096);
+        let allocator = linked_list_over(&mut arena);
+
+        unsafe { allocator.alloc(Layout::from_size_align(24, 8).unwrap()) };
+        let aligned = unsafe { allocator.alloc(Layout::from_size_align(32, 256).unwrap()) };
+        assert!(!aligned.is_null());
+        assert_eq!(aligned as usize % 256, 0);
+    }
+
+    #[test_case]
+    fn freed_memory_is_reused() {
+        let mut arena = Arena::new(4096);
+        let allocator = linked_list_over(&mut arena);
+        let layout = Layout::from_size_align(1024, 8).unwrap();
+
+        // more than fits in the arena at once
+        for _ in 0..16 {
+            let ptr = unsafe { allocator.alloc(layout) };
+            assert!(!ptr.is_null());
+            unsafe { allocator.dealloc(ptr, layout) };
+        }
+    }
+
+    #[test_case]
+    fn stats_see_every_free_region() {
+        let mut arena = Arena::new(4096);
+        let allocator = linked_list_over(&mut arena);
+        let layout = Layout::from_size_align(512, 8).unwrap();
+
+        let a = unsafe { allocator.alloc(layout) };
+        let b = unsafe { allocator.alloc(layout) };
+        unsafe { allocator.dealloc(a, layout) };
+
+        let stats = allocator.lock().stats();
+        assert_eq!(stats.counters.allocations, 2);
+        assert_eq!(stats.counters.frees, 1);
+        assert_eq!(stats.counters.bytes_in_use, 512);
+        assert_eq!(stats.free_bytes, arena.size() - 512);
+        assert_eq!(stats.largest_free_block, arena.size() - 1024);
+        assert!(stats.fragmentation_percent() > 0);
+
+        unsafe { allocator.dealloc(b, layout) };
+    }
+
+    #[test_case]
+    fn freed_neighbours_merge_back() {
+        let mut arena = Arena::new(4096);
+        let allocator = linked_list_over(&mut arena);
+        let layout = Layout::from_size_align(256, 8).unwrap();
+
+        let ptrs = [(); 4].map(|_| unsafe { allocator.alloc(layout) });
+        // free out of order, so merges with both neighbours are needed
+        for i in [1, 3, 0, 2] {
+            unsafe { allocator.dealloc(ptrs[i], layout) };
+        }
+
+        let stats = allocator.lock().stats();
+        assert_eq!(stats.free_bytes, arena.size());
+        assert_eq!(stats.largest_free_block, arena.size());
+    }
+
+    #[test_case]
+    fn free_list_is_sorted_by_address() {
+        let mut arena = Arena::new(4096);
+        let allocator = linked_list_over(&mut arena);
+        let layout = Layout::from_size_align(128, 8).unwrap();
+
+        let ptrs = [(); 6].map(|_| unsafe { allocator.alloc(layout) });
+        for i in [4, 0, 2] {
+            unsafe { allocator.dealloc(ptrs[i], layout) };
+        }
+
+        let allocator = allocator.lock();
+        let mut last = 0;
+        let mut current = allocator.head.next.as_deref();
+        while let Some(region) = current {
+            assert!(region.start_addr() > last);
+            last = region.end_addr();
+            current = region.next.as_deref();
+        }
+    }
+
+    #[test_case]
+    fn best_fit_picks_the_smallest_hole() {
+        let mut arena = Arena::new(4096);
+        let allocator = linked_list_over(&mut arena);
+        allocator.lock().set_strategy(FitStrategy::BestFit);
+        let big = Layout::from_size_align(512, 8).unwrap();
+        let small = Layout::from_size_align(128, 8).unwrap();
+
+        // holes of 512 and 128 bytes, kept apart by live allocations
+        let a = unsafe { allocator.alloc(big) };
+        unsafe { allocator.alloc(small) };
+        let b = unsafe { allocator.alloc(small) };
+        unsafe { allocator.alloc(small) };
+        unsafe { allocator.dealloc(a, big) };
+        unsafe { allocator.dealloc(b, small) };
+
+        assert_eq!(unsafe { allocator.alloc(small) }, b);
     }
-}
\ No newline at end of file
+
+    #[test_case]
+    fn next_fit_continues_after_the_last_allocation() {
+        let mut arena = Arena::new(4096);
+        let allocator = linked_list_over(&mut arena);
+        allocator.lock().set_strategy(FitStrategy::NextFit);
+        let layout = Layout::from_size_align(128, 8).unwrap();
+
+        let a = unsafe { allocator.alloc(layout) };
+        let b = unsafe { allocator.alloc(layout) };
+        unsafe { allocator.dealloc(a, layout) };
+
+        // first fit would reuse `a`
+        let c = unsafe { allocator.alloc(layout) };
+        assert!(c as usize > b as usize);
+    }
+
+    #[test_case]
+    fn fit_names_round_trip() {
+        for strategy in [FitStrategy::FirstFit, FitStrategy::BestFit, FitStrategy::NextFit] {
+            assert_eq!(FitStrategy::from_name(strategy.name()), Some(strategy));
+        }
+        assert_eq!(FitStrategy::from_name("worst"), None);
+    }
+
+    #[test_case]
+    fn out_of_memory_returns_null() {
+        let mut arena = Arena::new(4096);
+        let allocator = linked_list_over(&mut arena);
+
+        let too_big = Layout::from_size_align(arena.size() + 8, 8).unwrap();
+        assert!(unsafe { allocator.alloc(too_big) }.is_null());
+    }
+}
@@ -0,0 +1,93 @@
+use super::fixed_size_block::BLOCK_SIZES;
+
+/// Counters every allocator keeps up to date under its lock.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct AllocCounters {
+    pub allocations: usize,
+    pub frees: usize,
+    /// Bytes handed out and not freed yet, as requested by the callers.
+    pub bytes_in_use: usize,
+    /// Highest `bytes_in_use` seen so far.
+    pub peak_bytes_in_use: usize,
+}
+
+impl AllocCounters {
+    pub const fn new() -> Self {
+        AllocCounters {
+            allocations: 0,
+            frees: 0,
+            bytes_in_use: 0,
+            peak_bytes_in_use: 0,
+        }
+    }
+
+    pub fn record_alloc(&mut self, size: usize) {
+        self.allocations += 1;
+        self.bytes_in_use += size;
+        self.peak_bytes_in_use = self.peak_bytes_in_use.max(self.bytes_in_use);
+    }
+
+    pub fn record_free(&mut self, size: usize) {
+        self.frees += 1;
+        self.bytes_in_use -= size;
+    }
+}
+
+/// A snapshot of an allocator's state.
+#[derive(Debug, Clone, Copy)]
+pub struct HeapStats {
+    pub counters: AllocCounters,
+    /// Free bytes in the general purpose heap (the fallback heap for the
+    /// fixed-size block allocator).
+    pub free_bytes: usize,
+    /// Largest single allocation that fits in `free_bytes` without growing
+    /// the heap.
+    pub largest_free_block: usize,
+    /// Free blocks in the slabs of each `BLOCK_SIZES` class, for the
+    /// fixed-size block allocator only.
+    pub free_blocks: Option<[usize; BLOCK_SIZES.len()]>,
+}
+
+impl HeapStats {
+    /// How much of the free memory is unusable for the largest request it
+    /// could otherwise serve, from 0 (one contiguous block) to 100.
+    pub fn fragmentation_percent(&self) -> usize {
+        let largest = self.largest_free_block.min(self.free_bytes);
+        match (largest * 100).checked_div(self.free_bytes) {
+            Some(usable_percent) => 100 - usable_percent,
+            None => 0,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn stats_with(free_bytes: usize, largest_free_block: usize) -> HeapStats {
+        HeapStats {
+            counters: AllocCounters::new(),
+            free_bytes,
+            largest_free_block,
+            free_blocks: None,
+        }
+    }
+
+    #[test_case]
+    fn peak_survives_frees() {
+        let mut counters = AllocCounters::new();
+        counters.record_alloc(100);
+        counters.record_alloc(50);
+        counters.record_free(100);
+        counters.record_alloc(20);
+        assert_eq!(counters.bytes_in_use, 70);
+        assert_eq!(counters.peak_bytes_in_use, 150);
+    }
+
+    #[test_case]
+    fn fragmentation_is_a_percentage() {
+        assert_eq!(stats_with(0, 0).fragmentation_percent(), 0);
+        assert_eq!(stats_with(1000, 1000).fragmentation_percent(), 0);
+        assert_eq!(stats_with(1000, 250).fragmentation_percent(), 75);
+    }
+}
@@ -1,10 +1,26 @@
 use spin::Mutex;
 use lazy_static::lazy_static;
 use crate::drivers::vga_buffer::{WRITER, BUFFER_WIDTH, Color, ALL_COLORS};
-use crate::{os_info, println};
+use crate::{os_info, print, println, serial_print};
 use pc_keyboard::KeyCode;
-use crate::ramfs;
+use crate::vfs;
 use alloc::string::{String, ToString};
+use core::fmt::{self, Write};
+use core::sync::atomic::{AtomicBool, Ordering};
+use core::time::Duration;
+use crate::power::PowerAction;
+use crate::time;
+use x86_64::structures::paging::PageTableFlags;
+use x86_64::VirtAddr;
+
+/// Set while `execute_command` runs, so fault handlers know whether there is
+/// a shell to go back to.
+static COMMAND_RUNNING: AtomicBool = AtomicBool::new(false);
+
+/// Returns `true` if a shell command is currently executing.
+pub fn command_running() -> bool {
+    COMMAND_RUNNING.load(Ordering::SeqCst)
+}
 
 pub fn get_color_by_name(name: &str) -> Option<Color> {
     match name {
@@ -28,27 +44,142 @@ pub fn get_color_by_name(name: &str) -> Option<Color> {
     }
 }
 
-fn num_to_string(mut num: usize) -> String {
-    if num == 0 {
-        return String::from("0");
+/// Prints `bytes` as UTF-8, and bytes that aren't as Latin-1, except for a
+/// character cut off at the end. Returns how many bytes that one has.
+fn print_text_prefix(mut bytes: &[u8]) -> usize {
+    loop {
+        match core::str::from_utf8(bytes) {
+            Ok(text) => {
+                print!("{}", text);
+                return 0;
+            }
+            Err(err) => {
+                let (valid, rest) = bytes.split_at(err.valid_up_to());
+                print!("{}", core::str::from_utf8(valid).unwrap_or_default());
+                match err.error_len() {
+                    Some(len) => {
+                        rest[..len].iter().for_each(|&byte| print!("{}", byte as char));
+                        bytes = &rest[len..];
+                    }
+                    None => return rest.len(),
+                }
+            }
+        }
+    }
+}
+
+/// Prints `bytes` as UTF-8, and bytes that aren't as Latin-1.
+fn print_text(bytes: &[u8]) {
+    let cut_off = print_text_prefix(bytes);
+    bytes[bytes.len() - cut_off..].iter().for_each(|&byte| print!("{}", byte as char));
+}
+
+/// Parses a hexadecimal address, with or without a `0x` prefix. `_` can
+/// be used to group digits.
+fn parse_address(text: &str) -> Option<u64> {
+    let digits = text.strip_prefix("0x").unwrap_or(text);
+    let mut value = None;
+    for c in digits.chars().filter(|&c| c != '_') {
+        let digit = u64::from(c.to_digit(16)?);
+        value = Some(value.unwrap_or(0u64).checked_mul(16)?.checked_add(digit)?);
+    }
+    value
+}
+
+/// Page table flags in the `rwx` style, plus whatever else is set that
+/// matters for kernel mappings.
+struct ShortFlags(PageTableFlags);
+
+impl fmt::Display for ShortFlags {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let flags = self.0;
+        let parts = [
+            "r",
+            if flags.contains(PageTableFlags::WRITABLE) { "w" } else { "-" },
+            if flags.contains(PageTableFlags::NO_EXECUTE) { "-" } else { "x" },
+            if flags.contains(PageTableFlags::USER_ACCESSIBLE) { " user" } else { "" },
+            if flags.contains(PageTableFlags::NO_CACHE) { " uncached" } else { "" },
+            if flags.contains(PageTableFlags::GLOBAL) { " global" } else { "" },
+        ];
+        // put together on the stack so `pad` can honour width and alignment
+        let mut buf = [0; 24];
+        let mut len = 0;
+        for part in parts {
+            buf[len..len + part.len()].copy_from_slice(part.as_bytes());
+            len += part.len();
+        }
+        f.pad(core::str::from_utf8(&buf[..len]).map_err(|_| fmt::Error)?)
+    }
+}
+
+/// File type and permission bits in the `ls -l` style, like `drwxr-xr-x`.
+struct Permissions(vfs::FileType, u16);
+
+impl fmt::Display for Permissions {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(if self.0 == vfs::FileType::Dir { "d" } else { "-" })?;
+        for shift in [6, 3, 0] {
+            let bits = self.1 >> shift;
+            f.write_str(if bits & 0o4 != 0 { "r" } else { "-" })?;
+            f.write_str(if bits & 0o2 != 0 { "w" } else { "-" })?;
+            f.write_str(if bits & 0o1 != 0 { "x" } else { "-" })?;
+        }
+        Ok(())
     }
+}
 
-    let mut buf = [0u8; 20]; // Enough for 64-bit numbers
-    let mut i = 20;
-    while num > 0 {
-        i -= 1;
-        buf[i] = b'0' + (num % 10) as u8;
-        num /= 10;
+/// A ramfs timestamp, which counts from boot.
+struct Timestamp(Duration);
+
+impl fmt::Display for Timestamp {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}.{:03}s", self.0.as_secs(), self.0.subsec_millis())
     }
+}
 
-    // Convert the relevant slice to String
-    let mut s = String::new();
-    for &b in &buf[i..] {
-        s.push(b as char);
+/// Prints one `ls` line. Directories get a trailing `/`.
+fn print_dir_entry(name: &str, metadata: &vfs::Metadata, long: bool) {
+    let marker = if metadata.file_type == vfs::FileType::Dir { "/" } else { "" };
+    if long {
+        println!(
+            "{} {:>5} {:>5} {:>8} {:>12} {}{}",
+            Permissions(metadata.file_type, metadata.mode),
+            metadata.inode,
+            metadata.owner,
+            metadata.size,
+            Timestamp(metadata.modified),
+            name,
+            marker
+        );
+    } else {
+        println!(" - {}{}", name, marker);
     }
-    s
 }
 
+/// Names the part of the address space `addr` belongs to, for `ptdump`.
+fn address_space_name(addr: u64) -> &'static str {
+    use crate::allocator::{HEAP_MAX_SIZE, HEAP_START};
+    use crate::memory::vmm::{VMM_SIZE, VMM_START};
+
+    let heap = HEAP_START as u64..(HEAP_START + HEAP_MAX_SIZE) as u64;
+    let vmm = VMM_START..VMM_START + VMM_SIZE;
+    let physical = crate::memory::physical_memory_offset().map(|offset| {
+        let end = crate::memory::memory_map()
+            .and_then(|map| map.iter().map(|region| region.range.end_addr()).max())
+            .unwrap_or(0);
+        offset.as_u64()..offset.as_u64() + end
+    });
+
+    if heap.contains(&addr) {
+        "heap"
+    } else if vmm.contains(&addr) {
+        "vmm"
+    } else if physical.is_some_and(|physical| physical.contains(&addr)) {
+        "physical memory"
+    } else {
+        ""
+    }
+}
 
 const PROMPT: &str = "> ";
 const PROMPT_LEN: usize = 2;
@@ -60,12 +191,8 @@ pub struct Cli {
     active: bool,
     prompt_row: usize,
     current_dir: String,
-}
-
-fn delay() {
-    for _ in 0..1_000_000 {
-        x86_64::instructions::nop();
-    }
+    /// Set by `bye` and `oops`, for the keyboard task to carry out.
+    power_action: Option<PowerAction>,
 }
 
 impl Cli {
@@ -77,15 +204,30 @@ impl Cli {
             active: false,
             prompt_row: 0,
             current_dir: "/".to_string(),
+            power_action: None,
         }
     }
 
+    /// The shutdown or reboot the last command asked for. Waiting for it
+    /// must not happen with the CLI locked, see [`run_power_action`].
+    pub fn take_power_action(&mut self) -> Option<PowerAction> {
+        self.power_action.take()
+    }
+
     pub fn activate(&mut self) {
         self.active = true;
         self.clear_input();
         self.display_prompt();
     }
 
+    /// Drops the command that was running and shows a fresh prompt. Used when
+    /// a command is cut short by a CPU exception.
+    pub fn abort_command(&mut self) {
+        COMMAND_RUNNING.store(false, Ordering::SeqCst);
+        self.clear_input();
+        self.display_prompt();
+    }
+
     fn clear_input(&mut self) {
         self.input_buffer = [0; 128];
         self.buffer_index = 0;
@@ -95,7 +237,9 @@ impl Cli {
     fn display_prompt(&mut self) {
         let mut writer = WRITER.lock();
         self.prompt_row = writer.cursor_row();
-        writer.write_string((self.current_dir.clone() + PROMPT).as_str());
+        writer.write_string(&self.current_dir);
+        writer.write_string(PROMPT);
+        serial_print!("{}{}", self.current_dir, PROMPT);
     }
 
     pub fn handle_input(&mut self, c: char) {
@@ -120,12 +264,14 @@ impl Cli {
                 if self.cursor_index > 0 {
                     self.cursor_index -= 1;
                     self.redraw_input();
+                    serial_print!("\x1b[D");
                 }
             }
             KeyCode::ArrowRight => {
                 if self.cursor_index < self.buffer_index {
                     self.cursor_index += 1;
                     self.redraw_input();
+                    serial_print!("\x1b[C");
                 }
             }
             _ => {}
@@ -136,7 +282,8 @@ impl Cli {
         let mut writer = WRITER.lock();
 
         // How many rows the input occupies
-        let total_len = (self.current_dir.clone() + PROMPT).as_str().len() + self.buffer_index;
+        let prompt_len = self.current_dir.len() + PROMPT.len();
+        let total_len = prompt_len + self.buffer_index;
         let rows = total_len / BUFFER_WIDTH + 1;
 
         // Clear all affected rows
@@ -149,7 +296,8 @@ impl Cli {
         writer.set_cursor(self.prompt_row, 0);
 
         // Draw prompt
-        writer.write_string((self.current_dir.clone() + PROMPT).as_str());
+        writer.write_string(&self.current_dir);
+        writer.write_string(PROMPT);
 
         // Draw input buffer
         for i in 0..self.buffer_index {
@@ -157,13 +305,26 @@ impl Cli {
         }
 
         // Absolute cursor positioning
-        let visual_index = (self.current_dir.clone() + PROMPT).as_str().len() + self.cursor_index;
+        let visual_index = prompt_len + self.cursor_index;
         let row_offset = visual_index / BUFFER_WIDTH;
         let col = visual_index % BUFFER_WIDTH;
 
         writer.set_cursor(self.prompt_row + row_offset, col);
     }
 
+    /// Redraws the input line on the serial terminal with ANSI escapes.
+    /// Typing at the end of the line is just echoed instead, which keeps the
+    /// output readable when the shell is scripted.
+    fn redraw_serial_input(&self) {
+        let input = core::str::from_utf8(&self.input_buffer[..self.buffer_index]).unwrap_or("");
+        serial_print!("\r\x1b[K{}{}{}", self.current_dir, PROMPT, input);
+
+        let behind = self.buffer_index - self.cursor_index;
+        if behind > 0 {
+            serial_print!("\x1b[{}D", behind);
+        }
+    }
+
 
     fn handle_char(&mut self, c: char) {
         if self.buffer_index >= self.input_buffer.len() - 1 {
@@ -174,11 +335,18 @@ impl Cli {
             self.input_buffer[i + 1] = self.input_buffer[i];
         }
 
+        let at_end = self.cursor_index == self.buffer_index;
+
         self.input_buffer[self.cursor_index] = c as u8;
         self.buffer_index += 1;
         self.cursor_index += 1;
 
         self.redraw_input();
+        if at_end {
+            serial_print!("{}", c);
+        } else {
+            self.redraw_serial_input();
+        }
     }
 
     fn handle_backspace(&mut self) {
@@ -190,15 +358,23 @@ impl Cli {
             self.input_buffer[i - 1] = self.input_buffer[i];
         }
 
+        let at_end = self.cursor_index == self.buffer_index;
+
         self.buffer_index -= 1;
         self.cursor_index -= 1;
         self.input_buffer[self.buffer_index] = 0;
 
         self.redraw_input();
+        if at_end {
+            serial_print!("\x08 \x08");
+        } else {
+            self.redraw_serial_input();
+        }
     }
 
     fn clear(&mut self) {
         WRITER.lock().clear_screen();
+        serial_print!("\x1b[2J\x1b[H");
     }
 
     fn execute_command(&mut self) {
@@ -207,6 +383,7 @@ impl Cli {
             .trim();
 
         println!();
+        COMMAND_RUNNING.store(true, Ordering::SeqCst);
 
         let mut parts = raw_input.splitn(2, ' ');
         let command = parts.next().unwrap_or("");
@@ -214,11 +391,82 @@ impl Cli {
 
         match command {
             "ls" => {
-                if let Some(entries) = ramfs::list_dir(&*self.current_dir, args) {
-                    for e in entries {
-                        println!(" - {}", e);
+                let (mut long, mut all, mut bad_flag) = (false, false, false);
+                let mut path = "";
+                for arg in args.split_whitespace() {
+                    match arg.strip_prefix('-') {
+                        Some(flags) => {
+                            for flag in flags.chars() {
+                                match flag {
+                                    'l' => long = true,
+                                    'a' => all = true,
+                                    _ => bad_flag = true,
+                                }
+                            }
+                        }
+                        None => path = arg,
                     }
                 }
+
+                match vfs::list_dir(&self.current_dir, path) {
+                    _ if bad_flag => println!("Usage: ls [-l] [-a] [path]"),
+                    Ok(entries) => {
+                        if all {
+                            let parent = vfs::resolve_path(&self.current_dir, path)
+                                .map_err(vfs::FsError::from)
+                                .and_then(|dir| vfs::stat(&dir, ".."));
+                            let dots = [(".", vfs::stat(&self.current_dir, path)), ("..", parent)];
+                            for (name, metadata) in dots {
+                                if let Ok(metadata) = metadata {
+                                    print_dir_entry(name, &metadata, long);
+                                }
+                            }
+                        }
+                        for entry in entries.iter().filter(|entry| all || !entry.name.starts_with('.')) {
+                            print_dir_entry(&entry.name, &entry.metadata, long);
+                        }
+                    }
+                    Err(err) => println!("ls: {}: {}", path, err),
+                }
+            },
+            "mount" => {
+                let mut words = args.split_whitespace();
+                match (words.next(), words.next(), words.next()) {
+                    (Some(fs_type), Some(path), None) => {
+                        if let Err(err) = vfs::mount(&self.current_dir, fs_type, path) {
+                            println!("mount: {}: {}", path, err);
+                        }
+                    }
+                    _ => println!("Usage: mount <type> <path>\nTypes: {}", vfs::FILESYSTEMS.join(", ")),
+                }
+            },
+            "umount" => {
+                if let Err(err) = vfs::umount(&self.current_dir, args) {
+                    println!("umount: {}: {}", args, err);
+                }
+            },
+            "mounts" => {
+                vfs::for_each_mount(|path, fs_type| println!("{:<8} {}", fs_type, path));
+            },
+            "stat" => {
+                match vfs::stat(&self.current_dir, args) {
+                    _ if args.is_empty() => println!("Usage: stat <path>"),
+                    Ok(metadata) => {
+                        let (kind, unit) = match metadata.file_type {
+                            vfs::FileType::File => ("file", "bytes"),
+                            vfs::FileType::Dir => ("directory", "entries"),
+                        };
+                        println!("    File: {}", args);
+                        println!("    Type: {}", kind);
+                        println!("    Size: {} {}", metadata.size, unit);
+                        println!("   Inode: {}", metadata.inode);
+                        println!("    Mode: {:04o} ({})", metadata.mode, Permissions(metadata.file_type, metadata.mode));
+                        println!("   Owner: {}", metadata.owner);
+                        println!(" Created: {} after boot", Timestamp(metadata.created));
+                        println!("Modified: {} after boot", Timestamp(metadata.modified));
+                    }
+                    Err(err) => println!("stat: {}: {}", args, err),
+                }
             },
             "banner" => {
                 println!("          _            ____   _____ ");
@@ -235,13 +483,17 @@ impl Cli {
 
                 loop {
                     // Generate unique filename: file_0.txt, file_1.txt, ...
-                    let mut filename = String::from("file_");
-                    filename.push_str(&num_to_string(file_index));
-                    filename.push_str(".txt");
+                    // reserved up front, so formatting it can't hit a full heap
+                    let mut filename = String::new();
+                    if filename.try_reserve(32).is_err() {
+                        println!("Out of memory after {} files", file_index);
+                        break;
+                    }
+                    let _ = write!(filename, "file_{}.txt", file_index);
 
-                    let success = ramfs::create_file(&*self.current_dir, &filename, "HEEsduhkghdfjkhdfkjghdfjkghdfkghdfkjghdfkjghdfkjghdfkjghdfghdfjkghdfjkghdfkghdfkjghdfkghdfjkghdfjkghdfjkghdfjkghdfjghdfkghdfjkghdfkjghdfjkghdfkjghdfjkghdfjkghdfkjghdfjkghdfkghdfjkhdfjkghdfjkghdfkhdfgjkdfgfgddfjkhdfjkdfgjkhdfg".as_ref());
-                    if !success {
-                        println!("Failed to create file {}", filename);
+                    let result = vfs::create_file(&*self.current_dir, &filename, "HEEsduhkghdfjkhdfkjghdfjkghdfkghdfkjghdfkjghdfkjghdfkjghdfghdfjkghdfjkghdfkghdfkjghdfkghdfjkghdfjkghdfjkghdfjkghdfjghdfkghdfjkghdfkjghdfjkghdfkjghdfjkghdfjkghdfkjghdfjkghdfkghdfjkhdfjkghdfjkghdfkhdfgjkdfgfgddfjkhdfjkdfgjkhdfg".as_ref());
+                    if let Err(err) = result {
+                        println!("Failed to create file {}: {}", filename, err);
                         break;
                     }
 
@@ -253,28 +505,301 @@ impl Cli {
                 }
 
                 println!("Stress test finished. Created {} files.", file_index);
+                println!("The heap is now {} KiB.", crate::allocator::heap_size() / 1024);
+            },
+            "ramtest" => {
+                use crate::memory::ramtest::{self, Mode};
+                const MAX_LISTED: usize = 20;
+                const PROGRESS_STEP: u64 = 64 * 1024 * 1024;
+
+                match Mode::from_name(if args.is_empty() { "quick" } else { args }) {
+                    None => println!("Usage: ramtest [quick|full]"),
+                    Some(_) if crate::memory::physical_memory_offset().is_none() => {
+                        println!("Paging isn't set up yet.");
+                    }
+                    Some(mode) => {
+                        println!("Testing free RAM ({} mode)...", mode.name());
+                        let mut listed = 0;
+                        let mut next_progress = PROGRESS_STEP;
+                        let result = ramtest::run(
+                            mode,
+                            |fault| {
+                                if listed < MAX_LISTED {
+                                    println!(
+                                        "Fault at {:#014x}: wrote {:#018x}, read {:#018x} ({})",
+                                        fault.addr.as_u64(), fault.expected, fault.found, fault.test.name()
+                                    );
+                                }
+                                listed += 1;
+                            },
+                            |tested| {
+                                if tested >= next_progress {
+                                    println!("Tested {} MiB so far...", tested / 1024 / 1024);
+                                    next_progress += PROGRESS_STEP;
+                                }
+                            },
+                        );
+                        match result {
+                            Ok(summary) => {
+                                if summary.faults > MAX_LISTED {
+                                    println!("... and {} more faults", summary.faults - MAX_LISTED);
+                                }
+                                println!(
+                                    "Tested {} KiB, skipped {} KiB in use. {} faults found.",
+                                    summary.tested_frames * 4,
+                                    summary.skipped_frames * 4,
+                                    summary.faults
+                                );
+                            }
+                            Err(err) => println!("RAM test stopped: {:?}", err),
+                        }
+                    }
+                }
             },
             "cd" => {
-                if let Some(new_dir) = ramfs::change_directory(&*self.current_dir, args) {
-                    self.current_dir = new_dir;
-                    println!("Changed to {}", self.current_dir); // "/home"
-                } else {
-                    println!("Path not found!");
+                match vfs::change_directory(&*self.current_dir, args) {
+                    Ok(new_dir) => {
+                        self.current_dir = new_dir;
+                        println!("Changed to {}", self.current_dir); // "/home"
+                    }
+                    Err(err) => println!("cd: {}: {}", args, err),
                 }
             },
             "mkfile" => {
-                ramfs::create_file(&*self.current_dir, args, "".as_ref());
+                if let Err(err) = vfs::create_file(&*self.current_dir, args, "".as_ref()) {
+                    println!("mkfile: {}: {}", args, err);
+                }
             },
             "mkdir" => {
-                ramfs::mkdir(&*self.current_dir, args);
+                if let Err(err) = vfs::mkdir(&*self.current_dir, args) {
+                    println!("mkdir: {}: {}", args, err);
+                }
             },
             "rem" => {
-                ramfs::delete(&*self.current_dir, args);
+                if let Err(err) = vfs::delete(&*self.current_dir, args) {
+                    println!("rem: {}: {}", args, err);
+                }
             },
             "readfile" => {
-                if let Some(data) = ramfs::read_file(&*self.current_dir, args) {
-                    let text = core::str::from_utf8(&data).unwrap();
-                    println!("file contents: {}", text);
+                use crate::vfs::file::{self, OpenFlags};
+
+                // streamed, so big files don't need a copy on the heap
+                match file::open(&self.current_dir, args, OpenFlags::READ) {
+                    Ok(fd) => {
+                        print!("file contents: ");
+                        let mut buf = [0; 256];
+                        // bytes of a character cut off by the end of the last read
+                        let mut pending = 0;
+                        loop {
+                            match file::read(fd, &mut buf[pending..]) {
+                                Ok(0) => {
+                                    print_text(&buf[..pending]);
+                                    break;
+                                }
+                                Ok(read) => {
+                                    let end = pending + read;
+                                    pending = print_text_prefix(&buf[..end]);
+                                    buf.copy_within(end - pending..end, 0);
+                                }
+                                Err(err) => {
+                                    print!("\nreadfile: {}: {}", args, err);
+                                    break;
+                                }
+                            }
+                        }
+                        println!();
+                        let _ = file::close(fd);
+                    }
+                    Err(err) => println!("readfile: {}: {}", args, err),
+                }
+            },
+            "uptime" => {
+                let uptime = time::uptime();
+                let secs = uptime.as_secs();
+                let millihertz = time::frequency_millihertz();
+                println!(
+                    "Up for {}h {:02}m {:02}.{:03}s ({} ticks at {}.{:03} Hz)",
+                    secs / 3600,
+                    (secs / 60) % 60,
+                    secs % 60,
+                    uptime.subsec_millis(),
+                    time::ticks(),
+                    millihertz / 1000,
+                    millihertz % 1000
+                );
+            },
+            "heapstat" => {
+                use crate::allocator::{self, fixed_size_block::BLOCK_SIZES};
+
+                let stats = allocator::heap_stats();
+                let counters = stats.counters;
+                match allocator::backend() {
+                    allocator::Backend::LinkedList => println!(
+                        "Backend: {} ({} fit)",
+                        allocator::Backend::LinkedList.name(),
+                        allocator::fit_strategy().name()
+                    ),
+                    backend => println!("Backend: {}", backend.name()),
+                }
+                println!(
+                    "Allocations: {}, frees: {}, live: {}",
+                    counters.allocations,
+                    counters.frees,
+                    counters.allocations - counters.frees
+                );
+                println!(
+                    "In use: {} bytes, peak: {} bytes, heap mapped: {} KiB",
+                    counters.bytes_in_use,
+                    counters.peak_bytes_in_use,
+                    allocator::heap_size() / 1024
+                );
+                println!(
+                    "Free: {} bytes, largest block: {} bytes, {}% fragmented",
+                    stats.free_bytes,
+                    stats.largest_free_block,
+                    stats.fragmentation_percent()
+                );
+                if let Some(free_blocks) = stats.free_blocks {
+                    println!("Free blocks per size:");
+                    for (i, (size, count)) in BLOCK_SIZES.iter().zip(free_blocks.iter()).enumerate() {
+                        print!("  {:>4} B: {:<6}", size, count);
+                        if i % 4 == 3 {
+                            println!();
+                        }
+                    }
+                    println!();
+                }
+            },
+            "heaplive" => {
+                #[cfg(feature = "alloc-debug")]
+                {
+                    const MAX_LISTED: usize = 40;
+
+                    let debug = crate::allocator::debug_allocator();
+                    let (count, bytes) = debug.live();
+                    println!("{} live allocations, {} bytes", count, bytes);
+                    println!("{:>8} {:>18} {:>8} {:>5}", "ID", "Address", "Size", "Align");
+
+                    let mut listed = 0;
+                    let mut corrupted = 0;
+                    debug.for_each_live(|allocation| {
+                        if let Err(corruption) = allocation.state {
+                            corrupted += 1;
+                            println!("{:>8} {:#018x} {}", allocation.id, allocation.addr, corruption);
+                        } else if listed < MAX_LISTED {
+                            println!(
+                                "{:>8} {:#018x} {:>8} {:>5}",
+                                allocation.id, allocation.addr, allocation.size, allocation.align
+                            );
+                        }
+                        listed += 1;
+                    });
+                    if listed > MAX_LISTED {
+                        println!("... and {} more", listed - MAX_LISTED);
+                    }
+                    if corrupted > 0 {
+                        println!("{} allocations have broken red zones!", corrupted);
+                    }
+                }
+                #[cfg(not(feature = "alloc-debug"))]
+                println!("Live allocations are only tracked with the alloc-debug feature.");
+            },
+            "allocbench" => {
+                use crate::allocator::{bench, Backend};
+
+                println!(
+                    "{} operations per backend, {} KiB each.",
+                    bench::OPERATIONS,
+                    bench::ARENA_SIZE / 1024
+                );
+                println!("{:<18}{:>12}{:>8}{:>12}{:>8}", "backend", "cycles/op", "failed", "peak KiB", "frag");
+                for backend in Backend::ALL {
+                    let result = bench::run(backend);
+                    println!(
+                        "{:<18}{:>12}{:>8}{:>12}{:>7}%",
+                        result.backend.name(),
+                        result.cycles_per_op,
+                        result.failed,
+                        result.stats.counters.peak_bytes_in_use / 1024,
+                        result.stats.fragmentation_percent()
+                    );
+                }
+            },
+            "meminfo" => {
+                const FRAME_KIB: usize = 4;
+
+                let counts = crate::memory::FRAME_ALLOCATOR.lock().as_ref().map(|frames| {
+                    (frames.total_frames(), frames.free_frames(), frames.used_frames())
+                });
+                match counts {
+                    Some((total, free, used)) => {
+                        println!(
+                            "Physical memory: {} KiB usable, {} KiB free, {} KiB used",
+                            total * FRAME_KIB,
+                            free * FRAME_KIB,
+                            used * FRAME_KIB
+                        );
+                        println!("Frames: {} total, {} free, {} used", total, free, used);
+                    }
+                    None => println!("The frame allocator isn't set up yet."),
+                }
+                println!(
+                    "Heap: {} KiB mapped, grows up to {} KiB",
+                    crate::allocator::heap_size() / 1024,
+                    crate::allocator::HEAP_MAX_SIZE / 1024
+                );
+            },
+            "memmap" => {
+                match crate::memory::memory_map() {
+                    Some(map) => {
+                        println!("{:<14} {:<14} {:>10}  Type", "Start", "End", "Size");
+                        for region in map.iter() {
+                            let (start, end) = (region.range.start_addr(), region.range.end_addr());
+                            println!(
+                                "{:#014x} {:#014x} {:>6} KiB  {:?}",
+                                start, end, (end - start) / 1024, region.region_type
+                            );
+                        }
+                    }
+                    None => println!("The memory map isn't available yet."),
+                }
+            },
+            "vtop" => {
+                let addr = parse_address(args).map(VirtAddr::try_new);
+                match (addr, crate::memory::physical_memory_offset()) {
+                    (None, _) => println!("Usage: vtop <hex address>"),
+                    (Some(Err(_)), _) => println!("{} is not a canonical address.", args),
+                    (_, None) => println!("Paging isn't set up yet."),
+                    (Some(Ok(addr)), Some(offset)) => {
+                        for step in unsafe { crate::memory::page_walk(addr, offset) }.iter().flatten() {
+                            println!(
+                                "P{}[{:>3}] -> {:#x} {:?}",
+                                step.level, step.index, step.addr.as_u64(), step.flags
+                            );
+                        }
+                        match unsafe { crate::memory::translate_addr(addr, offset) } {
+                            Some(phys) => println!("{:#x} -> {:#x}", addr.as_u64(), phys.as_u64()),
+                            None => println!("{:#x} is not mapped.", addr.as_u64()),
+                        }
+                    }
+                }
+            },
+            "ptdump" => {
+                match crate::memory::physical_memory_offset() {
+                    Some(offset) => unsafe {
+                        crate::memory::for_each_mapped_range(offset, |range| {
+                            let start = range.start.as_u64();
+                            println!(
+                                "{:#018x}-{:#018x} {:>8} KiB {:<6} {}",
+                                start,
+                                start.wrapping_add(range.size),
+                                range.size / 1024,
+                                ShortFlags(range.flags),
+                                address_space_name(start)
+                            );
+                        });
+                    },
+                    None => println!("Paging isn't set up yet."),
                 }
             },
             "hello" => println!("Hello World!"),
@@ -287,7 +812,36 @@ impl Cli {
             "info" => {
                 match args {
                     "ls" => {
-                        println!("Lists files and directories in the current directory.");
+                        println!(
+                            "Lists files and directories, marking directories with a /.\n\
+                 -l shows mode, inode, owner, size and modification time,\n\
+                 -a also shows . and .. and names starting with a dot.\n\
+                 Usage: ls [-l] [-a] [path]"
+                        );
+                    }
+                    "mount" => {
+                        println!(
+                            "Mounts a new, empty filesystem on a directory. Only ramfs\n\
+                 exists so far.\n\
+                 Usage: mount <type> <path>"
+                        );
+                    }
+                    "umount" => {
+                        println!(
+                            "Unmounts the filesystem mounted at a path. Its files are gone\n\
+                 with it.\n\
+                 Usage: umount <path>"
+                        );
+                    }
+                    "mounts" => {
+                        println!("Lists mounted filesystems with their type and mount point.");
+                    }
+                    "stat" => {
+                        println!(
+                            "Shows the type, size, inode, mode, owner and timestamps of a\n\
+                 file or directory.\n\
+                 Usage: stat <path>"
+                        );
                     }
                     "cd" => {
                         println!("Changes the current directory.\nUsage: cd <path>");
@@ -299,7 +853,7 @@ impl Cli {
                         println!("Creates a new directory in the current directory.\nUsage: mkdir <dirname>");
                     }
                     "rem" => {
-                        println!("Removes a file or directory.\nUsage: rem <name>");
+                        println!("Removes a file or an empty directory.\nUsage: rem <name>");
                     }
                     "readfile" => {
                         println!("Reads and prints the contents of a file.\nUsage: readfile <filename>");
@@ -316,6 +870,51 @@ impl Cli {
                  until allocation fails. Useful for testing memory limits."
                         );
                     }
+                    "ramtest" => {
+                        println!(
+                            "Runs walking ones/zeros and address-in-address over every free\n\
+                 physical frame and lists faulty addresses. Full mode adds March C-\n\
+                 and bypasses the cache, which takes much longer.\n\
+                 Usage: ramtest [quick|full]"
+                        );
+                    }
+                    "uptime" => {
+                        println!("Shows how long the system has been running.");
+                    }
+                    "meminfo" => {
+                        println!("Shows free and used physical memory and the heap size.");
+                    }
+                    "memmap" => {
+                        println!("Lists the memory regions the bootloader reported, with their type.");
+                    }
+                    "vtop" => {
+                        println!(
+                            "Translates a virtual address to a physical one and shows the\n\
+                 page table entry used at each level.\n\
+                 Usage: vtop <hex address>"
+                        );
+                    }
+                    "ptdump" => {
+                        println!("Lists every mapped range of virtual memory with its flags.");
+                    }
+                    "heaplive" => {
+                        println!(
+                            "Lists live heap allocations with their sizes and checks their\n\
+                 red zones. Needs a build with the alloc-debug feature."
+                        );
+                    }
+                    "allocbench" => {
+                        println!(
+                            "Runs the same allocation workload on the bump, linked-list and\n\
+                 fixed-size block allocators and compares speed, failures and fragmentation."
+                        );
+                    }
+                    "heapstat" => {
+                        println!(
+                            "Shows heap allocation counters, bytes in use and their peak,\n\
+                 free memory and fragmentation, and the free block lists."
+                        );
+                    }
                     "hello" => {
                         println!("Prints \"Hello World!\" to the screen.");
                     }
@@ -326,10 +925,11 @@ impl Cli {
                         println!("Clears the screen.");
                     }
                     "bye" => {
-                        println!("Shuts down the system. (may not work on real hardware)");
+                        println!("Shuts down the system using ACPI, or reboots if that fails.");
                     }
                     "oops" => {
-                        println!("Reboots the system. (may not work on real hardware)");
+                        println!("Reboots the system using the ACPI reset register,\n\
+                 falling back to the keyboard controller.");
                     }
                     "listcolors" => {
                         println!("Lists all available text colors.");
@@ -384,50 +984,34 @@ impl Cli {
             },
             "bye" => {
                 println!("See ya, nerd.");
-                delay();
-                delay();
-
-                // 1️⃣ Try QEMU ACPI poweroff
-                unsafe {
-                    use x86_64::instructions::port::Port;
-                    let mut port: Port<u16> = Port::new(0x604);
-                    port.write(0x2000);
-                }
-
-                delay();
-                delay();
-
-                unsafe {
-                    use x86_64::instructions::port::Port;
-                    let mut port: Port<u8> = Port::new(0x64);
-                    while port.read() & 0x02 != 0 {}
-                    port.write(0xFE);
-                }
-
-                loop {
-                    x86_64::instructions::hlt();
-                }
+                self.power_action = Some(PowerAction::Shutdown);
             },
             "oops" => {
                 println!("Oopsie daisy. Rebooting...");
-                delay();
-                delay();
-                unsafe {
-                    use x86_64::instructions::port::Port;
-                    let mut port: Port<u8> = Port::new(0x64);
-                    while port.read() & 0x02 != 0 {}
-                    port.write(0xFE);
-                }
+                self.power_action = Some(PowerAction::Reboot);
             },
             "" => {}
             _ => println!("Unknown command: {}", command),
         }
 
+        COMMAND_RUNNING.store(false, Ordering::SeqCst);
         self.clear_input();
-        self.display_prompt();
+        // no prompt when the machine is about to go down
+        if self.power_action.is_none() {
+            self.display_prompt();
+        }
     }
 }
 
 lazy_static! {
     pub static ref CLI: Mutex<Cli> = Mutex::new(Cli::new());
+}
+
+/// Carries out the shutdown or reboot the last command asked for, if any.
+/// The input tasks call this after every key that can end a command.
+pub async fn run_power_action() {
+    let action = CLI.lock().take_power_action();
+    if let Some(action) = action {
+        action.run().await;
+    }
 }
\ No newline at end of file
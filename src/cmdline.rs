@@ -0,0 +1,53 @@
+//! The kernel command line.
+//!
+//! bootloader 0.9 has no way to pass one at boot, so it is baked in at build
+//! time from the `WHY_OS_CMDLINE` environment variable:
+//!
+//! ```text
+//! WHY_OS_CMDLINE="allocator=linked_list" cargo bootimage
+//! ```
+//!
+//! Options are space separated `key=value` pairs.
+
+const CMDLINE: &str = match option_env!("WHY_OS_CMDLINE") {
+    Some(cmdline) => cmdline,
+    None => "",
+};
+
+/// The whole command line, empty if none was given.
+pub fn raw() -> &'static str {
+    CMDLINE
+}
+
+/// Returns the value of option `key`, if it was given.
+pub fn get(key: &str) -> Option<&'static str> {
+    find(CMDLINE, key)
+}
+
+fn find<'a>(cmdline: &'a str, key: &str) -> Option<&'a str> {
+    cmdline
+        .split_whitespace()
+        .filter_map(|option| option.split_once('='))
+        .filter(|(name, _)| *name == key)
+        .map(|(_, value)| value)
+        .next_back()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::find;
+
+    #[test_case]
+    fn options_are_found_by_key() {
+        let cmdline = "allocator=bump  quiet=yes";
+        assert_eq!(find(cmdline, "allocator"), Some("bump"));
+        assert_eq!(find(cmdline, "quiet"), Some("yes"));
+        assert_eq!(find(cmdline, "missing"), None);
+        assert_eq!(find("", "allocator"), None);
+    }
+
+    #[test_case]
+    fn last_option_wins() {
+        assert_eq!(find("allocator=bump allocator=linked_list", "allocator"), Some("linked_list"));
+    }
+}
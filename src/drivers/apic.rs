@@ -0,0 +1,223 @@
+use alloc::vec::Vec;
+use core::ptr;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use spin::Mutex;
+use x86_64::registers::model_specific::Msr;
+use x86_64::structures::paging::{FrameAllocator, Mapper, Size4KiB};
+use x86_64::VirtAddr;
+use crate::acpi::madt::Madt;
+use crate::memory::vmm::{VmmError, VMM};
+
+const IA32_APIC_BASE_MSR: u32 = 0x1B;
+const APIC_BASE_ENABLE: u64 = 1 << 11;
+
+// local APIC registers, as offsets into its MMIO page
+const LAPIC_ID: usize = 0x20;
+const LAPIC_TPR: usize = 0x80;
+const LAPIC_EOI: usize = 0xB0;
+const LAPIC_SVR: usize = 0xF0;
+const LAPIC_LVT_TIMER: usize = 0x320;
+const LAPIC_TIMER_INITIAL: usize = 0x380;
+const LAPIC_TIMER_CURRENT: usize = 0x390;
+const LAPIC_TIMER_DIVIDE: usize = 0x3E0;
+
+const SVR_ENABLE: u32 = 1 << 8;
+const LVT_MASKED: u32 = 1 << 16;
+const LVT_TIMER_PERIODIC: u32 = 1 << 17;
+const TIMER_DIVIDE_BY_16: u32 = 0b0011;
+
+/// Vector the local APIC delivers spurious interrupts on. They need no EOI.
+pub const SPURIOUS_VECTOR: u8 = 0xFF;
+
+// IOAPIC registers, accessed indirectly through IOREGSEL/IOWIN
+const IOREGSEL: usize = 0x00;
+const IOWIN: usize = 0x10;
+const IOAPIC_VERSION: u32 = 0x01;
+const IOAPIC_REDIRECTION_TABLE: u32 = 0x10;
+
+const REDIRECT_ACTIVE_LOW: u64 = 1 << 13;
+const REDIRECT_LEVEL_TRIGGERED: u64 = 1 << 15;
+const REDIRECT_MASKED: u64 = 1 << 16;
+
+/// PIT ticks the local APIC timer is measured against during calibration.
+const CALIBRATION_TICKS: u64 = 10;
+
+/// Virtual address of the local APIC registers, 0 until `init`.
+static LOCAL_APIC: AtomicU64 = AtomicU64::new(0);
+/// Set once interrupts are delivered through the APICs instead of the PICs.
+static ACTIVE: AtomicBool = AtomicBool::new(false);
+
+struct MappedIoApic {
+    base: VirtAddr,
+    gsi_base: u32,
+    redirection_entries: u32,
+}
+
+impl MappedIoApic {
+    unsafe fn read(&self, register: u32) -> u32 {
+        unsafe {
+            ptr::write_volatile(self.base.as_mut_ptr::<u32>().byte_add(IOREGSEL), register);
+            ptr::read_volatile(self.base.as_ptr::<u32>().byte_add(IOWIN))
+        }
+    }
+
+    unsafe fn write(&self, register: u32, value: u32) {
+        unsafe {
+            ptr::write_volatile(self.base.as_mut_ptr::<u32>().byte_add(IOREGSEL), register);
+            ptr::write_volatile(self.base.as_mut_ptr::<u32>().byte_add(IOWIN), value);
+        }
+    }
+
+    unsafe fn set_redirection(&self, gsi: u32, entry: u64) {
+        let register = IOAPIC_REDIRECTION_TABLE + 2 * (gsi - self.gsi_base);
+        unsafe {
+            self.write(register, entry as u32);
+            self.write(register + 1, (entry >> 32) as u32);
+        }
+    }
+
+    fn handles(&self, gsi: u32) -> bool {
+        gsi >= self.gsi_base && gsi < self.gsi_base + self.redirection_entries
+    }
+}
+
+static IO_APICS: Mutex<Vec<MappedIoApic>> = Mutex::new(Vec::new());
+
+/// Whether the CPU has a local APIC, according to CPUID.
+pub fn is_supported() -> bool {
+    let cpuid = core::arch::x86_64::__cpuid(1);
+    cpuid.edx & (1 << 9) != 0
+}
+
+/// Whether interrupts are currently delivered through the APICs.
+pub fn is_active() -> bool {
+    ACTIVE.load(Ordering::SeqCst)
+}
+
+unsafe fn lapic_read(register: usize) -> u32 {
+    let base = LOCAL_APIC.load(Ordering::SeqCst) as usize;
+    unsafe { ptr::read_volatile((base + register) as *const u32) }
+}
+
+unsafe fn lapic_write(register: usize, value: u32) {
+    let base = LOCAL_APIC.load(Ordering::SeqCst) as usize;
+    unsafe { ptr::write_volatile((base + register) as *mut u32, value) }
+}
+
+/// Maps the local APIC and every IOAPIC in `madt`, software-enables the
+/// local APIC and masks all IOAPIC inputs. Nothing is delivered through the
+/// APICs until [`route_isa_irq`] and [`start_timer`] are called.
+pub fn init(
+    madt: &Madt,
+    mapper: &mut impl Mapper<Size4KiB>,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+) -> Result<(), VmmError> {
+    let mut vmm = VMM.lock();
+    let lapic = vmm.map_mmio(madt.local_apic_address, 4096, mapper, frame_allocator)?;
+    LOCAL_APIC.store(lapic.as_u64(), Ordering::SeqCst);
+
+    unsafe {
+        let mut base_msr = Msr::new(IA32_APIC_BASE_MSR);
+        let base = base_msr.read();
+        base_msr.write(base | APIC_BASE_ENABLE);
+
+        lapic_write(LAPIC_TPR, 0);
+        lapic_write(LAPIC_LVT_TIMER, LVT_MASKED);
+        lapic_write(LAPIC_SVR, SVR_ENABLE | SPURIOUS_VECTOR as u32);
+    }
+
+    let mut io_apics = IO_APICS.lock();
+    for io_apic in &madt.io_apics {
+        let base = vmm.map_mmio(io_apic.address, 4096, mapper, frame_allocator)?;
+        let mut mapped = MappedIoApic {
+            base,
+            gsi_base: io_apic.gsi_base,
+            redirection_entries: 0,
+        };
+        unsafe {
+            mapped.redirection_entries = ((mapped.read(IOAPIC_VERSION) >> 16) & 0xFF) + 1;
+            for gsi in mapped.gsi_base..mapped.gsi_base + mapped.redirection_entries {
+                mapped.set_redirection(gsi, REDIRECT_MASKED);
+            }
+        }
+        io_apics.push(mapped);
+    }
+
+    Ok(())
+}
+
+/// APIC ID of the current processor.
+pub fn id() -> u8 {
+    unsafe { (lapic_read(LAPIC_ID) >> 24) as u8 }
+}
+
+/// Measures how far the local APIC timer counts down during one tick of the
+/// already running PIT clock, so that it keeps ticking at the length
+/// [`time::tick_picos`](crate::time::tick_picos) reports rather than at the
+/// nominal rate. Interrupts must be enabled.
+pub fn calibrate_timer() -> u32 {
+    use crate::time;
+    use x86_64::instructions::hlt;
+
+    unsafe {
+        lapic_write(LAPIC_TIMER_DIVIDE, TIMER_DIVIDE_BY_16);
+        lapic_write(LAPIC_LVT_TIMER, LVT_MASKED);
+    }
+
+    // start right on a tick edge
+    let start = time::ticks();
+    while time::ticks() == start {
+        hlt();
+    }
+
+    unsafe { lapic_write(LAPIC_TIMER_INITIAL, u32::MAX) };
+    let start = time::ticks();
+    while time::ticks() - start < CALIBRATION_TICKS {
+        hlt();
+    }
+    let remaining = unsafe { lapic_read(LAPIC_TIMER_CURRENT) };
+    unsafe { lapic_write(LAPIC_TIMER_INITIAL, 0) };
+
+    ((u32::MAX - remaining) as u64 / CALIBRATION_TICKS).max(1) as u32
+}
+
+/// Starts the local APIC timer in periodic mode, firing `vector` every
+/// `count` timer decrements (as measured by [`calibrate_timer`]).
+pub fn start_timer(vector: u8, count: u32) {
+    unsafe {
+        lapic_write(LAPIC_TIMER_DIVIDE, TIMER_DIVIDE_BY_16);
+        lapic_write(LAPIC_LVT_TIMER, LVT_TIMER_PERIODIC | vector as u32);
+        lapic_write(LAPIC_TIMER_INITIAL, count);
+    }
+    ACTIVE.store(true, Ordering::SeqCst);
+}
+
+/// Routes legacy ISA `irq` to `vector` on the current processor, honouring
+/// the MADT's interrupt source overrides. Returns `false` if no IOAPIC
+/// handles the resulting GSI.
+pub fn route_isa_irq(madt: &Madt, irq: u8, vector: u8) -> bool {
+    let (gsi, source_override) = madt.isa_irq(irq);
+    let io_apics = IO_APICS.lock();
+    let io_apic = match io_apics.iter().find(|a| a.handles(gsi)) {
+        Some(io_apic) => io_apic,
+        None => return false,
+    };
+
+    let mut entry = vector as u64 | ((id() as u64) << 56);
+    if let Some(o) = source_override {
+        if o.active_low() {
+            entry |= REDIRECT_ACTIVE_LOW;
+        }
+        if o.level_triggered() {
+            entry |= REDIRECT_LEVEL_TRIGGERED;
+        }
+    }
+
+    unsafe { io_apic.set_redirection(gsi, entry) };
+    true
+}
+
+/// Signals the end of an interrupt to the local APIC.
+pub fn end_of_interrupt() {
+    unsafe { lapic_write(LAPIC_EOI, 0) };
+}
@@ -1 +1,4 @@
-pub mod vga_buffer;
\ No newline at end of file
+pub mod vga_buffer;
+pub mod pit;
+pub mod apic;
+pub mod uart;
\ No newline at end of file
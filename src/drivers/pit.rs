@@ -0,0 +1,34 @@
+use x86_64::instructions::port::Port;
+
+/// Frequency of the oscillator driving the PIT, in Hz.
+pub const BASE_FREQUENCY: u32 = 1_193_182;
+
+const CHANNEL_0_DATA: u16 = 0x40;
+const COMMAND: u16 = 0x43;
+
+/// Channel 0, lobyte/hibyte access, mode 3 (square wave), binary counting.
+const CHANNEL_0_SQUARE_WAVE: u8 = 0b0011_0110;
+
+/// Picoseconds in a second.
+const PICOS_PER_SEC: u64 = 1_000_000_000_000;
+
+/// Programs channel 0 of the PIT to fire IRQ 0 at roughly `hz` times per
+/// second and returns the period that was actually set, in picoseconds.
+/// The divisor is whole, so the real rate is rarely exactly `hz`.
+pub fn set_frequency(hz: u32) -> u64 {
+    let divisor = (BASE_FREQUENCY / hz).clamp(1, u16::MAX as u32) as u16;
+
+    let mut command: Port<u8> = Port::new(COMMAND);
+    let mut data: Port<u8> = Port::new(CHANNEL_0_DATA);
+    unsafe {
+        command.write(CHANNEL_0_SQUARE_WAVE);
+        data.write((divisor & 0xFF) as u8);
+        data.write((divisor >> 8) as u8);
+    }
+
+    period_picos(divisor)
+}
+
+fn period_picos(divisor: u16) -> u64 {
+    (divisor as u64 * PICOS_PER_SEC).div_ceil(BASE_FREQUENCY as u64)
+}
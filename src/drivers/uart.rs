@@ -0,0 +1,129 @@
+use core::fmt;
+use x86_64::instructions::port::Port;
+
+/// I/O port base of the first serial port.
+pub const COM1: u16 = 0x3F8;
+/// Legacy IRQ line of COM1.
+pub const COM1_IRQ: u8 = 4;
+
+// register offsets from the base port
+const DATA: u16 = 0;
+const INTERRUPT_ENABLE: u16 = 1;
+const FIFO_CONTROL: u16 = 2;
+const LINE_CONTROL: u16 = 3;
+const MODEM_CONTROL: u16 = 4;
+const LINE_STATUS: u16 = 5;
+
+// line status bits
+const DATA_READY: u8 = 1 << 0;
+const TRANSMIT_EMPTY: u8 = 1 << 5;
+
+/// How long `send` waits for the transmitter before giving up on a byte.
+const SEND_SPIN_LIMIT: usize = 100_000;
+
+/// A 16550 UART driven through its I/O ports.
+pub struct SerialPort {
+    base: u16,
+    present: bool,
+}
+
+impl SerialPort {
+    /// Creates a driver for the UART at `base`. Nothing is touched until
+    /// `init` is called.
+    ///
+    /// # Safety
+    ///
+    /// `base` must be the I/O base of a 16550 compatible UART, or of nothing
+    /// at all.
+    pub const unsafe fn new(base: u16) -> Self {
+        SerialPort { base, present: false }
+    }
+
+    fn port(&self, offset: u16) -> Port<u8> {
+        Port::new(self.base + offset)
+    }
+
+    /// Sets the UART up for 38400 baud 8N1 with FIFOs and the receive
+    /// interrupt enabled. Returns `false` if no UART answered the loopback
+    /// test, in which case all later output is dropped.
+    pub fn init(&mut self) -> bool {
+        unsafe {
+            self.port(INTERRUPT_ENABLE).write(0x00);
+
+            // divisor latch on, 115200 / 3 = 38400 baud
+            self.port(LINE_CONTROL).write(0x80);
+            self.port(DATA).write(0x03);
+            self.port(INTERRUPT_ENABLE).write(0x00);
+
+            // 8 data bits, no parity, one stop bit, divisor latch off
+            self.port(LINE_CONTROL).write(0x03);
+            // enable and clear the FIFOs, interrupt at 14 bytes
+            self.port(FIFO_CONTROL).write(0xC7);
+
+            // loopback mode to check that there is a UART at all
+            self.port(MODEM_CONTROL).write(0x1E);
+            self.port(DATA).write(0xAE);
+            self.present = self.port(DATA).read() == 0xAE;
+
+            // back to normal: DTR, RTS, OUT1 and OUT2 (which gates the IRQ line)
+            self.port(MODEM_CONTROL).write(0x0F);
+
+            if self.present {
+                // received data available
+                self.port(INTERRUPT_ENABLE).write(0x01);
+            }
+        }
+        self.present
+    }
+
+    /// Whether `init` found a UART.
+    pub fn is_present(&self) -> bool {
+        self.present
+    }
+
+    /// Sends one byte, waiting a bounded time for the transmitter.
+    pub fn send(&mut self, byte: u8) {
+        if !self.present {
+            return;
+        }
+
+        let mut line_status = self.port(LINE_STATUS);
+        unsafe {
+            for _ in 0..SEND_SPIN_LIMIT {
+                if line_status.read() & TRANSMIT_EMPTY != 0 {
+                    break;
+                }
+                core::hint::spin_loop();
+            }
+            self.port(DATA).write(byte);
+        }
+    }
+
+    /// Returns the next received byte, if there is one.
+    pub fn try_receive(&mut self) -> Option<u8> {
+        if !self.present {
+            return None;
+        }
+
+        unsafe {
+            if self.port(LINE_STATUS).read() & DATA_READY != 0 {
+                Some(self.port(DATA).read())
+            } else {
+                None
+            }
+        }
+    }
+}
+
+impl fmt::Write for SerialPort {
+    /// Writes `s`, turning `\n` into `\r\n` for the terminal on the other end.
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for byte in s.bytes() {
+            if byte == b'\n' {
+                self.send(b'\r');
+            }
+            self.send(byte);
+        }
+        Ok(())
+    }
+}
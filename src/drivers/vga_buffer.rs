@@ -272,4 +272,83 @@ pub fn _print(args: fmt::Arguments) {
     interrupts::without_interrupts(|| {
         WRITER.lock().write_fmt(args).unwrap();
     });
-}
\ No newline at end of file
+
+    // everything on screen also goes out on COM1
+    crate::serial::_print(args);
+}
+
+/// Releases the locks [`_print`] takes, for code that interrupted their
+/// holder and can't wait for it: the panic screen and the handlers for
+/// NMIs, machine checks and double faults.
+///
+/// # Safety
+///
+/// Whoever held the locks must cope with output landing in the middle of
+/// their own.
+pub unsafe fn force_unlock_console() {
+    unsafe {
+        WRITER.force_unlock();
+        crate::serial::SERIAL1.force_unlock();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use x86_64::instructions::interrupts;
+
+    fn row_text(writer: &Writer, row: usize, len: usize) -> [u8; BUFFER_WIDTH] {
+        let mut text = [b' '; BUFFER_WIDTH];
+        for (col, byte) in text.iter_mut().enumerate().take(len) {
+            *byte = writer.buffer.chars[row][col].read().ascii_character;
+        }
+        text
+    }
+
+    #[test_case]
+    fn writes_at_cursor() {
+        interrupts::without_interrupts(|| {
+            let mut writer = WRITER.lock();
+            writer.clear_screen();
+            writer.write_string("hello");
+            assert_eq!(&row_text(&writer, 0, 5)[..5], b"hello");
+            assert_eq!((writer.cursor_row(), writer.cursor_col()), (0, 5));
+        });
+    }
+
+    #[test_case]
+    fn long_lines_wrap() {
+        interrupts::without_interrupts(|| {
+            let mut writer = WRITER.lock();
+            writer.clear_screen();
+            for _ in 0..BUFFER_WIDTH {
+                writer.write_byte(b'a');
+            }
+            writer.write_byte(b'b');
+            assert_eq!(row_text(&writer, 0, BUFFER_WIDTH), [b'a'; BUFFER_WIDTH]);
+            assert_eq!(row_text(&writer, 1, 1)[0], b'b');
+        });
+    }
+
+    #[test_case]
+    fn scrolls_when_the_screen_is_full() {
+        interrupts::without_interrupts(|| {
+            let mut writer = WRITER.lock();
+            writer.clear_screen();
+
+            // one more line than fits, the last newline scrolls
+            for line in 0..BUFFER_HEIGHT {
+                writer.write_byte(b'A' + line as u8);
+                writer.write_byte(b'\n');
+            }
+
+            for row in 0..BUFFER_HEIGHT - 1 {
+                assert_eq!(row_text(&writer, row, 1)[0], b'A' + row as u8 + 1);
+            }
+            assert_eq!(row_text(&writer, BUFFER_HEIGHT - 1, BUFFER_WIDTH), [b' '; BUFFER_WIDTH]);
+            assert_eq!((writer.cursor_row(), writer.cursor_col()), (BUFFER_HEIGHT - 1, 0));
+
+            writer.clear_screen();
+        });
+    }
+}
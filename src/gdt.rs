@@ -5,18 +5,28 @@ use x86_64::structures::gdt::{GlobalDescriptorTable, Descriptor};
 use x86_64::structures::gdt::SegmentSelector;
 
 pub const DOUBLE_FAULT_IST_INDEX: u16 = 0;
+pub const NMI_IST_INDEX: u16 = 1;
+pub const MACHINE_CHECK_IST_INDEX: u16 = 2;
+pub const PAGE_FAULT_IST_INDEX: u16 = 3;
+
+/// Reserves a static stack of the given size and evaluates to its top.
+macro_rules! ist_stack {
+    ($size:expr) => {{
+        const STACK_SIZE: usize = $size;
+        static mut STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
+
+        let stack_start = VirtAddr::from_ptr(&raw const STACK);
+        stack_start + STACK_SIZE as u64
+    }};
+}
 
 lazy_static! {
     static ref TSS: TaskStateSegment = {
         let mut tss = TaskStateSegment::new();
-        tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = {
-            const STACK_SIZE: usize = 4096 * 5;
-            static mut STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
-
-            let stack_start = VirtAddr::from_ptr(unsafe { STACK.as_ptr() });
-            let stack_end = stack_start + STACK_SIZE as u64;
-            stack_end
-        };
+        tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = ist_stack!(4096 * 5);
+        tss.interrupt_stack_table[NMI_IST_INDEX as usize] = ist_stack!(4096 * 5);
+        tss.interrupt_stack_table[MACHINE_CHECK_IST_INDEX as usize] = ist_stack!(4096 * 5);
+        tss.interrupt_stack_table[PAGE_FAULT_IST_INDEX as usize] = ist_stack!(4096 * 5);
         tss
     };
 }
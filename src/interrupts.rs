@@ -1,7 +1,10 @@
-use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame};
+use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame, PageFaultErrorCode};
+use x86_64::VirtAddr;
+use x86_64::structures::paging::{FrameAllocator, Mapper, Size4KiB};
 use crate::{println, print};
 use lazy_static::lazy_static;
 use crate::gdt;
+use crate::drivers::{apic, uart, vga_buffer};
 use pic8259::ChainedPics;
 use spin;
 use pc_keyboard::KeyCode;
@@ -10,7 +13,11 @@ use pc_keyboard::KeyCode;
 #[repr(u8)]
 pub enum InterruptIndex {
     Timer = PIC_1_OFFSET,
-    Keyboard
+    Keyboard,
+    Serial = PIC_1_OFFSET + uart::COM1_IRQ,
+    PicSpurious = PIC_1_OFFSET + 7,
+    PicSlaveSpurious = PIC_2_OFFSET + 7,
+    ApicSpurious = apic::SPURIOUS_VECTOR,
 }
 
 impl InterruptIndex {
@@ -29,13 +36,99 @@ pub const PIC_2_OFFSET: u8 = PIC_1_OFFSET + 8;
 pub static PICS: spin::Mutex<ChainedPics> =
     spin::Mutex::new(unsafe { ChainedPics::new(PIC_1_OFFSET, PIC_2_OFFSET) });
 
+/// General purpose registers as they were when the last exception that
+/// dumps them was raised. Saved by the entry stubs [`set_handler!`] adds.
+#[repr(C)]
+struct Registers {
+    rax: u64,
+    rbx: u64,
+    rcx: u64,
+    rdx: u64,
+    rsi: u64,
+    rdi: u64,
+    rbp: u64,
+    r8: u64,
+    r9: u64,
+    r10: u64,
+    r11: u64,
+    r12: u64,
+    r13: u64,
+    r14: u64,
+    r15: u64,
+}
+
+static mut SAVED_REGISTERS: Registers = Registers {
+    rax: 0, rbx: 0, rcx: 0, rdx: 0, rsi: 0, rdi: 0, rbp: 0,
+    r8: 0, r9: 0, r10: 0, r11: 0, r12: 0, r13: 0, r14: 0, r15: 0,
+};
+
+/// Installs `handler` for an IDT entry behind a stub that saves the general
+/// purpose registers to [`SAVED_REGISTERS`] and jumps on to it. The stack is
+/// left alone, so the handler finds the frame the CPU pushed as usual.
+macro_rules! set_handler {
+    ($entry:expr, $handler:ident) => {{
+        #[unsafe(naked)]
+        extern "C" fn stub() {
+            core::arch::naked_asm!(
+                "mov [rip + {regs}], rax",
+                "mov [rip + {regs} + 8], rbx",
+                "mov [rip + {regs} + 16], rcx",
+                "mov [rip + {regs} + 24], rdx",
+                "mov [rip + {regs} + 32], rsi",
+                "mov [rip + {regs} + 40], rdi",
+                "mov [rip + {regs} + 48], rbp",
+                "mov [rip + {regs} + 56], r8",
+                "mov [rip + {regs} + 64], r9",
+                "mov [rip + {regs} + 72], r10",
+                "mov [rip + {regs} + 80], r11",
+                "mov [rip + {regs} + 88], r12",
+                "mov [rip + {regs} + 96], r13",
+                "mov [rip + {regs} + 104], r14",
+                "mov [rip + {regs} + 112], r15",
+                "jmp {handler}",
+                regs = sym SAVED_REGISTERS,
+                handler = sym $handler,
+            );
+        }
+
+        // checks the handler's signature against the entry
+        $entry.set_handler_fn($handler);
+        #[allow(unused_unsafe)]
+        unsafe { $entry.set_handler_addr(VirtAddr::from_ptr(stub as *const ())) }
+    }};
+}
+
 lazy_static! {
     static ref IDT: InterruptDescriptorTable = {
         let mut idt = InterruptDescriptorTable::new();
+        set_handler!(idt.divide_error, divide_error_handler);
+        set_handler!(idt.debug, debug_handler);
         idt.breakpoint.set_handler_fn(breakpoint_handler);
+        set_handler!(idt.overflow, overflow_handler);
+        set_handler!(idt.bound_range_exceeded, bound_range_exceeded_handler);
+        set_handler!(idt.invalid_opcode, invalid_opcode_handler);
+        set_handler!(idt.device_not_available, device_not_available_handler);
+        set_handler!(idt.invalid_tss, invalid_tss_handler);
+        set_handler!(idt.segment_not_present, segment_not_present_handler);
+        set_handler!(idt.stack_segment_fault, stack_segment_fault_handler);
+        set_handler!(idt.general_protection_fault, general_protection_fault_handler);
+        set_handler!(idt.x87_floating_point, x87_floating_point_handler);
+        set_handler!(idt.alignment_check, alignment_check_handler);
+        set_handler!(idt.simd_floating_point, simd_floating_point_handler);
+        set_handler!(idt.virtualization, virtualization_handler);
+        set_handler!(idt.cp_protection_exception, cp_protection_handler);
+        set_handler!(idt.hv_injection_exception, hv_injection_handler);
+        set_handler!(idt.vmm_communication_exception, vmm_communication_handler);
+        set_handler!(idt.security_exception, security_exception_handler);
         unsafe {
-            idt.double_fault.set_handler_fn(double_fault_handler)
+            set_handler!(idt.double_fault, double_fault_handler)
                 .set_stack_index(gdt::DOUBLE_FAULT_IST_INDEX);
+            set_handler!(idt.non_maskable_interrupt, nmi_handler)
+                .set_stack_index(gdt::NMI_IST_INDEX);
+            set_handler!(idt.machine_check, machine_check_handler)
+                .set_stack_index(gdt::MACHINE_CHECK_IST_INDEX);
+            set_handler!(idt.page_fault, page_fault_handler)
+                .set_stack_index(gdt::PAGE_FAULT_IST_INDEX);
         }
 
         idt[InterruptIndex::Timer.as_usize()]
@@ -44,6 +137,18 @@ lazy_static! {
         idt[InterruptIndex::Keyboard.as_usize()]
             .set_handler_fn(keyboard_interrupt_handler);
 
+        idt[InterruptIndex::Serial.as_usize()]
+            .set_handler_fn(serial_interrupt_handler);
+
+        idt[InterruptIndex::PicSpurious.as_usize()]
+            .set_handler_fn(spurious_interrupt_handler);
+
+        idt[InterruptIndex::PicSlaveSpurious.as_usize()]
+            .set_handler_fn(slave_spurious_interrupt_handler);
+
+        idt[InterruptIndex::ApicSpurious.as_usize()]
+            .set_handler_fn(spurious_interrupt_handler);
+
         idt
     };
 }
@@ -52,68 +157,453 @@ pub fn init_idt() {
     IDT.load();
 }
 
+/// Remaps the 8259 PICs and unmasks the COM1 line, which the firmware
+/// usually leaves masked.
+pub fn init_pics() {
+    let mut pics = PICS.lock();
+    unsafe {
+        pics.initialize();
+        let [master, slave] = pics.read_masks();
+        pics.write_masks(master & !(1 << uart::COM1_IRQ), slave);
+    }
+}
+
+/// Moves interrupt delivery from the 8259 PICs to the local APIC and the
+/// IOAPIC when both the CPU and the ACPI tables say they exist. Otherwise
+/// the PICs simply stay in charge.
+///
+/// Calibrates the APIC timer against the PIT, so interrupts must already
+/// be enabled.
+pub fn init_apic(
+    mapper: &mut impl Mapper<Size4KiB>,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+) {
+    use crate::acpi;
+    use x86_64::instructions::interrupts;
+
+    let madt = match acpi::madt::parse() {
+        Some(madt) if apic::is_supported() => madt,
+        _ => {
+            println!("No APIC found, using the 8259 PIC.");
+            return;
+        }
+    };
+
+    if let Err(err) = apic::init(&madt, mapper, frame_allocator) {
+        println!("Failed to map the APIC ({:?}), using the 8259 PIC.", err);
+        return;
+    }
+    let timer_count = apic::calibrate_timer();
+
+    // routed before the 8259 goes away, so there is still input without it
+    let routed = interrupts::without_interrupts(|| {
+        if !apic::route_isa_irq(&madt, 1, InterruptIndex::Keyboard.as_u8()) {
+            return false;
+        }
+        if madt.pic_compatible {
+            unsafe { PICS.lock().disable() };
+        }
+        if !apic::route_isa_irq(&madt, uart::COM1_IRQ, InterruptIndex::Serial.as_u8()) {
+            println!("No IOAPIC input for COM1, serial input is off.");
+        }
+        apic::start_timer(InterruptIndex::Timer.as_u8(), timer_count);
+        true
+    });
+    if !routed {
+        println!("No IOAPIC input for the keyboard, using the 8259 PIC.");
+        return;
+    }
+
+    println!(
+        "Using the local APIC and {} IOAPIC(s), {} CPU(s) found.",
+        madt.io_apics.len(),
+        madt.local_apics.iter().filter(|l| l.enabled).count()
+    );
+}
+
+/// Acknowledges an interrupt to whichever controller delivered it.
+fn end_of_interrupt(index: InterruptIndex) {
+    if apic::is_active() {
+        apic::end_of_interrupt();
+    } else {
+        unsafe {
+            PICS.lock()
+                .notify_end_of_interrupt(index.as_u8());
+        }
+    }
+}
+
+/// Prints the context saved by the CPU, the general purpose registers the
+/// entry stub saved and the control registers.
+fn dump_registers(stack_frame: &InterruptStackFrame) {
+    use x86_64::registers::control::{Cr0, Cr2, Cr3, Cr4};
+
+    let r = unsafe { (&raw const SAVED_REGISTERS).read() };
+
+    println!(
+        "RIP: {:#018x}  CS: {:#06x}  RFLAGS: {:#018x}",
+        stack_frame.instruction_pointer.as_u64(),
+        stack_frame.code_segment,
+        stack_frame.cpu_flags
+    );
+    println!(
+        "RSP: {:#018x}  SS: {:#06x}",
+        stack_frame.stack_pointer.as_u64(),
+        stack_frame.stack_segment
+    );
+    println!("RAX: {:#018x}  RBX: {:#018x}  RCX: {:#018x}", r.rax, r.rbx, r.rcx);
+    println!("RDX: {:#018x}  RSI: {:#018x}  RDI: {:#018x}", r.rdx, r.rsi, r.rdi);
+    println!("RBP: {:#018x}  R8:  {:#018x}  R9:  {:#018x}", r.rbp, r.r8, r.r9);
+    println!("R10: {:#018x}  R11: {:#018x}  R12: {:#018x}", r.r10, r.r11, r.r12);
+    println!("R13: {:#018x}  R14: {:#018x}  R15: {:#018x}", r.r13, r.r14, r.r15);
+    println!("CR0: {:#018x}  CR2: {:#018x}", Cr0::read_raw(), Cr2::read_raw());
+    println!(
+        "CR3: {:#018x}  CR4: {:#018x}",
+        Cr3::read().0.start_address().as_u64(),
+        Cr4::read_raw()
+    );
+}
+
+/// Decodes the error code pushed by #TS, #NP, #SS and #GP, which names the
+/// segment selector that caused the fault (or is zero if none did).
+fn decode_selector_error(error_code: u64) {
+    if error_code == 0 {
+        println!("Error Code: 0 (not segment related)");
+        return;
+    }
+
+    let table = match (error_code >> 1) & 0b11 {
+        0 => "GDT",
+        2 => "LDT",
+        _ => "IDT",
+    };
+    println!(
+        "Error Code: {:#x} ({} index {}{})",
+        error_code,
+        table,
+        (error_code >> 3) & 0x1fff,
+        if error_code & 1 != 0 { ", external event" } else { "" }
+    );
+}
+
+/// Decodes the error code pushed by #CP, which names the instruction that
+/// broke control flow enforcement.
+fn decode_control_protection_error(error_code: u64) {
+    let cause = match error_code & 0x7fff {
+        1 => "near RET",
+        2 => "far RET/IRET",
+        3 => "missing ENDBRANCH",
+        4 => "RSTORSSP",
+        5 => "SETSSBSY",
+        _ => "unknown",
+    };
+    println!("Error Code: {:#x} ({})", error_code, cause);
+}
+
+fn decode_raw_error(error_code: u64) {
+    println!("Error Code: {:#x}", error_code);
+}
+
+/// Defines a handler for a fault that reports it and abandons the shell
+/// command that caused it. Outside of a command the fault is fatal.
+macro_rules! fault_handler {
+    ($name:ident, $title:expr) => {
+        extern "x86-interrupt" fn $name(mut stack_frame: InterruptStackFrame) {
+            if console_locked() {
+                panic!("EXCEPTION: {} with the console locked", $title);
+            }
+            println!("EXCEPTION: {}", $title);
+            dump_registers(&stack_frame);
+            if let Err(reason) = recover_to_shell(&mut stack_frame, None) {
+                panic!("EXCEPTION: {} {}", $title, reason);
+            }
+        }
+    };
+    ($name:ident, $title:expr, $decode:ident) => {
+        extern "x86-interrupt" fn $name(mut stack_frame: InterruptStackFrame, error_code: u64) {
+            if console_locked() {
+                panic!("EXCEPTION: {} with the console locked", $title);
+            }
+            println!("EXCEPTION: {}", $title);
+            $decode(error_code);
+            dump_registers(&stack_frame);
+            if let Err(reason) = recover_to_shell(&mut stack_frame, None) {
+                panic!("EXCEPTION: {} {}", $title, reason);
+            }
+        }
+    };
+}
+
+fault_handler!(divide_error_handler, "DIVIDE ERROR");
+fault_handler!(overflow_handler, "OVERFLOW");
+fault_handler!(bound_range_exceeded_handler, "BOUND RANGE EXCEEDED");
+fault_handler!(invalid_opcode_handler, "INVALID OPCODE");
+fault_handler!(device_not_available_handler, "DEVICE NOT AVAILABLE");
+fault_handler!(invalid_tss_handler, "INVALID TSS", decode_selector_error);
+fault_handler!(segment_not_present_handler, "SEGMENT NOT PRESENT", decode_selector_error);
+fault_handler!(stack_segment_fault_handler, "STACK-SEGMENT FAULT", decode_selector_error);
+fault_handler!(general_protection_fault_handler, "GENERAL PROTECTION FAULT", decode_selector_error);
+fault_handler!(x87_floating_point_handler, "x87 FLOATING-POINT EXCEPTION");
+fault_handler!(alignment_check_handler, "ALIGNMENT CHECK", decode_raw_error);
+fault_handler!(simd_floating_point_handler, "SIMD FLOATING-POINT EXCEPTION");
+fault_handler!(virtualization_handler, "VIRTUALIZATION EXCEPTION");
+fault_handler!(cp_protection_handler, "CONTROL PROTECTION EXCEPTION", decode_control_protection_error);
+fault_handler!(hv_injection_handler, "HYPERVISOR INJECTION EXCEPTION");
+fault_handler!(vmm_communication_handler, "VMM COMMUNICATION EXCEPTION", decode_raw_error);
+fault_handler!(security_exception_handler, "SECURITY EXCEPTION", decode_raw_error);
+
 // new
 extern "x86-interrupt" fn double_fault_handler(
     stack_frame: InterruptStackFrame, _error_code: u64) -> !
 {
+    // fatal anyway, so whoever holds the console isn't coming back
+    unsafe { vga_buffer::force_unlock_console() };
+    dump_registers(&stack_frame);
     panic!("EXCEPTION: DOUBLE FAULT\n{:#?}", stack_frame);
 }
 
+extern "x86-interrupt" fn machine_check_handler(
+    stack_frame: InterruptStackFrame) -> !
+{
+    unsafe { vga_buffer::force_unlock_console() };
+    dump_registers(&stack_frame);
+    panic!("EXCEPTION: MACHINE CHECK\n{:#?}", stack_frame);
+}
+
+extern "x86-interrupt" fn nmi_handler(
+    stack_frame: InterruptStackFrame)
+{
+    // masking interrupts doesn't keep NMIs out of `_print`, and waiting for
+    // the code we interrupted would deadlock; its output gets garbled at worst
+    unsafe { vga_buffer::force_unlock_console() };
+    println!("EXCEPTION: NON-MASKABLE INTERRUPT");
+    dump_registers(&stack_frame);
+}
+
+extern "x86-interrupt" fn debug_handler(
+    stack_frame: InterruptStackFrame)
+{
+    println!("EXCEPTION: DEBUG");
+    dump_registers(&stack_frame);
+}
+
 extern "x86-interrupt" fn breakpoint_handler(
     stack_frame: InterruptStackFrame)
 {
     println!("EXCEPTION: BREAKPOINT\n{:#?}", stack_frame);
 }
 
+extern "x86-interrupt" fn page_fault_handler(
+    mut stack_frame: InterruptStackFrame,
+    error_code: PageFaultErrorCode,
+) {
+    use x86_64::registers::control::Cr2;
+    use crate::memory;
+
+    let accessed = Cr2::read();
+    if console_locked() {
+        panic!("EXCEPTION: PAGE FAULT at {:?} with the console locked", accessed);
+    }
+
+    println!("EXCEPTION: PAGE FAULT");
+    println!("Accessed Address: {:?}", accessed);
+    println!("Error Code: {:?}", error_code);
+    println!(
+        "  cause: {}",
+        if error_code.contains(PageFaultErrorCode::PROTECTION_VIOLATION) {
+            "protection violation"
+        } else {
+            "page not present"
+        }
+    );
+    println!(
+        "  access: {}",
+        if error_code.contains(PageFaultErrorCode::INSTRUCTION_FETCH) {
+            "instruction fetch"
+        } else if error_code.contains(PageFaultErrorCode::CAUSED_BY_WRITE) {
+            "write"
+        } else {
+            "read"
+        }
+    );
+    println!(
+        "  mode: {}",
+        if error_code.contains(PageFaultErrorCode::USER_MODE) { "user" } else { "kernel" }
+    );
+    dump_registers(&stack_frame);
+
+    if let Some(offset) = memory::physical_memory_offset() {
+        let walk = unsafe { memory::page_walk(accessed, offset) };
+        println!("Page table walk:");
+        for step in walk.iter().flatten() {
+            println!(
+                "  P{}[{:>3}] -> {:#x} {:?}",
+                step.level, step.index, step.addr.as_u64(), step.flags
+            );
+        }
+
+        match unsafe { memory::translate_addr(accessed, offset) } {
+            Some(phys) => println!("Translates to: {:?}", phys),
+            None => println!("Translates to: <unmapped>"),
+        }
+    }
+
+    if let Err(reason) = recover_to_shell(&mut stack_frame, Some(accessed)) {
+        panic!("EXCEPTION: PAGE FAULT {}", reason);
+    }
+}
+
+/// Redirects the interrupted context to [`resume_shell`] if the fault was
+/// raised while a shell command was running, and it's safe to go back.
+///
+/// Otherwise returns why not, and the caller should treat the fault as
+/// fatal. `fault_address` is the address a page fault was raised for.
+fn recover_to_shell(stack_frame: &mut InterruptStackFrame, fault_address: Option<VirtAddr>)
+                    -> Result<(), &'static str>
+{
+    use x86_64::registers::rflags::RFlags;
+
+    // commands run in the keyboard task with interrupts enabled; a fault with
+    // them disabled came from an interrupt handler that can't be abandoned
+    let interrupts_were_enabled = RFlags::from_bits_truncate(stack_frame.cpu_flags)
+        .contains(RFlags::INTERRUPT_FLAG);
+    if !crate::cli::command_running() || !interrupts_were_enabled {
+        return Err("outside of a shell command");
+    }
+    if fault_address.is_some_and(in_stack_guard_page) {
+        return Err("in the stack guard page, the kernel stack overflowed");
+    }
+    // the shell prints and allocates on its way back
+    if kernel_lock_held() {
+        return Err("with a kernel lock held");
+    }
+    let stack_top = crate::shell_stack_top().ok_or("before the shell started")?;
+
+    unsafe {
+        stack_frame.as_mut().update(|frame| {
+            frame.instruction_pointer = VirtAddr::from_ptr(resume_shell as *const ());
+            // start over on the shell's stack, aligned as if `resume_shell`
+            // had just been called (16-byte aligned before the return address)
+            frame.stack_pointer = stack_top - 8u64;
+        });
+    }
+    Ok(())
+}
+
+/// Whether `addr` is in the unmapped page below the shell's stack, which
+/// means the stack overflowed.
+fn in_stack_guard_page(addr: VirtAddr) -> bool {
+    use x86_64::structures::paging::Page;
+    use crate::memory;
+
+    let (top, offset) = match (crate::shell_stack_top(), memory::physical_memory_offset()) {
+        (Some(top), Some(offset)) => (top, offset),
+        _ => return false,
+    };
+    let mapped = |page: Page| unsafe { memory::translate_addr(page.start_address(), offset) }.is_some();
+
+    // the stack is mapped from its top down to the guard page
+    let fault_page = Page::containing_address(addr);
+    let mut page = Page::containing_address(top);
+    while page > fault_page && mapped(page) {
+        page -= 1;
+    }
+    page == fault_page && !mapped(page)
+}
+
+/// Whether any lock the way back to the shell needs is held, by the code
+/// that faulted or by what it interrupted.
+fn kernel_lock_held() -> bool {
+    use crate::memory::{FRAME_ALLOCATOR, MAPPER};
+    use crate::{allocator, vfs};
+
+    console_locked()
+        || allocator::is_locked()
+        || vfs::MOUNTS.try_lock().is_none()
+        || vfs::file::is_locked()
+        || MAPPER.try_lock().is_none()
+        || FRAME_ALLOCATOR.try_lock().is_none()
+}
+
+/// Whether the locks `println!` takes are held. A fault raised with them
+/// held, as inside `_print`, can't print anything without deadlocking, so
+/// the handlers go straight to the panic screen, which takes them by force.
+fn console_locked() -> bool {
+    use crate::drivers::vga_buffer::WRITER;
+    use crate::serial::SERIAL1;
+
+    WRITER.try_lock().is_none() || SERIAL1.try_lock().is_none()
+}
+
+/// Landing point for [`recover_to_shell`]. The faulting command is abandoned
+/// together with the executor it ran on, and the shell starts over on a new
+/// one. The old executor's memory is leaked, but its tasks' files are closed.
+extern "C" fn resume_shell() -> ! {
+    use crate::cli::CLI;
+
+    // the command was running with the CLI locked
+    unsafe { CLI.force_unlock() };
+
+    for task in crate::take_shell_tasks().iter().flatten() {
+        crate::vfs::file::close_all(*task);
+    }
+
+    println!("Command aborted, back to the shell.");
+    CLI.lock().abort_command();
+
+    crate::run_shell();
+}
+
 extern "x86-interrupt" fn timer_interrupt_handler(
     _stack_frame: InterruptStackFrame)
 {
     // print!(".");
+    crate::time::tick();
 
-    unsafe {
-        PICS.lock()
-            .notify_end_of_interrupt(InterruptIndex::Timer.as_u8());
-    }
+    end_of_interrupt(InterruptIndex::Timer);
 }
 
 extern "x86-interrupt" fn keyboard_interrupt_handler(
     _stack_frame: InterruptStackFrame
 ) {
-    use pc_keyboard::{layouts, DecodedKey, HandleControl, Keyboard, ScancodeSet1};
-    use spin::Mutex;
     use x86_64::instructions::port::Port;
-    use crate::cli::CLI;
-
-    lazy_static! {
-        static ref KEYBOARD: Mutex<Keyboard<layouts::Us104Key, ScancodeSet1>> =
-            Mutex::new(Keyboard::new(
-                ScancodeSet1::new(),
-                layouts::Us104Key,
-                HandleControl::Ignore
-            ));
-    }
 
-    let mut keyboard = KEYBOARD.lock();
     let mut port = Port::new(0x60);
     let scancode: u8 = unsafe { port.read() };
+    crate::task::keyboard::add_scancode(scancode);
 
-    if let Ok(Some(key_event)) = keyboard.add_byte(scancode) {
-        if let Some(key) = keyboard.process_keyevent(key_event) {
-            match key {
-                DecodedKey::Unicode(character) => {
-                    // Send input to CLI instead of directly printing
-                    CLI.lock().handle_input(character);
-                }
-                DecodedKey::RawKey(key) => {
-                    // Handle special keys if needed
-                    CLI.lock().handle_special_key(key);
-                }
-            }
+    end_of_interrupt(InterruptIndex::Keyboard);
+}
+
+extern "x86-interrupt" fn serial_interrupt_handler(
+    _stack_frame: InterruptStackFrame
+) {
+    // drain the FIFO, the UART only raises the line again for new data
+    {
+        let mut serial = crate::serial::SERIAL1.lock();
+        while let Some(byte) = serial.try_receive() {
+            crate::task::serial::add_byte(byte);
         }
     }
 
-    unsafe {
-        PICS.lock()
-            .notify_end_of_interrupt(InterruptIndex::Keyboard.as_u8());
+    end_of_interrupt(InterruptIndex::Serial);
+}
+
+/// Spurious interrupts from either controller must not be acknowledged.
+extern "x86-interrupt" fn spurious_interrupt_handler(
+    _stack_frame: InterruptStackFrame
+) {
+}
+
+/// A spurious interrupt from the slave PIC still went through the master's
+/// cascade input, which has to be acknowledged. The slave itself must not be.
+extern "x86-interrupt" fn slave_spurious_interrupt_handler(
+    _stack_frame: InterruptStackFrame
+) {
+    if !apic::is_active() {
+        unsafe {
+            PICS.lock()
+                .notify_end_of_interrupt(PIC_1_OFFSET + 2);
+        }
     }
-}
\ No newline at end of file
+}
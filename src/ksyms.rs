@@ -0,0 +1,96 @@
+//! Kernel symbol table, used to put names on addresses in backtraces.
+//!
+//! The table can only be built once the kernel is linked, so the kernel
+//! reserves an empty `.ksyms` section and `tools/embed-symbols.ps1` fills it
+//! in afterwards. Layout (little endian):
+//!
+//! ```text
+//! header:  b"KSYM", count: u32, strings_offset: u32, reserved: u32
+//! entries: count x { addr: u64, size: u32, name_offset: u32 }, sorted by addr
+//! strings: NUL-terminated names, name_offset is relative to strings_offset
+//! ```
+use core::{slice, str};
+
+/// Size of the `.ksyms` section. Must match `$TableSize` in the script.
+pub const KSYMS_SIZE: usize = 512 * 1024;
+
+const MAGIC: &[u8; 4] = b"KSYM";
+const HEADER_SIZE: usize = 16;
+const ENTRY_SIZE: usize = 16;
+
+const fn empty_table() -> [u8; KSYMS_SIZE] {
+    let mut table = [0; KSYMS_SIZE];
+    table[0] = MAGIC[0];
+    table[1] = MAGIC[1];
+    table[2] = MAGIC[2];
+    table[3] = MAGIC[3];
+    table
+}
+
+#[used]
+#[unsafe(link_section = ".ksyms")]
+static KSYMS: [u8; KSYMS_SIZE] = empty_table();
+
+fn table() -> &'static [u8] {
+    // the contents are patched after linking, keep the compiler from
+    // folding reads of the placeholder
+    let ptr = core::hint::black_box(KSYMS.as_ptr());
+    unsafe { slice::from_raw_parts(ptr, KSYMS_SIZE) }
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+    let mut buf = [0; 4];
+    buf.copy_from_slice(&bytes[offset..offset + 4]);
+    u32::from_le_bytes(buf)
+}
+
+fn read_u64(bytes: &[u8], offset: usize) -> u64 {
+    let mut buf = [0; 8];
+    buf.copy_from_slice(&bytes[offset..offset + 8]);
+    u64::from_le_bytes(buf)
+}
+
+/// Number of symbols embedded in the kernel, 0 if the table wasn't filled in.
+pub fn count() -> usize {
+    let table = table();
+    if &table[..4] != MAGIC {
+        return 0;
+    }
+    let count = read_u32(table, 4) as usize;
+    count.min((KSYMS_SIZE - HEADER_SIZE) / ENTRY_SIZE)
+}
+
+/// Finds the function containing `addr`, returning its name and the offset
+/// of `addr` into it.
+pub fn lookup(addr: u64) -> Option<(&'static str, u64)> {
+    let table = table();
+    let count = count();
+    let strings = read_u32(table, 8) as usize;
+    let entry = |i: usize| HEADER_SIZE + i * ENTRY_SIZE;
+
+    // last symbol starting at or before addr
+    let index = {
+        let (mut low, mut high) = (0, count);
+        while low < high {
+            let mid = (low + high) / 2;
+            if read_u64(table, entry(mid)) <= addr {
+                low = mid + 1;
+            } else {
+                high = mid;
+            }
+        }
+        low.checked_sub(1)?
+    };
+
+    let start = read_u64(table, entry(index));
+    let size = read_u32(table, entry(index) + 8) as u64;
+    if size != 0 && addr >= start + size {
+        return None;
+    }
+
+    let name_start = strings.checked_add(read_u32(table, entry(index) + 12) as usize)?;
+    let name_bytes = table.get(name_start..)?;
+    let name_len = name_bytes.iter().position(|&b| b == 0)?;
+    let name = str::from_utf8(&name_bytes[..name_len]).ok()?;
+    Some((name, addr - start))
+}
@@ -13,4 +13,21 @@ macro_rules! println {
     ($($arg:tt)*) => {
         $crate::print!("{}\n", format_args!($($arg)*))
     };
-}
\ No newline at end of file
+}
+
+#[macro_export]
+macro_rules! serial_print {
+    ($($arg:tt)*) => {
+        $crate::serial::_print(format_args!($($arg)*))
+    };
+}
+
+#[macro_export]
+macro_rules! serial_println {
+    () => {
+        $crate::serial_print!("\n");
+    };
+    ($($arg:tt)*) => {
+        $crate::serial_print!("{}\n", format_args!($($arg)*))
+    };
+}
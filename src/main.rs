@@ -1,4 +1,9 @@
 #![feature(abi_x86_interrupt)]
+#![feature(alloc_error_handler)]
+#![feature(allocator_api)]
+#![feature(custom_test_frameworks)]
+#![test_runner(crate::testing::test_runner)]
+#![reexport_test_harness_main = "test_main"]
 #![no_std]
 #![no_main]
 
@@ -11,18 +16,32 @@ use drivers::vga_buffer;
 mod drivers;
 mod os_info;
 mod macros;
+mod cmdline;
+mod serial;
 mod gdt;
 mod interrupts;
 pub mod cli;
 pub mod memory;
 pub mod allocator;
 pub mod task;
+pub mod time;
 mod ramfs;
+pub mod vfs;
+pub mod acpi;
+pub mod power;
+pub mod ksyms;
+#[cfg(not(test))]
+mod panic_screen;
+#[cfg(test)]
+mod testing;
 
 extern crate alloc;
 
 use alloc::{boxed::Box, rc::Rc, vec, vec::Vec};
 use crate::drivers::vga_buffer::Color;
+use core::sync::atomic::{AtomicU64, Ordering};
+use spin::Mutex;
+use crate::task::{executor::Executor, keyboard, Task, TaskId};
 
 pub fn hlt_loop() -> ! {
     loop {
@@ -30,10 +49,55 @@ pub fn hlt_loop() -> ! {
     }
 }
 
+/// Stack pointer the first [`run_shell`] started with. A shell restarted
+/// after a fault starts over from here, so faults don't use up the stack.
+static SHELL_STACK_TOP: AtomicU64 = AtomicU64::new(0);
+/// Tasks of the executor the shell runs on.
+static SHELL_TASKS: Mutex<[Option<TaskId>; 2]> = Mutex::new([None; 2]);
+
+/// Runs the shell's keyboard and serial input tasks on a fresh executor.
+/// Also used to get back to the shell after a command was killed by a CPU
+/// exception.
+pub fn run_shell() -> ! {
+    let rsp: u64;
+    unsafe { core::arch::asm!("mov {}, rsp", out(reg) rsp, options(nomem, nostack, preserves_flags)) };
+    // a restarted shell comes through here again further down the stack
+    let _ = SHELL_STACK_TOP.compare_exchange(0, rsp & !0xf, Ordering::SeqCst, Ordering::SeqCst);
+
+    let mut executor = Executor::new();
+    let mut tasks = [None; 2];
+    let keyboard = Task::new(keyboard::handle_keypresses());
+    tasks[0] = Some(keyboard.id());
+    executor.spawn(keyboard);
+    if serial::SERIAL1.lock().is_present() {
+        let serial = Task::new(task::serial::handle_serial_input());
+        tasks[1] = Some(serial.id());
+        executor.spawn(serial);
+    }
+    *SHELL_TASKS.lock() = tasks;
+    executor.run();
+}
+
+/// Where [`run_shell`] first started, 16-byte aligned, once it has run.
+pub fn shell_stack_top() -> Option<VirtAddr> {
+    match SHELL_STACK_TOP.load(Ordering::SeqCst) {
+        0 => None,
+        top => Some(VirtAddr::new(top)),
+    }
+}
+
+/// Takes the tasks of the executor the shell ran on, which is being
+/// abandoned.
+pub fn take_shell_tasks() -> [Option<TaskId>; 2] {
+    core::mem::take(&mut *SHELL_TASKS.lock())
+}
+
 fn init() {
     interrupts::init_idt();
     gdt::init();
-    unsafe { interrupts::PICS.lock().initialize() };
+    serial::init();
+    interrupts::init_pics();
+    time::init();
     x86_64::instructions::interrupts::enable();
 }
 
@@ -50,15 +114,30 @@ pub extern "C" fn _start(boot_info: &'static BootInfo) -> ! {
     println!("                 __/ |              ");
     println!("                |___/    v{}     \n", os_info::VERSION);
 
+    if !cmdline::raw().is_empty() {
+        println!("Kernel command line: {}", cmdline::raw());
+    }
+
     let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
     let mut mapper = unsafe { memory::init(phys_mem_offset) };
     let mut frame_allocator = unsafe {
-        memory::BootInfoFrameAllocator::init(&boot_info.memory_map)
+        memory::BitmapFrameAllocator::init(&boot_info.memory_map, phys_mem_offset)
     };
 
     allocator::init_heap(&mut mapper, &mut frame_allocator)
         .expect("heap initialization failed");
 
+    if !acpi::init() {
+        println!("No ACPI tables found.");
+    }
+    interrupts::init_apic(&mut mapper, &mut frame_allocator);
+
+    // from here on the heap maps more pages by itself when it runs out
+    memory::install(mapper, frame_allocator, &boot_info.memory_map);
+
+    #[cfg(test)]
+    test_main();
+
     vga_buffer::WRITER.lock().set_custom_color_code(vga_buffer::ColorCode::new(Color::Cyan, Color::Black));
 
     let heap_value = Box::new(41);
@@ -83,24 +162,32 @@ pub extern "C" fn _start(boot_info: &'static BootInfo) -> ! {
     );
 
     println!(
-        "memory allocated to OS is about {} bytes or {} KiB.",
-        allocator::HEAP_SIZE,
-        allocator::HEAP_SIZE / 1024
+        "heap is {} KiB for now and grows up to {} MiB, using the {} allocator.",
+        allocator::heap_size() / 1024,
+        allocator::HEAP_MAX_SIZE / 1024 / 1024,
+        allocator::backend().name()
     );
 
     vga_buffer::WRITER.lock().set_custom_color_code(vga_buffer::ColorCode::new(Color::Green, Color::Black));
 
-    ramfs::mkdir("/", "/home");
-    ramfs::create_file("/","/home/test.txt", b"This is a test file made at startup in RamFS.");
+    vfs::init();
+    vfs::mkdir("/", "/home").expect("couldn't create /home");
+    vfs::create_file("/","/home/test.txt", b"This is a test file made at startup in RamFS.")
+        .expect("couldn't create /home/test.txt");
 
     crate::cli::CLI.lock().activate();
 
-    hlt_loop();
+    run_shell();
+}
+
+#[cfg(not(test))]
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    panic_screen::show(info);
 }
 
+#[cfg(test)]
 #[panic_handler]
 fn panic(info: &PanicInfo) -> ! {
-    vga_buffer::WRITER.lock().set_custom_color_code(vga_buffer::ColorCode::new(Color::Red, Color::Black));
-    println!("{}", info);
-    hlt_loop();
+    testing::test_panic_handler(info);
 }
\ No newline at end of file
@@ -2,18 +2,53 @@ use x86_64::{
     structures::paging::{
         PageTable,
         OffsetPageTable,
-        Page,
-        PhysFrame,
-        Mapper,
-        Size4KiB,
-        FrameAllocator
+        PageTableFlags,
     },
     VirtAddr,
     PhysAddr
 };
-use bootloader::bootinfo::{MemoryMap, MemoryRegionType};
+use bootloader::bootinfo::MemoryMap;
+use core::sync::atomic::{AtomicU64, Ordering};
+use spin::Mutex;
+
+pub mod frame_allocator;
+pub mod ramtest;
+pub mod vmm;
+
+pub use frame_allocator::BitmapFrameAllocator;
+
+/// Virtual address at which the bootloader mapped all of physical memory.
+/// Set once by [`init`] so that interrupt handlers can walk page tables.
+static PHYSICAL_MEMORY_OFFSET: AtomicU64 = AtomicU64::new(0);
+
+/// The kernel's page tables, once `_start` is done setting things up.
+/// Lock before [`FRAME_ALLOCATOR`] when both are needed.
+pub static MAPPER: Mutex<Option<OffsetPageTable<'static>>> = Mutex::new(None);
+/// The kernel's frame allocator, once `_start` is done setting things up.
+pub static FRAME_ALLOCATOR: Mutex<Option<BitmapFrameAllocator>> = Mutex::new(None);
+/// The memory map the bootloader handed over.
+static MEMORY_MAP: Mutex<Option<&'static MemoryMap>> = Mutex::new(None);
+
+/// Hands the page tables and frame allocator over to the rest of the kernel,
+/// so things like the heap can map memory on their own. The memory map is
+/// kept around for the shell to show.
+pub fn install(
+    mapper: OffsetPageTable<'static>,
+    frame_allocator: BitmapFrameAllocator,
+    memory_map: &'static MemoryMap,
+) {
+    *MAPPER.lock() = Some(mapper);
+    *FRAME_ALLOCATOR.lock() = Some(frame_allocator);
+    *MEMORY_MAP.lock() = Some(memory_map);
+}
+
+/// The bootloader's memory map, once `_start` is done setting things up.
+pub fn memory_map() -> Option<&'static MemoryMap> {
+    *MEMORY_MAP.lock()
+}
 
 pub unsafe fn init(physical_memory_offset: VirtAddr) -> OffsetPageTable<'static> {
+    PHYSICAL_MEMORY_OFFSET.store(physical_memory_offset.as_u64(), Ordering::SeqCst);
     unsafe {
         let level_4_table = active_level_4_table(physical_memory_offset);
         OffsetPageTable::new(level_4_table, physical_memory_offset)
@@ -49,7 +84,7 @@ fn translate_addr_inner(addr: VirtAddr, physical_memory_offset: VirtAddr)
     let mut frame = level_4_table_frame;
 
     // traverse the multi-level page table
-    for &index in &table_indexes {
+    for (level, &index) in table_indexes.iter().enumerate() {
         // convert the frame into a page table reference
         let virt = physical_memory_offset + frame.start_address().as_u64();
         let table_ptr: *const PageTable = virt.as_ptr();
@@ -60,7 +95,16 @@ fn translate_addr_inner(addr: VirtAddr, physical_memory_offset: VirtAddr)
         frame = match entry.frame() {
             Ok(frame) => frame,
             Err(FrameError::FrameNotPresent) => return None,
-            Err(FrameError::HugeFrame) => panic!("huge pages not supported. are you dumb?"),
+            Err(FrameError::HugeFrame) => {
+                // a P3 entry maps 1 GiB, a P2 entry 2 MiB. the rest of the
+                // address is the offset into that page.
+                let page_size: u64 = match level {
+                    1 => 1 << 30,
+                    2 => 1 << 21,
+                    _ => return None,
+                };
+                return Some(entry.addr() + (addr.as_u64() & (page_size - 1)));
+            }
         };
     }
 
@@ -74,57 +118,213 @@ pub unsafe fn translate_addr(addr: VirtAddr, physical_memory_offset: VirtAddr)
     translate_addr_inner(addr, physical_memory_offset)
 }
 
-pub fn create_example_mapping(
-    page: Page,
-    mapper: &mut OffsetPageTable,
-    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
-) {
-    use x86_64::structures::paging::PageTableFlags as Flags;
+/// Returns the physical memory offset passed to [`init`], or `None` if
+/// paging has not been set up yet.
+pub fn physical_memory_offset() -> Option<VirtAddr> {
+    match PHYSICAL_MEMORY_OFFSET.load(Ordering::SeqCst) {
+        0 => None,
+        offset => Some(VirtAddr::new(offset)),
+    }
+}
 
-    let frame = PhysFrame::containing_address(PhysAddr::new(0xb8000));
-    let flags = Flags::PRESENT | Flags::WRITABLE;
+/// Returns the virtual address through which the bootloader's physical
+/// memory mapping reaches `addr`.
+///
+/// Panics if paging has not been initialized yet.
+pub fn phys_to_virt(addr: PhysAddr) -> VirtAddr {
+    physical_memory_offset().expect("memory::init not called") + addr.as_u64()
+}
 
-    let map_to_result = unsafe {
-        // FIXME: this is not safe, we do it only for testing
-        mapper.map_to(page, frame, flags, frame_allocator)
-    };
-    map_to_result.expect("map_to failed").flush();
+/// One step of a page table walk: the level visited, the index used into
+/// that table and the entry found there.
+#[derive(Debug, Clone, Copy)]
+pub struct PageWalkStep {
+    pub level: u8,
+    pub index: u16,
+    pub addr: PhysAddr,
+    pub flags: PageTableFlags,
 }
 
-pub struct EmptyFrameAllocator;
+/// Walks the active page tables for `addr`, starting at the level 4 table.
+///
+/// Unlike [`translate_addr`] this shows every level: the walk stops at the
+/// first entry that is not present or that maps a huge page. It never
+/// panics, so it is safe to call from fault handlers.
+///
+/// # Safety
+///
+/// The caller must guarantee that all of physical memory is mapped at
+/// `physical_memory_offset`.
+pub unsafe fn page_walk(addr: VirtAddr, physical_memory_offset: VirtAddr)
+                        -> [Option<PageWalkStep>; 4]
+{
+    use x86_64::registers::control::Cr3;
+
+    let (level_4_table_frame, _) = Cr3::read();
+    let table_indexes = [
+        addr.p4_index(), addr.p3_index(), addr.p2_index(), addr.p1_index()
+    ];
+    let mut steps = [None; 4];
+    let mut table_addr = level_4_table_frame.start_address();
 
-unsafe impl FrameAllocator<Size4KiB> for BootInfoFrameAllocator {
-    fn allocate_frame(&mut self) -> Option<PhysFrame> {
-        let frame = self.usable_frames().nth(self.next);
-        self.next += 1;
-        frame
+    for (i, &index) in table_indexes.iter().enumerate() {
+        let virt = physical_memory_offset + table_addr.as_u64();
+        let table = unsafe { &*virt.as_ptr::<PageTable>() };
+        let entry = &table[index];
+
+        steps[i] = Some(PageWalkStep {
+            level: 4 - i as u8,
+            index: u16::from(index),
+            addr: entry.addr(),
+            flags: entry.flags(),
+        });
+
+        if !entry.flags().contains(PageTableFlags::PRESENT)
+            || entry.flags().contains(PageTableFlags::HUGE_PAGE)
+        {
+            break;
+        }
+        table_addr = entry.addr();
     }
+
+    steps
 }
 
-pub struct BootInfoFrameAllocator {
-    memory_map: &'static MemoryMap,
-    next: usize,
+/// A run of virtual memory mapped with the same flags.
+#[derive(Debug, Clone, Copy)]
+pub struct MappedRange {
+    pub start: VirtAddr,
+    pub size: u64,
+    /// Flags of the last level entries, without `ACCESSED` and `DIRTY`.
+    /// `WRITABLE`, `USER_ACCESSIBLE` and `NO_EXECUTE` are the effective ones,
+    /// taking the entries of every level above into account.
+    pub flags: PageTableFlags,
 }
 
-impl BootInfoFrameAllocator {
-    /// Returns an iterator over the usable frames specified in the memory map.
-    fn usable_frames(&self) -> impl Iterator<Item = PhysFrame> {
-        // get usable regions from memory map
-        let regions = self.memory_map.iter();
-        let usable_regions = regions
-            .filter(|r| r.region_type == MemoryRegionType::Usable);
-        // map each region to its address range
-        let addr_ranges = usable_regions
-            .map(|r| r.range.start_addr()..r.range.end_addr());
-        // transform to an iterator of frame start addresses
-        let frame_addresses = addr_ranges.flat_map(|r| r.step_by(4096));
-        // create `PhysFrame` types from the start addresses
-        frame_addresses.map(|addr| PhysFrame::containing_address(PhysAddr::new(addr)))
+/// Walks all of the active page tables and calls `f` for every mapped range,
+/// in address order. Neighbouring pages with the same flags are merged into
+/// one range, whatever their page size.
+///
+/// # Safety
+///
+/// The caller must guarantee that all of physical memory is mapped at
+/// `physical_memory_offset`.
+pub unsafe fn for_each_mapped_range(physical_memory_offset: VirtAddr, mut f: impl FnMut(MappedRange)) {
+    use x86_64::registers::control::Cr3;
+
+    let (level_4_table_frame, _) = Cr3::read();
+    let mut current: Option<MappedRange> = None;
+
+    let mut add_page = |start: VirtAddr, size: u64, flags: PageTableFlags| {
+        let flags = flags - (PageTableFlags::ACCESSED | PageTableFlags::DIRTY);
+        match &mut current {
+            Some(range) if range.flags == flags
+                && range.start.as_u64().wrapping_add(range.size) == start.as_u64() => {
+                range.size += size;
+            }
+            _ => {
+                if let Some(range) = current.replace(MappedRange { start, size, flags }) {
+                    f(range);
+                }
+            }
+        }
+    };
+    unsafe {
+        walk_table(
+            level_4_table_frame.start_address(),
+            4,
+            0,
+            PageTableFlags::WRITABLE | PageTableFlags::USER_ACCESSIBLE,
+            physical_memory_offset,
+            &mut add_page,
+        );
+    }
+
+    if let Some(range) = current {
+        f(range);
+    }
+}
+
+/// Flags the CPU applies to a page whose entry has `entry` flags, under
+/// tables whose combined flags are `parent`: it is only writable and user
+/// accessible if every level allows it, and not executable if any forbids it.
+fn effective_flags(parent: PageTableFlags, entry: PageTableFlags) -> PageTableFlags {
+    let allowed = PageTableFlags::WRITABLE | PageTableFlags::USER_ACCESSIBLE;
+    (entry - (allowed - parent)) | (parent & PageTableFlags::NO_EXECUTE)
+}
+
+/// Calls `leaf` for every present page mapped by the table at `table_addr`,
+/// which sits at `level` and maps the addresses from `base` on. `parent` is
+/// the combination of the entries above it, see [`effective_flags`].
+unsafe fn walk_table(
+    table_addr: PhysAddr,
+    level: u8,
+    base: u64,
+    parent: PageTableFlags,
+    physical_memory_offset: VirtAddr,
+    leaf: &mut impl FnMut(VirtAddr, u64, PageTableFlags),
+) {
+    let virt = physical_memory_offset + table_addr.as_u64();
+    let table = unsafe { &*virt.as_ptr::<PageTable>() };
+    // 4 KiB at level 1, times 512 for every level above
+    let entry_size = 1u64 << (12 + 9 * (level as u64 - 1));
+
+    for (index, entry) in table.iter().enumerate() {
+        if !entry.flags().contains(PageTableFlags::PRESENT) {
+            continue;
+        }
+        let flags = effective_flags(parent, entry.flags());
+
+        let start = base + index as u64 * entry_size;
+        if level == 1 || flags.contains(PageTableFlags::HUGE_PAGE) {
+            leaf(VirtAddr::new_truncate(start), entry_size, flags);
+        } else {
+            unsafe { walk_table(entry.addr(), level - 1, start, flags, physical_memory_offset, leaf) };
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::allocator::HEAP_START;
+
+    #[test_case]
+    fn translation_sees_through_the_physical_memory_mapping() {
+        let offset = physical_memory_offset().unwrap();
+        let phys = PhysAddr::new(0x12_3456);
+        assert_eq!(unsafe { translate_addr(offset + phys.as_u64(), offset) }, Some(phys));
+    }
+
+    #[test_case]
+    fn flags_are_restricted_by_every_level() {
+        use PageTableFlags as F;
+
+        let all = F::WRITABLE | F::USER_ACCESSIBLE;
+        let leaf = F::PRESENT | F::WRITABLE | F::USER_ACCESSIBLE;
+        assert_eq!(effective_flags(all, leaf), leaf);
+        assert_eq!(effective_flags(F::PRESENT | F::USER_ACCESSIBLE, leaf), F::PRESENT | F::USER_ACCESSIBLE);
+        assert_eq!(effective_flags(F::PRESENT | F::WRITABLE, leaf), F::PRESENT | F::WRITABLE);
+        assert_eq!(effective_flags(all | F::NO_EXECUTE, leaf), leaf | F::NO_EXECUTE);
+        assert_eq!(effective_flags(all, F::PRESENT), F::PRESENT);
     }
-    pub unsafe fn init(memory_map: &'static MemoryMap) -> Self {
-        BootInfoFrameAllocator {
-            memory_map,
-            next: 0,
+
+    #[test_case]
+    fn mapped_ranges_are_sorted_and_cover_the_heap() {
+        let offset = physical_memory_offset().unwrap();
+        let mut last_end = 0;
+        let mut heap_mapped = false;
+
+        unsafe {
+            for_each_mapped_range(offset, |range| {
+                let start = range.start.as_u64();
+                assert!(start >= last_end);
+                last_end = start.saturating_add(range.size);
+                if (start..last_end).contains(&(HEAP_START as u64)) {
+                    heap_mapped = range.flags.contains(PageTableFlags::WRITABLE);
+                }
+            });
         }
+        assert!(heap_mapped);
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,304 @@
+use bootloader::bootinfo::{MemoryMap, MemoryRegionType};
+use x86_64::structures::paging::{FrameAllocator, FrameDeallocator, PageSize, PhysFrame, Size2MiB, Size4KiB};
+use x86_64::{PhysAddr, VirtAddr};
+
+const FRAME_SIZE: u64 = 4096;
+/// 4 KiB frames in a 2 MiB frame.
+const FRAMES_PER_HUGE_FRAME: usize = (Size2MiB::SIZE / FRAME_SIZE) as usize;
+const BITS_PER_WORD: usize = 64;
+
+/// Physical frame allocator keeping one bit per 4 KiB frame, set while the
+/// frame is in use. The bitmap lives in the first usable region big enough
+/// to hold it, and marks its own frames as used.
+pub struct BitmapFrameAllocator {
+    bitmap: &'static mut [u64],
+    /// Number of frames the bitmap covers, from address 0 up to the end of
+    /// the highest usable region.
+    frames: usize,
+    /// Frames that were usable RAM at boot, including the bitmap's.
+    usable: usize,
+    free: usize,
+    /// Word to start looking for a free frame at.
+    next_word: usize,
+}
+
+impl BitmapFrameAllocator {
+    /// Builds the allocator from the bootloader's memory map.
+    ///
+    /// # Safety
+    ///
+    /// The memory map must be valid, with every `Usable` region really unused,
+    /// and all of physical memory must be mapped at `physical_memory_offset`.
+    /// Must be called only once.
+    pub unsafe fn init(memory_map: &'static MemoryMap, physical_memory_offset: VirtAddr) -> Self {
+        let usable_regions = || memory_map.iter()
+            .filter(|r| r.region_type == MemoryRegionType::Usable);
+
+        let end = usable_regions().map(|r| r.range.end_addr()).max().unwrap_or(0);
+        let frames = (end / FRAME_SIZE) as usize;
+        let words = frames.div_ceil(BITS_PER_WORD);
+        let bitmap_frames = (words as u64 * 8).div_ceil(FRAME_SIZE);
+
+        let bitmap_start = usable_regions()
+            .find(|r| r.range.end_frame_number - r.range.start_frame_number >= bitmap_frames)
+            .map(|r| r.range.start_addr())
+            .expect("no usable region big enough for the frame bitmap");
+
+        let bitmap = unsafe {
+            let ptr = (physical_memory_offset + bitmap_start).as_mut_ptr::<u64>();
+            core::slice::from_raw_parts_mut(ptr, words)
+        };
+
+        let mut allocator = Self::new(bitmap, frames);
+        for region in usable_regions() {
+            let start = region.range.start_frame_number as usize;
+            let end = region.range.end_frame_number as usize;
+            allocator.set_range(start, end - start, false);
+        }
+        allocator.usable = allocator.free;
+
+        let bitmap_start = (bitmap_start / FRAME_SIZE) as usize;
+        allocator.set_range(bitmap_start, bitmap_frames as usize, true);
+
+        allocator
+    }
+
+    /// Creates an allocator over `bitmap` with all `frames` marked as used.
+    fn new(bitmap: &'static mut [u64], frames: usize) -> Self {
+        assert!(bitmap.len() * BITS_PER_WORD >= frames);
+        bitmap.fill(!0);
+        BitmapFrameAllocator { bitmap, frames, usable: 0, free: 0, next_word: 0 }
+    }
+
+    fn is_used(&self, frame: usize) -> bool {
+        self.bitmap[frame / BITS_PER_WORD] & (1 << (frame % BITS_PER_WORD)) != 0
+    }
+
+    /// Marks `count` frames from `start` as used or free, keeping the free
+    /// count right even if some already were.
+    fn set_range(&mut self, start: usize, count: usize, used: bool) {
+        for frame in start..(start + count).min(self.frames) {
+            if self.is_used(frame) == used {
+                continue;
+            }
+
+            let bit = 1 << (frame % BITS_PER_WORD);
+            if used {
+                self.bitmap[frame / BITS_PER_WORD] |= bit;
+                self.free -= 1;
+            } else {
+                self.bitmap[frame / BITS_PER_WORD] &= !bit;
+                self.free += 1;
+            }
+        }
+
+        if !used {
+            self.next_word = self.next_word.min(start / BITS_PER_WORD);
+        }
+    }
+
+    fn frame_at(index: usize) -> PhysFrame {
+        PhysFrame::containing_address(PhysAddr::new(index as u64 * FRAME_SIZE))
+    }
+
+    fn index_of(frame: PhysFrame) -> usize {
+        (frame.start_address().as_u64() / FRAME_SIZE) as usize
+    }
+
+    /// Allocates `count` physically contiguous frames, the first one aligned
+    /// to `align` frames (a power of two). Useful for DMA buffers and huge
+    /// pages.
+    pub fn allocate_contiguous(&mut self, count: usize, align: usize) -> Option<PhysFrame> {
+        assert!(align.is_power_of_two());
+        if count == 0 || count > self.free {
+            return None;
+        }
+
+        let mut start = 0;
+        while start + count <= self.frames {
+            // first used frame in the candidate run, if any
+            match (start..start + count).rev().find(|&frame| self.is_used(frame)) {
+                Some(used) => start = (used + 1).next_multiple_of(align),
+                None => {
+                    self.set_range(start, count, true);
+                    return Some(Self::frame_at(start));
+                }
+            }
+        }
+        None
+    }
+
+    /// Returns `count` frames starting at `start` to the allocator.
+    ///
+    /// # Safety
+    ///
+    /// The frames must have come from this allocator and must not be in use
+    /// anymore.
+    pub unsafe fn deallocate_contiguous(&mut self, start: PhysFrame, count: usize) {
+        self.set_range(Self::index_of(start), count, false);
+    }
+
+    /// Marks the first run of free frames at or after `from`, at most `max`
+    /// frames long, as used and returns where it starts and its length. Lets
+    /// the RAM tester borrow every free frame in turn.
+    pub fn claim_free_run(&mut self, from: PhysFrame, max: usize) -> Option<(PhysFrame, usize)> {
+        let start = (Self::index_of(from)..self.frames).find(|&frame| !self.is_used(frame))?;
+        let count = (start..self.frames.min(start + max))
+            .take_while(|&frame| !self.is_used(frame))
+            .count();
+        self.set_range(start, count, true);
+        Some((Self::frame_at(start), count))
+    }
+
+    /// Frames that were usable RAM at boot.
+    pub fn total_frames(&self) -> usize {
+        self.usable
+    }
+
+    /// Frames that can still be allocated.
+    pub fn free_frames(&self) -> usize {
+        self.free
+    }
+
+    /// Usable frames that are currently allocated.
+    pub fn used_frames(&self) -> usize {
+        self.usable - self.free
+    }
+}
+
+unsafe impl FrameAllocator<Size4KiB> for BitmapFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<PhysFrame> {
+        for word in self.next_word..self.bitmap.len() {
+            if self.bitmap[word] == !0 {
+                continue;
+            }
+
+            let frame = word * BITS_PER_WORD + self.bitmap[word].trailing_ones() as usize;
+            if frame >= self.frames {
+                break;
+            }
+            self.next_word = word;
+            self.set_range(frame, 1, true);
+            return Some(Self::frame_at(frame));
+        }
+        self.next_word = self.bitmap.len();
+        None
+    }
+}
+
+impl FrameDeallocator<Size4KiB> for BitmapFrameAllocator {
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame) {
+        self.set_range(Self::index_of(frame), 1, false);
+    }
+}
+
+/// 2 MiB frames are runs of 512 frames aligned to 2 MiB.
+unsafe impl FrameAllocator<Size2MiB> for BitmapFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<PhysFrame<Size2MiB>> {
+        let first = self.allocate_contiguous(FRAMES_PER_HUGE_FRAME, FRAMES_PER_HUGE_FRAME)?;
+        Some(PhysFrame::containing_address(first.start_address()))
+    }
+}
+
+impl FrameDeallocator<Size2MiB> for BitmapFrameAllocator {
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame<Size2MiB>) {
+        let first = PhysFrame::containing_address(frame.start_address());
+        unsafe { self.deallocate_contiguous(first, FRAMES_PER_HUGE_FRAME) };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::boxed::Box;
+    use alloc::vec;
+
+    /// An allocator over `frames` made-up frames, all of them free. Only the
+    /// bitmap is touched, never the frames themselves.
+    fn allocator_with(frames: usize) -> BitmapFrameAllocator {
+        let bitmap = Box::leak(vec![0; frames.div_ceil(BITS_PER_WORD)].into_boxed_slice());
+        let mut allocator = BitmapFrameAllocator::new(bitmap, frames);
+        allocator.set_range(0, frames, false);
+        allocator.usable = frames;
+        allocator
+    }
+
+    /// Picks the 4 KiB `FrameAllocator` impl.
+    fn allocate(allocator: &mut BitmapFrameAllocator) -> Option<PhysFrame> {
+        FrameAllocator::<Size4KiB>::allocate_frame(allocator)
+    }
+
+    #[test_case]
+    fn frames_are_handed_out_once() {
+        let mut allocator = allocator_with(100);
+        let a = allocate(&mut allocator).unwrap();
+        let b = allocate(&mut allocator).unwrap();
+        assert_ne!(a, b);
+        assert_eq!(allocator.free_frames(), 98);
+        assert_eq!(allocator.used_frames(), 2);
+    }
+
+    #[test_case]
+    fn freed_frames_are_reused() {
+        let mut allocator = allocator_with(100);
+        let a = allocate(&mut allocator).unwrap();
+        allocate(&mut allocator).unwrap();
+        unsafe { allocator.deallocate_frame(a) };
+        assert_eq!(allocator.free_frames(), 99);
+        assert_eq!(allocate(&mut allocator), Some(a));
+    }
+
+    #[test_case]
+    fn runs_out_of_frames() {
+        let mut allocator = allocator_with(70);
+        for _ in 0..70 {
+            assert!(allocate(&mut allocator).is_some());
+        }
+        assert_eq!(allocate(&mut allocator), None);
+        assert_eq!(allocator.free_frames(), 0);
+    }
+
+    #[test_case]
+    fn contiguous_runs_skip_used_frames() {
+        let mut allocator = allocator_with(256);
+        allocator.set_range(3, 1, true);
+
+        let run = allocator.allocate_contiguous(8, 1).unwrap();
+        assert_eq!(BitmapFrameAllocator::index_of(run), 4);
+
+        let aligned = allocator.allocate_contiguous(16, 64).unwrap();
+        assert_eq!(BitmapFrameAllocator::index_of(aligned), 64);
+        assert_eq!(allocator.free_frames(), 256 - 1 - 8 - 16);
+
+        unsafe { allocator.deallocate_contiguous(aligned, 16) };
+        assert_eq!(allocator.free_frames(), 256 - 1 - 8);
+        assert_eq!(allocator.allocate_contiguous(300, 1), None);
+    }
+
+    #[test_case]
+    fn huge_frames_are_aligned_runs() {
+        let mut allocator = allocator_with(3 * FRAMES_PER_HUGE_FRAME);
+        allocate(&mut allocator).unwrap();
+
+        let huge: PhysFrame<Size2MiB> = allocator.allocate_frame().unwrap();
+        assert_eq!(huge.start_address().as_u64(), Size2MiB::SIZE);
+        assert_eq!(allocator.free_frames(), 2 * FRAMES_PER_HUGE_FRAME - 1);
+
+        unsafe { allocator.deallocate_frame(huge) };
+        assert_eq!(allocator.free_frames(), 3 * FRAMES_PER_HUGE_FRAME - 1);
+    }
+
+    #[test_case]
+    fn free_runs_are_claimed_in_order() {
+        let mut allocator = allocator_with(100);
+        allocator.set_range(10, 5, true);
+
+        let (run, count) = allocator.claim_free_run(BitmapFrameAllocator::frame_at(0), 8).unwrap();
+        assert_eq!((BitmapFrameAllocator::index_of(run), count), (0, 8));
+        let (run, count) = allocator.claim_free_run(BitmapFrameAllocator::frame_at(8), 8).unwrap();
+        assert_eq!((BitmapFrameAllocator::index_of(run), count), (8, 2));
+        let (run, count) = allocator.claim_free_run(BitmapFrameAllocator::frame_at(10), 100).unwrap();
+        assert_eq!((BitmapFrameAllocator::index_of(run), count), (15, 85));
+        assert_eq!(allocator.claim_free_run(BitmapFrameAllocator::frame_at(0), 8), None);
+    }
+}
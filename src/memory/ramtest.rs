@@ -0,0 +1,361 @@
+//! Pattern tests over physical RAM, for checking real machines.
+//!
+//! Only frames the frame allocator has free can be tested, since the tests
+//! overwrite them. Each run of free frames is claimed from the allocator,
+//! tested and given back, so the rest of the kernel keeps working meanwhile.
+
+use core::arch::asm;
+use x86_64::structures::paging::{PageSize, PhysFrame, Size4KiB};
+use x86_64::{PhysAddr, VirtAddr};
+use super::vmm::{VmmError, VMM};
+use super::{phys_to_virt, FRAME_ALLOCATOR, MAPPER};
+
+/// Frames claimed and tested at once.
+const CHUNK_FRAMES: usize = 256;
+const WORDS_PER_FRAME: usize = (Size4KiB::SIZE / 8) as usize;
+
+/// How thorough a run is.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mode {
+    /// Walking ones and zeros, then address-in-address, through the cached
+    /// physical memory mapping. Finds dead cells and broken address or data
+    /// lines in seconds.
+    Quick,
+    /// Adds March C-, and runs everything through an uncached mapping so
+    /// every access reaches the memory chips instead of the cache.
+    Full,
+}
+
+impl Mode {
+    pub const ALL: [Mode; 2] = [Mode::Quick, Mode::Full];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Mode::Quick => "quick",
+            Mode::Full => "full",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Mode> {
+        Mode::ALL.iter().copied().find(|mode| mode.name() == name)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Test {
+    WalkingOnes,
+    WalkingZeros,
+    AddressInAddress,
+    MarchCMinus,
+}
+
+impl Test {
+    pub fn name(self) -> &'static str {
+        match self {
+            Test::WalkingOnes => "walking ones",
+            Test::WalkingZeros => "walking zeros",
+            Test::AddressInAddress => "address-in-address",
+            Test::MarchCMinus => "March C-",
+        }
+    }
+}
+
+/// A word that didn't read back what was written.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Fault {
+    pub addr: PhysAddr,
+    pub expected: u64,
+    pub found: u64,
+    pub test: Test,
+}
+
+/// What a whole run covered.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Summary {
+    pub tested_frames: usize,
+    /// Usable frames that were in use and so couldn't be tested.
+    pub skipped_frames: usize,
+    pub faults: usize,
+}
+
+/// Words of memory under test, addressed by index.
+trait Cells {
+    fn len(&self) -> usize;
+    fn read(&self, index: usize) -> u64;
+    fn write(&mut self, index: usize, value: u64);
+}
+
+/// Memory mapped at `ptr`. Every access is volatile so the compiler can't
+/// leave any out.
+struct Words {
+    ptr: *mut u64,
+    len: usize,
+}
+
+impl Cells for Words {
+    fn len(&self) -> usize {
+        self.len
+    }
+
+    fn read(&self, index: usize) -> u64 {
+        unsafe { self.ptr.add(index).read_volatile() }
+    }
+
+    fn write(&mut self, index: usize, value: u64) {
+        unsafe { self.ptr.add(index).write_volatile(value) }
+    }
+}
+
+/// Runs the tests picked by `mode` over `cells`, whose first word is at
+/// physical address `phys`, and calls `on_fault` for every mismatch.
+fn test_cells(cells: &mut impl Cells, phys: u64, mode: Mode, on_fault: &mut impl FnMut(Fault)) {
+    let mut report = |index: usize, expected: u64, found: u64, test: Test| {
+        on_fault(Fault { addr: PhysAddr::new(phys + index as u64 * 8), expected, found, test });
+    };
+
+    walking_bits(cells, &mut report);
+    address_in_address(cells, phys, &mut report);
+    if mode == Mode::Full {
+        march_c_minus(cells, &mut report);
+    }
+}
+
+/// Walks a single one through a field of zeros, and the other way round, in
+/// the first word of every frame. Finds stuck or shorted data lines.
+fn walking_bits(cells: &mut impl Cells, report: &mut impl FnMut(usize, u64, u64, Test)) {
+    for index in (0..cells.len()).step_by(WORDS_PER_FRAME) {
+        for (test, invert) in [(Test::WalkingOnes, 0), (Test::WalkingZeros, u64::MAX)] {
+            for bit in 0..64 {
+                let pattern = (1 << bit) ^ invert;
+                cells.write(index, pattern);
+                let found = cells.read(index);
+                if found != pattern {
+                    report(index, pattern, found, test);
+                    // one report per word is enough to know it's broken
+                    break;
+                }
+            }
+        }
+    }
+}
+
+/// Writes every word's own address into it, then its complement, and reads
+/// them all back. Finds address lines that alias two words.
+fn address_in_address(cells: &mut impl Cells, phys: u64, report: &mut impl FnMut(usize, u64, u64, Test)) {
+    for invert in [0, u64::MAX] {
+        let pattern = |index: usize| (phys + index as u64 * 8) ^ invert;
+        for index in 0..cells.len() {
+            cells.write(index, pattern(index));
+        }
+        for index in 0..cells.len() {
+            let found = cells.read(index);
+            if found != pattern(index) {
+                report(index, pattern(index), found, Test::AddressInAddress);
+            }
+        }
+    }
+}
+
+/// March C-: ⇕(w0) ⇑(r0,w1) ⇑(r1,w0) ⇓(r0,w1) ⇓(r1,w0) ⇕(r0), with whole
+/// words of zeros and ones. Finds stuck-at, transition and coupling faults.
+fn march_c_minus(cells: &mut impl Cells, report: &mut impl FnMut(usize, u64, u64, Test)) {
+    const ZERO: u64 = 0;
+    const ONE: u64 = u64::MAX;
+
+    let len = cells.len();
+    for index in 0..len {
+        cells.write(index, ZERO);
+    }
+    for (expected, write, down) in [(ZERO, ONE, false), (ONE, ZERO, false), (ZERO, ONE, true), (ONE, ZERO, true)] {
+        for step in 0..len {
+            let index = if down { len - 1 - step } else { step };
+            let found = cells.read(index);
+            if found != expected {
+                report(index, expected, found, Test::MarchCMinus);
+            }
+            cells.write(index, write);
+        }
+    }
+    for index in 0..len {
+        let found = cells.read(index);
+        if found != ZERO {
+            report(index, ZERO, found, Test::MarchCMinus);
+        }
+    }
+}
+
+/// Writes back and invalidates every cache line, so nothing cached for the
+/// frames from before they were freed lands on top of a test later.
+fn flush_caches() {
+    unsafe { asm!("wbinvd", options(nostack, preserves_flags)) };
+}
+
+/// Tests the `count` frames starting at `start`, which the caller owns.
+fn test_frames(start: PhysFrame, count: usize, mode: Mode, on_fault: &mut impl FnMut(Fault)) -> Result<(), VmmError> {
+    let phys = start.start_address();
+    let size = count as u64 * Size4KiB::SIZE;
+
+    let virt = match mode {
+        Mode::Quick => phys_to_virt(phys),
+        Mode::Full => {
+            let mut mapper = MAPPER.lock();
+            let mut frame_allocator = FRAME_ALLOCATOR.lock();
+            VMM.lock().map_mmio(
+                phys,
+                size,
+                mapper.as_mut().expect("memory::install not called"),
+                frame_allocator.as_mut().expect("memory::install not called"),
+            )?
+        }
+    };
+
+    flush_caches();
+    let mut words = Words { ptr: virt.as_mut_ptr(), len: count * WORDS_PER_FRAME };
+    test_cells(&mut words, phys.as_u64(), mode, on_fault);
+
+    if mode == Mode::Full {
+        unmap_frames(virt)?;
+    }
+    Ok(())
+}
+
+/// Drops the uncached mapping at `virt`. The frames stay with the caller.
+fn unmap_frames(virt: VirtAddr) -> Result<(), VmmError> {
+    let mut mapper = MAPPER.lock();
+    let mut frame_allocator = FRAME_ALLOCATOR.lock();
+    // mapped as device memory, so the VMM leaves the frames alone
+    unsafe {
+        VMM.lock().unmap(
+            virt,
+            mapper.as_mut().expect("memory::install not called"),
+            frame_allocator.as_mut().expect("memory::install not called"),
+        )
+    }
+}
+
+/// Tests every free frame. `on_fault` is called for every word that reads
+/// back wrong, and `on_progress` with the bytes tested so far after each
+/// chunk. Neither may touch the frame allocator's locks.
+///
+/// Needs `memory::install` to have run and must not be called with
+/// [`MAPPER`], [`FRAME_ALLOCATOR`] or [`VMM`] locked.
+pub fn run(
+    mode: Mode,
+    mut on_fault: impl FnMut(Fault),
+    mut on_progress: impl FnMut(u64),
+) -> Result<Summary, VmmError> {
+    let mut summary = Summary::default();
+    let mut on_fault = |fault: Fault| {
+        summary.faults += 1;
+        on_fault(fault);
+    };
+
+    let mut tested_frames = 0;
+    let mut next = PhysFrame::containing_address(PhysAddr::new(0));
+    let total_frames = loop {
+        let claimed = {
+            let mut frame_allocator = FRAME_ALLOCATOR.lock();
+            let frame_allocator = frame_allocator.as_mut().expect("memory::install not called");
+            frame_allocator.claim_free_run(next, CHUNK_FRAMES).ok_or(frame_allocator.total_frames())
+        };
+        let (start, count) = match claimed {
+            Ok(run) => run,
+            Err(total_frames) => break total_frames,
+        };
+
+        let result = test_frames(start, count, mode, &mut on_fault);
+        unsafe {
+            FRAME_ALLOCATOR.lock().as_mut().unwrap().deallocate_contiguous(start, count);
+        }
+        result?;
+
+        tested_frames += count;
+        next = start + count as u64;
+        on_progress(tested_frames as u64 * Size4KiB::SIZE);
+    };
+
+    summary.tested_frames = tested_frames;
+    summary.skipped_frames = total_frames.saturating_sub(tested_frames);
+    Ok(summary)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec;
+    use alloc::vec::Vec;
+
+    /// Memory where one bit of one word never changes.
+    struct StuckBit {
+        words: Vec<u64>,
+        index: usize,
+        bit: u64,
+        value: bool,
+    }
+
+    impl Cells for StuckBit {
+        fn len(&self) -> usize {
+            self.words.len()
+        }
+
+        fn read(&self, index: usize) -> u64 {
+            let word = self.words[index];
+            match (index == self.index, self.value) {
+                (false, _) => word,
+                (true, true) => word | self.bit,
+                (true, false) => word & !self.bit,
+            }
+        }
+
+        fn write(&mut self, index: usize, value: u64) {
+            self.words[index] = value;
+        }
+    }
+
+    fn memory(stuck_at: Option<(usize, u64, bool)>) -> StuckBit {
+        let (index, bit, value) = stuck_at.unwrap_or((usize::MAX, 0, false));
+        StuckBit { words: vec![0; 2 * WORDS_PER_FRAME], index, bit, value }
+    }
+
+    fn faults(cells: &mut StuckBit, mode: Mode) -> Vec<Fault> {
+        let mut faults = Vec::new();
+        test_cells(cells, 0x10_0000, mode, &mut |fault| faults.push(fault));
+        faults
+    }
+
+    #[test_case]
+    fn good_memory_passes() {
+        for mode in Mode::ALL {
+            assert_eq!(faults(&mut memory(None), mode), []);
+        }
+    }
+
+    #[test_case]
+    fn stuck_bits_are_found_by_every_test() {
+        let mut cells = memory(Some((WORDS_PER_FRAME, 1 << 5, true)));
+        let faults = faults(&mut cells, Mode::Full);
+
+        let addr = PhysAddr::new(0x10_0000 + Size4KiB::SIZE);
+        assert!(faults.iter().all(|fault| fault.addr == addr));
+        assert!(faults.iter().all(|fault| fault.found ^ fault.expected == 1 << 5));
+        for test in [Test::WalkingOnes, Test::WalkingZeros, Test::AddressInAddress, Test::MarchCMinus] {
+            assert!(faults.iter().any(|fault| fault.test == test));
+        }
+    }
+
+    #[test_case]
+    fn quick_mode_skips_march() {
+        let mut cells = memory(Some((3, 1, false)));
+        let faults = faults(&mut cells, Mode::Quick);
+        assert!(!faults.is_empty());
+        assert!(faults.iter().all(|fault| fault.test == Test::AddressInAddress));
+    }
+
+    #[test_case]
+    fn mode_names_round_trip() {
+        for mode in Mode::ALL {
+            assert_eq!(Mode::from_name(mode.name()), Some(mode));
+        }
+        assert_eq!(Mode::from_name("slow"), None);
+    }
+}
@@ -0,0 +1,504 @@
+use alloc::vec::Vec;
+use core::ptr;
+use spin::Mutex;
+use x86_64::structures::paging::{
+    mapper::{FlagUpdateError, MapToError, UnmapError},
+    FrameAllocator, FrameDeallocator, Mapper, Page, PageSize, PageTableFlags, PhysFrame, Size2MiB,
+    Size4KiB,
+};
+use x86_64::{PhysAddr, VirtAddr};
+use super::{phys_to_virt, FRAME_ALLOCATOR, MAPPER};
+
+const PAGE_SIZE: u64 = Size4KiB::SIZE;
+
+/// Start of the kernel virtual address space the VMM hands out.
+pub const VMM_START: u64 = 0x_5555_0000_0000;
+/// Size of that address space, 64 GiB.
+pub const VMM_SIZE: u64 = 64 * 1024 * 1024 * 1024;
+/// Unmapped pages left after every region, so running off its end faults
+/// instead of corrupting the next one.
+const GUARD_PAGES: u64 = 1;
+
+/// The kernel's virtual memory manager. Lock after [`MAPPER`] and
+/// [`FRAME_ALLOCATOR`] when they're needed too.
+pub static VMM: Mutex<VirtualMemoryManager> =
+    Mutex::new(VirtualMemoryManager::new(VMM_START, VMM_SIZE));
+
+#[derive(Debug)]
+pub enum VmmError {
+    /// No hole in the address space is big enough.
+    OutOfVirtualMemory,
+    /// The region list couldn't grow.
+    OutOfMemory,
+    /// No region starts at the given address.
+    NoSuchRegion,
+    /// The frame allocator ran out of frames.
+    OutOfFrames,
+    /// Part of the region was mapped already, maybe by a huge page.
+    AlreadyMapped,
+    Unmap(UnmapError),
+    Protect(FlagUpdateError),
+}
+
+impl<S: PageSize> From<MapToError<S>> for VmmError {
+    fn from(err: MapToError<S>) -> Self {
+        match err {
+            MapToError::FrameAllocationFailed => VmmError::OutOfFrames,
+            MapToError::ParentEntryHugePage | MapToError::PageAlreadyMapped(_) => {
+                VmmError::AlreadyMapped
+            }
+        }
+    }
+}
+
+/// What's behind a region's pages.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RegionKind {
+    /// Frames from the frame allocator, freed with the region.
+    Memory,
+    /// Device registers starting at the given physical address. The frames
+    /// aren't RAM and are never freed.
+    Mmio(PhysAddr),
+}
+
+/// A page-aligned range of kernel virtual memory handed out by the VMM.
+#[derive(Debug, Clone, Copy)]
+pub struct Region {
+    pub start: VirtAddr,
+    /// Number of pages, each `page_size` bytes.
+    pub pages: u64,
+    /// 4 KiB, or 2 MiB for regions mapped with huge pages.
+    pub page_size: u64,
+    pub kind: RegionKind,
+    pub flags: PageTableFlags,
+}
+
+impl Region {
+    pub fn size(&self) -> u64 {
+        self.pages * self.page_size
+    }
+
+    pub fn end(&self) -> VirtAddr {
+        self.start + self.size()
+    }
+
+    fn page_range<S: PageSize>(&self) -> impl Iterator<Item = Page<S>> {
+        debug_assert_eq!(self.page_size, S::SIZE);
+        let first = Page::<S>::containing_address(self.start);
+        (0..self.pages).map(move |i| first + i)
+    }
+}
+
+/// Keeps track of which parts of the kernel address space are in use. The
+/// regions are kept sorted by address; the holes between them are free.
+pub struct VirtualMemoryManager {
+    start: u64,
+    end: u64,
+    regions: Vec<Region>,
+}
+
+impl VirtualMemoryManager {
+    pub const fn new(start: u64, size: u64) -> Self {
+        VirtualMemoryManager {
+            start,
+            end: start + size,
+            regions: Vec::new(),
+        }
+    }
+
+    /// The regions handed out, sorted by address.
+    pub fn regions(&self) -> &[Region] {
+        &self.regions
+    }
+
+    /// Finds a hole for `pages` pages of `page_size` bytes, aligned to the
+    /// page size, and records the region there without mapping anything.
+    fn reserve(&mut self, pages: u64, page_size: u64, kind: RegionKind, flags: PageTableFlags)
+               -> Result<Region, VmmError>
+    {
+        let size = pages.checked_mul(page_size).ok_or(VmmError::OutOfVirtualMemory)?;
+        // may run with the page tables locked, so the heap can't grow here
+        self.regions.try_reserve(1).map_err(|_| VmmError::OutOfMemory)?;
+
+        let mut start = self.start.next_multiple_of(page_size);
+        let mut index = self.regions.len();
+        for (i, region) in self.regions.iter().enumerate() {
+            if start + size + GUARD_PAGES * PAGE_SIZE <= region.start.as_u64() {
+                index = i;
+                break;
+            }
+            start = (region.end().as_u64() + GUARD_PAGES * PAGE_SIZE).next_multiple_of(page_size);
+        }
+        if index == self.regions.len() && start + size > self.end {
+            return Err(VmmError::OutOfVirtualMemory);
+        }
+
+        let region = Region { start: VirtAddr::new(start), pages, page_size, kind, flags };
+        self.regions.insert(index, region);
+        Ok(region)
+    }
+
+    /// Forgets the region starting at `start` and returns it.
+    fn release(&mut self, start: VirtAddr) -> Result<Region, VmmError> {
+        let index = self.position(start)?;
+        Ok(self.regions.remove(index))
+    }
+
+    fn position(&self, start: VirtAddr) -> Result<usize, VmmError> {
+        self.regions
+            .binary_search_by_key(&start, |region| region.start)
+            .map_err(|_| VmmError::NoSuchRegion)
+    }
+
+    /// Maps `size` bytes, rounded up to whole pages, of fresh zeroed frames
+    /// with `flags`.
+    pub fn map(
+        &mut self,
+        size: u64,
+        flags: PageTableFlags,
+        mapper: &mut impl Mapper<Size4KiB>,
+        frame_allocator: &mut (impl FrameAllocator<Size4KiB> + FrameDeallocator<Size4KiB>),
+    ) -> Result<Region, VmmError> {
+        self.map_pages::<Size4KiB>(size, flags, mapper, frame_allocator)
+    }
+
+    /// Like [`map`](Self::map), but with 2 MiB pages backed by physically
+    /// contiguous frames. Uses far fewer page table entries and TLB slots
+    /// for big regions like a framebuffer; `size` is rounded up to 2 MiB.
+    pub fn map_huge(
+        &mut self,
+        size: u64,
+        flags: PageTableFlags,
+        mapper: &mut impl Mapper<Size2MiB>,
+        frame_allocator: &mut (impl FrameAllocator<Size2MiB> + FrameAllocator<Size4KiB>
+                               + FrameDeallocator<Size2MiB>),
+    ) -> Result<Region, VmmError> {
+        self.map_pages::<Size2MiB>(size, flags, mapper, frame_allocator)
+    }
+
+    /// Maps fresh frames of size `S`. Page tables always come in 4 KiB
+    /// frames, hence the second allocator bound.
+    fn map_pages<S: PageSize>(
+        &mut self,
+        size: u64,
+        flags: PageTableFlags,
+        mapper: &mut impl Mapper<S>,
+        frame_allocator: &mut (impl FrameAllocator<S> + FrameAllocator<Size4KiB> + FrameDeallocator<S>),
+    ) -> Result<Region, VmmError> {
+        let flags = flags | PageTableFlags::PRESENT;
+        let pages = size.div_ceil(S::SIZE).max(1);
+        let region = self.reserve(pages, S::SIZE, RegionKind::Memory, flags)?;
+
+        for (mapped, page) in region.page_range::<S>().enumerate() {
+            let result = match FrameAllocator::<S>::allocate_frame(frame_allocator) {
+                Some(frame) => unsafe {
+                    // zeroed through the physical memory mapping, since `flags`
+                    // may not allow writing to the page
+                    let frame_start = phys_to_virt(frame.start_address()).as_mut_ptr::<u8>();
+                    ptr::write_bytes(frame_start, 0, S::SIZE as usize);
+                    mapper.map_to(page, frame, flags, frame_allocator)
+                        .map(|flush| flush.flush())
+                        .inspect_err(|_| frame_allocator.deallocate_frame(frame))
+                },
+                None => Err(MapToError::FrameAllocationFailed),
+            };
+
+            if let Err(err) = result {
+                // undo the pages mapped so far
+                let partial = Region { pages: mapped as u64, ..region };
+                unsafe { Self::unmap_pages::<S>(&partial, mapper, frame_allocator)? };
+                self.release(region.start)?;
+                return Err(err.into());
+            }
+        }
+
+        Ok(region)
+    }
+
+    /// Maps `size` bytes of device memory starting at `phys` as uncached and
+    /// returns the virtual address of `phys`.
+    ///
+    /// The physical memory mapping from the bootloader is cacheable and so
+    /// not suitable for registers; this maps the frames a second time.
+    pub fn map_mmio(
+        &mut self,
+        phys: PhysAddr,
+        size: u64,
+        mapper: &mut impl Mapper<Size4KiB>,
+        frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+    ) -> Result<VirtAddr, VmmError> {
+        let first_frame = PhysFrame::<Size4KiB>::containing_address(phys);
+        let last_frame = PhysFrame::<Size4KiB>::containing_address(phys + size.max(1) - 1u64);
+        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE
+            | PageTableFlags::NO_CACHE | PageTableFlags::WRITE_THROUGH;
+
+        let pages = (last_frame - first_frame) + 1;
+        let kind = RegionKind::Mmio(first_frame.start_address());
+        let region = self.reserve(pages, PAGE_SIZE, kind, flags)?;
+
+        let frames = PhysFrame::range_inclusive(first_frame, last_frame);
+        for (page, frame) in region.page_range::<Size4KiB>().zip(frames) {
+            let result = unsafe { mapper.map_to(page, frame, flags, frame_allocator) };
+            match result {
+                Ok(flush) => flush.flush(),
+                Err(err) => {
+                    // device frames aren't ours to free, so just unmap them
+                    for page in region.page_range::<Size4KiB>().take_while(|&mapped| mapped != page) {
+                        if let Ok((_, flush)) = mapper.unmap(page) {
+                            flush.flush();
+                        }
+                    }
+                    self.release(region.start)?;
+                    return Err(err.into());
+                }
+            }
+        }
+
+        Ok(region.start + (phys - first_frame.start_address()))
+    }
+
+    /// Unmaps the region starting at `start`, flushes it from the TLB and
+    /// gives its frames back to the frame allocator, unless it maps device
+    /// memory.
+    ///
+    /// # Safety
+    ///
+    /// Nothing may use the region anymore.
+    pub unsafe fn unmap(
+        &mut self,
+        start: VirtAddr,
+        mapper: &mut (impl Mapper<Size4KiB> + Mapper<Size2MiB>),
+        frame_deallocator: &mut (impl FrameDeallocator<Size4KiB> + FrameDeallocator<Size2MiB>),
+    ) -> Result<(), VmmError> {
+        let region = self.regions[self.position(start)?];
+        unsafe {
+            match region.page_size {
+                Size2MiB::SIZE => Self::unmap_pages::<Size2MiB>(&region, mapper, frame_deallocator)?,
+                _ => Self::unmap_pages::<Size4KiB>(&region, mapper, frame_deallocator)?,
+            }
+        }
+        self.release(start)?;
+        Ok(())
+    }
+
+    unsafe fn unmap_pages<S: PageSize>(
+        region: &Region,
+        mapper: &mut impl Mapper<S>,
+        frame_deallocator: &mut impl FrameDeallocator<S>,
+    ) -> Result<(), VmmError> {
+        for page in region.page_range::<S>() {
+            let (frame, flush) = mapper.unmap(page).map_err(VmmError::Unmap)?;
+            flush.flush();
+            if region.kind == RegionKind::Memory {
+                unsafe { frame_deallocator.deallocate_frame(frame) };
+            }
+        }
+        Ok(())
+    }
+
+    /// Changes the flags of every page in the region starting at `start`.
+    /// `PRESENT` is always kept.
+    ///
+    /// # Safety
+    ///
+    /// Taking away `WRITABLE` or adding `NO_EXECUTE` must not break anything
+    /// still using the region.
+    pub unsafe fn protect(
+        &mut self,
+        start: VirtAddr,
+        flags: PageTableFlags,
+        mapper: &mut (impl Mapper<Size4KiB> + Mapper<Size2MiB>),
+    ) -> Result<(), VmmError> {
+        let index = self.position(start)?;
+        let region = &mut self.regions[index];
+        let flags = flags | PageTableFlags::PRESENT;
+
+        unsafe {
+            match region.page_size {
+                Size2MiB::SIZE => Self::protect_pages::<Size2MiB>(region, flags, mapper)?,
+                _ => Self::protect_pages::<Size4KiB>(region, flags, mapper)?,
+            }
+        }
+        region.flags = flags;
+        Ok(())
+    }
+
+    unsafe fn protect_pages<S: PageSize>(
+        region: &Region,
+        flags: PageTableFlags,
+        mapper: &mut impl Mapper<S>,
+    ) -> Result<(), VmmError> {
+        for page in region.page_range::<S>() {
+            let flush = unsafe { mapper.update_flags(page, flags) }.map_err(VmmError::Protect)?;
+            flush.flush();
+        }
+        Ok(())
+    }
+}
+
+/// Maps `size` bytes of fresh zeroed memory with `flags` in the kernel
+/// address space. Needs `memory::install` to have run.
+pub fn map(size: u64, flags: PageTableFlags) -> Result<Region, VmmError> {
+    let mut mapper = MAPPER.lock();
+    let mut frame_allocator = FRAME_ALLOCATOR.lock();
+    VMM.lock().map(
+        size,
+        flags,
+        mapper.as_mut().expect("memory::install not called"),
+        frame_allocator.as_mut().expect("memory::install not called"),
+    )
+}
+
+/// Maps `size` bytes of fresh zeroed memory with 2 MiB pages. Needs
+/// `memory::install` to have run.
+pub fn map_huge(size: u64, flags: PageTableFlags) -> Result<Region, VmmError> {
+    let mut mapper = MAPPER.lock();
+    let mut frame_allocator = FRAME_ALLOCATOR.lock();
+    VMM.lock().map_huge(
+        size,
+        flags,
+        mapper.as_mut().expect("memory::install not called"),
+        frame_allocator.as_mut().expect("memory::install not called"),
+    )
+}
+
+/// Unmaps the region starting at `start` and frees its frames. Needs
+/// `memory::install` to have run.
+///
+/// # Safety
+///
+/// Nothing may use the region anymore.
+pub unsafe fn unmap(start: VirtAddr) -> Result<(), VmmError> {
+    let mut mapper = MAPPER.lock();
+    let mut frame_allocator = FRAME_ALLOCATOR.lock();
+    unsafe {
+        VMM.lock().unmap(
+            start,
+            mapper.as_mut().expect("memory::install not called"),
+            frame_allocator.as_mut().expect("memory::install not called"),
+        )
+    }
+}
+
+/// Changes the flags of the region starting at `start`. Needs
+/// `memory::install` to have run.
+///
+/// # Safety
+///
+/// See [`VirtualMemoryManager::protect`].
+pub unsafe fn protect(start: VirtAddr, flags: PageTableFlags) -> Result<(), VmmError> {
+    let mut mapper = MAPPER.lock();
+    unsafe { VMM.lock().protect(start, flags, mapper.as_mut().expect("memory::install not called")) }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory;
+
+    fn flags() -> PageTableFlags {
+        PageTableFlags::PRESENT | PageTableFlags::WRITABLE
+    }
+
+    #[test_case]
+    fn regions_are_kept_apart_by_guard_pages() {
+        let mut vmm = VirtualMemoryManager::new(VMM_START, 16 * PAGE_SIZE);
+        let a = vmm.reserve(2, PAGE_SIZE, RegionKind::Memory, flags()).unwrap();
+        let b = vmm.reserve(3, PAGE_SIZE, RegionKind::Memory, flags()).unwrap();
+        assert_eq!(a.start.as_u64(), VMM_START);
+        assert_eq!(b.start, a.end() + GUARD_PAGES * PAGE_SIZE);
+    }
+
+    #[test_case]
+    fn released_holes_are_reused() {
+        let mut vmm = VirtualMemoryManager::new(VMM_START, 16 * PAGE_SIZE);
+        let a = vmm.reserve(2, PAGE_SIZE, RegionKind::Memory, flags()).unwrap();
+        vmm.reserve(2, PAGE_SIZE, RegionKind::Memory, flags()).unwrap();
+        vmm.release(a.start).unwrap();
+
+        let c = vmm.reserve(1, PAGE_SIZE, RegionKind::Memory, flags()).unwrap();
+        assert_eq!(c.start, a.start);
+        assert!(vmm.regions().windows(2).all(|pair| pair[0].start < pair[1].start));
+        assert!(matches!(vmm.release(c.start + 1u64), Err(VmmError::NoSuchRegion)));
+    }
+
+    #[test_case]
+    fn address_space_runs_out() {
+        let mut vmm = VirtualMemoryManager::new(VMM_START, 4 * PAGE_SIZE);
+        vmm.reserve(3, PAGE_SIZE, RegionKind::Memory, flags()).unwrap();
+        assert!(matches!(
+            vmm.reserve(1, PAGE_SIZE, RegionKind::Memory, flags()),
+            Err(VmmError::OutOfVirtualMemory)
+        ));
+    }
+
+    #[test_case]
+    fn mapped_memory_is_usable_and_freed() {
+        let free_frames = || memory::FRAME_ALLOCATOR.lock().as_ref().unwrap().free_frames();
+        // the first mapping may need new page tables, which are kept
+        let region = map(3 * PAGE_SIZE, flags()).unwrap();
+        unsafe { unmap(region.start).unwrap() };
+        let before = free_frames();
+
+        let region = map(3 * PAGE_SIZE, flags()).unwrap();
+        assert_eq!(free_frames(), before - 3);
+        let memory = region.start.as_mut_ptr::<u64>();
+        unsafe {
+            assert_eq!(memory.read(), 0);
+            memory.write(0xdead_beef);
+            assert_eq!(memory.read(), 0xdead_beef);
+        }
+
+        unsafe {
+            protect(region.start, PageTableFlags::empty()).unwrap();
+            unmap(region.start).unwrap();
+        }
+        assert_eq!(free_frames(), before);
+        let offset = memory::physical_memory_offset().unwrap();
+        assert_eq!(unsafe { memory::translate_addr(region.start, offset) }, None);
+    }
+
+    #[test_case]
+    fn read_only_memory_is_zeroed() {
+        let region = map(2 * PAGE_SIZE, flags()).unwrap();
+        unsafe {
+            ptr::write_bytes(region.start.as_mut_ptr::<u8>(), 0xaa, region.size() as usize);
+            unmap(region.start).unwrap();
+        }
+
+        // likely gets the frames just dirtied back
+        let region = map(2 * PAGE_SIZE, PageTableFlags::empty()).unwrap();
+        let words = region.size() as usize / 8;
+        let memory = region.start.as_ptr::<u64>();
+        assert!((0..words).all(|index| unsafe { memory.add(index).read_volatile() } == 0));
+        unsafe { unmap(region.start).unwrap() };
+    }
+
+    #[test_case]
+    fn huge_regions_are_aligned() {
+        let mut vmm = VirtualMemoryManager::new(VMM_START, 8 * Size2MiB::SIZE);
+        vmm.reserve(1, PAGE_SIZE, RegionKind::Memory, flags()).unwrap();
+        let huge = vmm.reserve(2, Size2MiB::SIZE, RegionKind::Memory, flags()).unwrap();
+        assert_eq!(huge.start.as_u64() % Size2MiB::SIZE, 0);
+        assert_eq!(huge.size(), 2 * Size2MiB::SIZE);
+    }
+
+    #[test_case]
+    fn huge_pages_translate_and_free() {
+        let free_frames = || memory::FRAME_ALLOCATOR.lock().as_ref().unwrap().free_frames();
+        let offset = memory::physical_memory_offset().unwrap();
+        let region = map_huge(Size2MiB::SIZE, flags()).unwrap();
+        unsafe { unmap(region.start).unwrap() };
+        let before = free_frames();
+
+        let region = map_huge(Size2MiB::SIZE, flags()).unwrap();
+        assert_eq!(free_frames(), before - 512);
+        let start = unsafe { memory::translate_addr(region.start, offset) }.unwrap();
+        assert_eq!(start.as_u64() % Size2MiB::SIZE, 0);
+        let inside = region.start + 0x1_2345u64;
+        assert_eq!(unsafe { memory::translate_addr(inside, offset) }, Some(start + 0x1_2345u64));
+
+        unsafe { unmap(region.start).unwrap() };
+        assert_eq!(free_frames(), before);
+    }
+}
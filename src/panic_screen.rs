@@ -0,0 +1,107 @@
+use core::fmt::{self, Write};
+use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicBool, Ordering};
+use x86_64::instructions::interrupts;
+use x86_64::structures::paging::PageTableFlags;
+use x86_64::VirtAddr;
+use crate::drivers::vga_buffer::{self, Color, ColorCode, WRITER};
+use crate::serial::SERIAL1;
+use crate::{hlt_loop, ksyms, memory};
+
+/// Deepest call chain the backtrace will follow.
+const MAX_FRAMES: usize = 16;
+
+static PANICKING: AtomicBool = AtomicBool::new(false);
+
+/// Writes to the VGA buffer and COM1 at the same time, without waiting for
+/// any locks the panicking code may have been holding.
+struct PanicWriter;
+
+impl Write for PanicWriter {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        WRITER.lock().write_string(s);
+        SERIAL1.lock().write_str(s)
+    }
+}
+
+/// Shows the panic screen with a backtrace and halts for good.
+pub fn show(info: &PanicInfo) -> ! {
+    interrupts::disable();
+
+    // a panic while drawing the panic screen; nothing sane left to do
+    if PANICKING.swap(true, Ordering::SeqCst) {
+        hlt_loop();
+    }
+
+    unsafe { vga_buffer::force_unlock_console() };
+    {
+        let mut writer = WRITER.lock();
+        writer.set_custom_color_code(ColorCode::new(Color::White, Color::Red));
+        writer.clear_screen();
+    }
+
+    let mut out = PanicWriter;
+    let _ = writeln!(out, "*** KERNEL PANIC ***\n");
+    let _ = writeln!(out, "{}\n", info);
+    let _ = writeln!(out, "Backtrace:");
+    print_backtrace(&mut out);
+    let _ = writeln!(out, "\nSystem halted.");
+
+    hlt_loop();
+}
+
+/// Follows the chain of saved frame pointers starting at our own frame.
+fn print_backtrace(out: &mut PanicWriter) {
+    let mut rbp: u64;
+    unsafe { core::arch::asm!("mov {}, rbp", out(reg) rbp) };
+
+    if ksyms::count() == 0 {
+        let _ = writeln!(out, "  (no symbol table, run tools/embed-symbols.ps1)");
+    }
+
+    for depth in 0..MAX_FRAMES {
+        if rbp == 0 || !rbp.is_multiple_of(8) || !is_mapped(rbp) || !is_mapped(rbp + 8) {
+            break;
+        }
+
+        // [rbp] holds the caller's rbp, [rbp + 8] the return address
+        let (next, return_address) = unsafe { (*(rbp as *const u64), *((rbp + 8) as *const u64)) };
+        if return_address == 0 {
+            break;
+        }
+
+        // point into the call instruction, not at the one after it
+        let call_site = return_address - 1;
+        match ksyms::lookup(call_site) {
+            Some((name, offset)) => {
+                let _ = writeln!(out, "  #{:02} {:#018x} {}+{:#x}", depth, return_address, name, offset);
+            }
+            None => {
+                let _ = writeln!(out, "  #{:02} {:#018x} <unknown>", depth, return_address);
+            }
+        }
+
+        // stacks grow down, so a sane chain only ever moves up
+        if next <= rbp {
+            break;
+        }
+        rbp = next;
+    }
+}
+
+fn is_mapped(addr: u64) -> bool {
+    let offset = match memory::physical_memory_offset() {
+        Some(offset) => offset,
+        None => return true, // paging not set up, only the boot stack exists
+    };
+    let addr = match VirtAddr::try_new(addr) {
+        Ok(addr) => addr,
+        Err(_) => return false,
+    };
+
+    unsafe { memory::page_walk(addr, offset) }
+        .iter()
+        .flatten()
+        .last()
+        .is_some_and(|step| step.flags.contains(PageTableFlags::PRESENT))
+}
@@ -0,0 +1,138 @@
+use core::time::Duration;
+use x86_64::instructions::port::Port;
+use x86_64::PhysAddr;
+use crate::acpi::fadt::{self, Fadt, GenericAddress};
+use crate::{hlt_loop, memory, println, time};
+
+/// SCI_EN in PM1_CNT: set once the firmware has handed power management to ACPI.
+const SCI_EN: u16 = 1;
+/// SLP_EN in PM1_CNT: writing it enters the sleep state in SLP_TYP.
+const SLP_EN: u16 = 1 << 13;
+
+/// Something the shell asked the machine to do, carried out by the task
+/// that runs the shell once the CLI is unlocked again.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PowerAction {
+    Shutdown,
+    Reboot,
+}
+
+impl PowerAction {
+    /// Waits a moment so the last message can be read, then shuts down or
+    /// reboots. Never completes.
+    pub async fn run(self) {
+        time::sleep(Duration::from_millis(500)).await;
+        match self {
+            PowerAction::Shutdown => shutdown().await,
+            PowerAction::Reboot => reboot().await,
+        }
+    }
+}
+
+/// Powers the machine off through ACPI S5. Falls back to rebooting if
+/// that isn't possible or doesn't take. Never completes.
+pub async fn shutdown() {
+    if let Some(fadt) = fadt::parse() {
+        match fadt.s5_sleep_types() {
+            Some(sleep_types) => enter_s5(&fadt, sleep_types).await,
+            None => println!("No \\_S5_ object in the DSDT."),
+        }
+        // still here: give the hardware a moment, then give up
+        time::sleep(Duration::from_millis(500)).await;
+    }
+
+    println!("ACPI shutdown failed, rebooting instead.");
+    reboot().await;
+}
+
+/// Reboots the machine using the ACPI reset register, then the keyboard
+/// controller, and finally a triple fault. Never completes.
+pub async fn reboot() {
+    if let Some(reset) = fadt::parse().and_then(|f| f.reset_register.map(|r| (r, f.reset_value))) {
+        write_reset_register(reset.0, reset.1);
+        time::sleep(Duration::from_millis(500)).await;
+    }
+
+    reset_via_keyboard_controller().await;
+    time::sleep(Duration::from_millis(500)).await;
+
+    triple_fault();
+}
+
+async fn enter_s5(fadt: &Fadt, (slp_typ_a, slp_typ_b): (u8, u8)) {
+    let mut pm1a: Port<u16> = Port::new(fadt.pm1a_control_block as u16);
+
+    // switch to ACPI mode if the firmware hasn't already
+    if unsafe { pm1a.read() } & SCI_EN == 0 && fadt.smi_command_port != 0 && fadt.acpi_enable != 0 {
+        let mut smi_command: Port<u8> = Port::new(fadt.smi_command_port as u16);
+        unsafe { smi_command.write(fadt.acpi_enable) };
+
+        let deadline = time::Instant::now() + Duration::from_secs(1);
+        while unsafe { pm1a.read() } & SCI_EN == 0 && time::Instant::now() < deadline {
+            time::sleep(Duration::from_millis(10)).await;
+        }
+    }
+
+    unsafe {
+        pm1a.write(((slp_typ_a as u16) << 10) | SLP_EN);
+        if fadt.pm1b_control_block != 0 {
+            let mut pm1b: Port<u16> = Port::new(fadt.pm1b_control_block as u16);
+            pm1b.write(((slp_typ_b as u16) << 10) | SLP_EN);
+        }
+    }
+}
+
+fn write_reset_register(register: GenericAddress, value: u8) {
+    match register.space {
+        fadt::SPACE_SYSTEM_IO => unsafe {
+            Port::<u8>::new(register.address as u16).write(value);
+        },
+        fadt::SPACE_SYSTEM_MEMORY => unsafe {
+            let addr = memory::phys_to_virt(PhysAddr::new(register.address));
+            core::ptr::write_volatile(addr.as_mut_ptr::<u8>(), value);
+        },
+        fadt::SPACE_PCI_CONFIG => {
+            // bus 0; device, function and offset are packed into the address
+            let device = (register.address >> 32) & 0xFFFF;
+            let function = (register.address >> 16) & 0xFFFF;
+            let offset = register.address & 0xFFFF;
+            let config_address = 0x8000_0000 | (device << 11) | (function << 8) | (offset & 0xFC);
+            unsafe {
+                Port::<u32>::new(0xCF8).write(config_address as u32);
+                Port::<u8>::new(0xCFC + (offset & 0b11) as u16).write(value);
+            }
+        }
+        _ => {}
+    }
+}
+
+/// Pulses the CPU reset line through the 8042 keyboard controller. Gives
+/// up if the input buffer doesn't drain, as on machines without an 8042,
+/// where the status port reads 0xFF.
+async fn reset_via_keyboard_controller() {
+    let mut port: Port<u8> = Port::new(0x64);
+
+    let deadline = time::Instant::now() + Duration::from_millis(100);
+    while unsafe { port.read() } & 0x02 != 0 {
+        if time::Instant::now() >= deadline {
+            return;
+        }
+        time::sleep(Duration::from_millis(1)).await;
+    }
+    unsafe { port.write(0xFE) };
+}
+
+/// Loads an empty IDT and raises an exception, which can't be delivered and
+/// escalates to a triple fault, which resets the CPU.
+fn triple_fault() -> ! {
+    use x86_64::instructions::tables::{lidt, DescriptorTablePointer};
+    use x86_64::VirtAddr;
+
+    let empty = DescriptorTablePointer { limit: 0, base: VirtAddr::new(0) };
+    unsafe {
+        lidt(&empty);
+        core::arch::asm!("int3");
+    }
+
+    hlt_loop();
+}
@@ -1,250 +1,469 @@
+//! An in-memory filesystem.
+//!
+//! Everything here allocates fallibly, so running out of heap makes an
+//! operation fail instead of taking the kernel down.
+
 use alloc::{
-    string::{String, ToString},
+    collections::TryReserveError,
+    string::String,
+    sync::Arc,
     vec::Vec,
 };
+use core::sync::atomic::{AtomicU64, Ordering};
+use core::time::Duration;
 use spin::Mutex;
+use crate::time;
+use crate::vfs::{split_path, DirEntry, File, FileSystem, FileType, FsError, Metadata};
+
+/// Permission bits new files get.
+pub const DEFAULT_FILE_MODE: u16 = 0o644;
+/// Permission bits new directories get.
+pub const DEFAULT_DIR_MODE: u16 = 0o755;
+/// There are no users yet, so everything belongs to root.
+pub const ROOT_OWNER: u32 = 0;
+
+const ROOT_INODE: u64 = 1;
+/// Shared by every ramfs, so inodes are unique across mounts too.
+static NEXT_INODE: AtomicU64 = AtomicU64::new(ROOT_INODE + 1);
 
 #[derive(Debug)]
 pub enum NodeType {
-    File { data: Vec<u8> },
+    File { file: Arc<RamFile> },
     Dir { children: Vec<Node> },
 }
 
+/// The contents of a ramfs file, shared with everyone who has it open.
+#[derive(Debug)]
+pub struct RamFile {
+    contents: Mutex<Contents>,
+}
+
+#[derive(Debug)]
+struct Contents {
+    data: Vec<u8>,
+    /// Uptime of the last change.
+    modified: Duration,
+}
+
+impl RamFile {
+    fn new(data: &[u8]) -> Result<Self, TryReserveError> {
+        Ok(RamFile {
+            contents: Mutex::new(Contents { data: try_vec(data)?, modified: time::uptime() }),
+        })
+    }
+
+    /// Size and modification time.
+    fn metadata(&self) -> (usize, Duration) {
+        let contents = self.contents.lock();
+        (contents.data.len(), contents.modified)
+    }
+}
+
+/// Grows `data` to `len` bytes with zeros, failing instead of aborting.
+fn grow(data: &mut Vec<u8>, len: usize) -> Result<(), FsError> {
+    if len > data.len() {
+        data.try_reserve(len - data.len())?;
+        data.resize(len, 0);
+    }
+    Ok(())
+}
+
+impl File for RamFile {
+    fn size(&self) -> u64 {
+        self.contents.lock().data.len() as u64
+    }
+
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> Result<usize, FsError> {
+        let contents = self.contents.lock();
+        let rest = contents.data.get(offset as usize..).unwrap_or(&[]);
+        let len = rest.len().min(buf.len());
+        buf[..len].copy_from_slice(&rest[..len]);
+        Ok(len)
+    }
+
+    fn write_at(&self, offset: u64, data: &[u8]) -> Result<usize, FsError> {
+        let start = offset as usize;
+        let end = start.checked_add(data.len()).ok_or(FsError::InvalidArgument)?;
+        let mut contents = self.contents.lock();
+        grow(&mut contents.data, end)?;
+        contents.data[start..end].copy_from_slice(data);
+        contents.modified = time::uptime();
+        Ok(data.len())
+    }
+
+    fn append(&self, data: &[u8]) -> Result<u64, FsError> {
+        let mut contents = self.contents.lock();
+        contents.data.try_reserve(data.len())?;
+        contents.data.extend_from_slice(data);
+        contents.modified = time::uptime();
+        Ok(contents.data.len() as u64)
+    }
+
+    fn truncate(&self, size: u64) -> Result<(), FsError> {
+        let mut contents = self.contents.lock();
+        grow(&mut contents.data, size as usize)?;
+        contents.data.truncate(size as usize);
+        contents.modified = time::uptime();
+        Ok(())
+    }
+}
+
 #[derive(Debug)]
 pub struct Node {
     pub name: String,
     pub node_type: NodeType,
+    pub inode: u64,
+    /// Unix style permission bits, like `0o644`.
+    pub mode: u16,
+    pub owner: u32,
+    /// Uptime when the node was created.
+    pub created: Duration,
+    /// Uptime when the entries of a directory last changed. Files keep
+    /// theirs in the [`RamFile`].
+    pub modified: Duration,
 }
 
 impl Node {
-    fn new_dir(name: &str) -> Self {
-        Node {
-            name: name.to_string(),
-            node_type: NodeType::Dir { children: Vec::new() },
-        }
+    fn new(name: &str, node_type: NodeType, mode: u16) -> Result<Self, TryReserveError> {
+        let now = time::uptime();
+        Ok(Node {
+            name: try_string(name)?,
+            node_type,
+            inode: NEXT_INODE.fetch_add(1, Ordering::Relaxed),
+            mode,
+            owner: ROOT_OWNER,
+            created: now,
+            modified: now,
+        })
     }
 
-    fn new_file(name: &str, data: Vec<u8>) -> Self {
-        Node {
-            name: name.to_string(),
-            node_type: NodeType::File { data },
+    fn new_dir(name: &str) -> Result<Self, FsError> {
+        Ok(Node::new(name, NodeType::Dir { children: Vec::new() }, DEFAULT_DIR_MODE)?)
+    }
+
+    fn new_file(name: &str, data: &[u8]) -> Result<Self, FsError> {
+        let file = Arc::try_new(RamFile::new(data)?)?;
+        Ok(Node::new(name, NodeType::File { file }, DEFAULT_FILE_MODE)?)
+    }
+
+    pub fn metadata(&self) -> Metadata {
+        let (file_type, size, modified) = match &self.node_type {
+            NodeType::File { file } => {
+                let (size, modified) = file.metadata();
+                (FileType::File, size, modified)
+            }
+            NodeType::Dir { children } => (FileType::Dir, children.len(), self.modified),
+        };
+        Metadata {
+            inode: self.inode,
+            file_type,
+            size: size as u64,
+            mode: self.mode,
+            owner: self.owner,
+            created: self.created,
+            modified,
         }
     }
-}
 
-/// Global RAMFS root
-static RAMFS_ROOT: Mutex<Node> = Mutex::new(Node {
-    name: String::new(),
-    node_type: NodeType::Dir { children: Vec::new() },
-});
+    /// Marks the contents as changed just now.
+    fn touch(&mut self) {
+        self.modified = time::uptime();
+    }
+}
 
-fn split_path(path: &str) -> Vec<&str> {
-    path.split('/').filter(|p| !p.is_empty()).collect()
+/// A filesystem that lives on the kernel heap and is gone once unmounted.
+pub struct RamFs {
+    root: Mutex<Node>,
 }
 
-/// Resolve relative or absolute path
-pub fn resolve_path(current_directory: &str, target: &str) -> String {
-    if target.starts_with('/') {
-        // Absolute path
-        target.to_string()
-    } else {
-        // Relative path
-        let mut parts: Vec<&str> = split_path(current_directory);
-        for p in split_path(target) {
-            match p {
-                "." => {}
-                ".." => { parts.pop(); }
-                _ => parts.push(p),
-            }
+impl RamFs {
+    pub fn new() -> Self {
+        let now = time::uptime();
+        RamFs {
+            root: Mutex::new(Node {
+                name: String::new(),
+                node_type: NodeType::Dir { children: Vec::new() },
+                inode: ROOT_INODE,
+                mode: DEFAULT_DIR_MODE,
+                owner: ROOT_OWNER,
+                created: now,
+                modified: now,
+            }),
         }
+    }
+}
 
-        // Build path manually
-        let mut path = String::new();
-        path.push('/');
-        let mut first = true;
-        for part in parts {
-            if !first {
-                path.push('/');
-            }
-            first = false;
-            path.push_str(part);
-        }
-        path
+impl Default for RamFs {
+    fn default() -> Self {
+        Self::new()
     }
 }
 
-fn find_dir_mut<'a>(mut current: &'a mut Node, parts: &[&str]) -> Option<&'a mut Node> {
+/// `text.to_string()` that fails instead of aborting when the heap is full.
+fn try_string(text: &str) -> Result<String, TryReserveError> {
+    let mut string = String::new();
+    string.try_reserve_exact(text.len())?;
+    string.push_str(text);
+    Ok(string)
+}
+
+/// `data.to_vec()` that fails instead of aborting when the heap is full.
+fn try_vec(data: &[u8]) -> Result<Vec<u8>, TryReserveError> {
+    let mut vec = Vec::new();
+    vec.try_reserve_exact(data.len())?;
+    vec.extend_from_slice(data);
+    Ok(vec)
+}
+
+fn find_dir_mut<'a, 'b>(
+    mut current: &'a mut Node,
+    parts: impl Iterator<Item = &'b str>,
+) -> Result<&'a mut Node, FsError> {
     for part in parts {
         match &mut current.node_type {
             NodeType::Dir { children } => {
-                current = children.iter_mut().find(|n| n.name == *part)?;
+                current = children.iter_mut().find(|n| n.name == part).ok_or(FsError::NotFound)?;
             }
-            _ => return None,
+            _ => return Err(FsError::NotADirectory),
         }
     }
-    Some(current)
+    Ok(current)
 }
 
+/// Finds the directory `path` would be in and the last component of it.
+/// The root has no parent, so it's an invalid name here.
 fn find_parent_dir_mut<'a, 'b>(
     root: &'a mut Node,
-    path: &'b [&'b str],
-) -> Option<(&'a mut Node, &'b str)> {
-    if path.is_empty() {
-        return None;
+    path: &'b str,
+) -> Result<(&'a mut Node, &'b str), FsError> {
+    let mut parts = split_path(path);
+    let name = parts.next_back().ok_or(FsError::InvalidName)?;
+    let parent = find_dir_mut(root, parts)?;
+    match parent.node_type {
+        NodeType::Dir { .. } => Ok((parent, name)),
+        _ => Err(FsError::NotADirectory),
     }
-
-    let (dirs, name) = path.split_at(path.len() - 1);
-    let parent = find_dir_mut(root, dirs)?;
-    Some((parent, name[0]))
 }
 
-/// Change current directory
-pub fn change_directory(current_directory: &str, to_directory: &str) -> Option<String> {
-    let path = resolve_path(current_directory, to_directory);
-    let parts = split_path(&path);
-    let root = &mut RAMFS_ROOT.lock();
-    if find_dir_mut(root, &parts).is_some() {
-        Some(path)
-    } else {
-        None
+/// The entries of `dir`, which [`find_parent_dir_mut`] made sure is one.
+fn children_mut(dir: &mut Node) -> &mut Vec<Node> {
+    match &mut dir.node_type {
+        NodeType::Dir { children } => children,
+        NodeType::File { .. } => unreachable!("not a directory"),
     }
 }
 
-/// Create directory
-pub fn mkdir(current_directory: &str, path: &str) -> bool {
-    let path = resolve_path(current_directory, path);
-    let parts = split_path(&path);
-    let mut root = RAMFS_ROOT.lock();
-
-    let (parent, name) = match find_parent_dir_mut(&mut root, &parts) {
-        Some(v) => v,
-        None => return false,
-    };
-
-    if let NodeType::Dir { children } = &mut parent.node_type {
-        if children.iter().any(|n| n.name == name) { return false; }
-        children.push(Node::new_dir(name));
-        true
-    } else {
-        false
+/// Names must be usable as a path component.
+fn check_name(name: &str) -> Result<(), FsError> {
+    match name {
+        "" | "." | ".." => Err(FsError::InvalidName),
+        _ if name.contains('/') => Err(FsError::InvalidName),
+        _ => Ok(()),
     }
 }
 
-/// Create file
-pub fn create_file(current_directory: &str, path: &str, data: &[u8]) -> bool {
-    let path = resolve_path(current_directory, path);
-    let parts = split_path(&path);
-    let mut root = RAMFS_ROOT.lock();
-
-    let (parent, name) = match find_parent_dir_mut(&mut root, &parts) {
-        Some(v) => v,
-        None => return false,
-    };
-
-    if let NodeType::Dir { children } = &mut parent.node_type {
-        if children.iter().any(|n| n.name == name) { return false; }
-        children.push(Node::new_file(name, data.to_vec()));
-        true
-    } else {
-        false
+/// Adds `node` to `parent`, unless a node with that name exists already.
+fn add_child(parent: &mut Node, node: impl FnOnce() -> Result<Node, FsError>, name: &str)
+             -> Result<(), FsError>
+{
+    check_name(name)?;
+    let children = children_mut(parent);
+    if children.iter().any(|n| n.name == name) {
+        return Err(FsError::AlreadyExists);
     }
+    children.try_reserve(1)?;
+    children.push(node()?);
+    parent.touch();
+    Ok(())
 }
 
-/// Update file
-pub fn update_file(current_directory: &str, path: &str, data: &[u8]) -> bool {
-    let path = resolve_path(current_directory, path);
-    let parts = split_path(&path);
-    let mut root = RAMFS_ROOT.lock();
+impl FileSystem for RamFs {
+    fn name(&self) -> &'static str {
+        "ramfs"
+    }
 
-    let file = match find_dir_mut(&mut root, &parts) {
-        Some(f) => f,
-        None => return false,
-    };
+    fn stat(&self, path: &str) -> Result<Metadata, FsError> {
+        let mut root = self.root.lock();
+        Ok(find_dir_mut(&mut root, split_path(path))?.metadata())
+    }
 
-    if let NodeType::File { data: file_data } = &mut file.node_type {
-        *file_data = data.to_vec();
-        true
-    } else { false }
-}
+    fn list_dir(&self, path: &str) -> Result<Vec<DirEntry>, FsError> {
+        let mut root = self.root.lock();
+        match &find_dir_mut(&mut root, split_path(path))?.node_type {
+            NodeType::Dir { children } => {
+                let mut entries = Vec::new();
+                entries.try_reserve_exact(children.len())?;
+                for child in children {
+                    entries.push(DirEntry { name: try_string(&child.name)?, metadata: child.metadata() });
+                }
+                Ok(entries)
+            }
+            NodeType::File { .. } => Err(FsError::NotADirectory),
+        }
+    }
 
-/// Rename file
-pub fn rename_file(current_directory: &str, path: &str, new_name: &str) -> bool {
-    let path = resolve_path(current_directory, path);
-    let parts = split_path(&path);
-    let mut root = RAMFS_ROOT.lock();
+    fn mkdir(&self, path: &str) -> Result<(), FsError> {
+        let mut root = self.root.lock();
+        let (parent, name) = find_parent_dir_mut(&mut root, path)?;
+        add_child(parent, || Node::new_dir(name), name)
+    }
 
-    let (parent, old_name) = match find_parent_dir_mut(&mut root, &parts) { Some(v) => v, None => return false };
+    fn create_file(&self, path: &str, data: &[u8]) -> Result<(), FsError> {
+        let mut root = self.root.lock();
+        let (parent, name) = find_parent_dir_mut(&mut root, path)?;
+        add_child(parent, || Node::new_file(name, data), name)
+    }
 
-    if let NodeType::Dir { children } = &mut parent.node_type {
-        if children.iter().any(|n| n.name == new_name) { return false; }
-        if let Some(f) = children.iter_mut().find(|n| n.name == old_name) {
-            if matches!(f.node_type, NodeType::File { .. }) {
-                f.name = new_name.to_string();
-                return true;
-            }
+    fn open(&self, path: &str) -> Result<Arc<dyn File>, FsError> {
+        let mut root = self.root.lock();
+        match &find_dir_mut(&mut root, split_path(path))?.node_type {
+            NodeType::File { file } => Ok(file.clone()),
+            NodeType::Dir { .. } => Err(FsError::IsADirectory),
         }
     }
-    false
-}
-
-/// Rename folder
-pub fn rename_folder(current_directory: &str, path: &str, new_name: &str) -> bool {
-    let path = resolve_path(current_directory, path);
-    let parts = split_path(&path);
-    let mut root = RAMFS_ROOT.lock();
 
-    let (parent, old_name) = match find_parent_dir_mut(&mut root, &parts) { Some(v) => v, None => return false };
+    fn rename(&self, path: &str, new_name: &str) -> Result<(), FsError> {
+        check_name(new_name)?;
+        let mut root = self.root.lock();
+        let (parent, old_name) = find_parent_dir_mut(&mut root, path)?;
+        let children = children_mut(parent);
+        let index = children.iter().position(|n| n.name == old_name).ok_or(FsError::NotFound)?;
+        if children.iter().any(|n| n.name == new_name) {
+            return Err(FsError::AlreadyExists);
+        }
+        children[index].name = try_string(new_name)?;
+        parent.touch();
+        Ok(())
+    }
 
-    if let NodeType::Dir { children } = &mut parent.node_type {
-        if children.iter().any(|n| n.name == new_name) { return false; }
-        if let Some(f) = children.iter_mut().find(|n| n.name == old_name) {
-            if matches!(f.node_type, NodeType::Dir { .. }) {
-                f.name = new_name.to_string();
-                return true;
+    fn delete(&self, path: &str) -> Result<(), FsError> {
+        let mut root = self.root.lock();
+        let (parent, name) = find_parent_dir_mut(&mut root, path)?;
+        let children = children_mut(parent);
+        let index = children.iter().position(|n| n.name == name).ok_or(FsError::NotFound)?;
+        if let NodeType::Dir { children } = &children[index].node_type {
+            if !children.is_empty() {
+                return Err(FsError::DirectoryNotEmpty);
             }
         }
+        children.remove(index);
+        parent.touch();
+        Ok(())
     }
-    false
 }
 
-/// Read file
-pub fn read_file(current_directory: &str, path: &str) -> Option<Vec<u8>> {
-    let path = resolve_path(current_directory, path);
-    let parts = split_path(&path);
-    let mut root = RAMFS_ROOT.lock();
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec;
 
-    let node = find_dir_mut(&mut root, &parts)?;
-    match &node.node_type {
-        NodeType::File { data } => Some(data.clone()),
-        _ => None,
+    fn read(file: &dyn File) -> Vec<u8> {
+        let mut data = vec![0; file.size() as usize];
+        assert_eq!(file.read_at(0, &mut data), Ok(data.len()));
+        data
     }
-}
 
-/// Delete
-pub fn delete(current_directory: &str, path: &str) -> bool {
-    let path = resolve_path(current_directory, path);
-    let parts = split_path(&path);
-    let mut root = RAMFS_ROOT.lock();
+    #[test_case]
+    fn nodes_are_found_by_path() {
+        let fs = RamFs::new();
+        fs.mkdir("/sub").unwrap();
+        fs.create_file("/sub/file.txt", b"hello").unwrap();
 
-    let (parent, name) = match find_parent_dir_mut(&mut root, &parts) { Some(v) => v, None => return false };
+        assert_eq!(read(&*fs.open("/sub/file.txt").unwrap()), b"hello");
+        assert_eq!(fs.open("/sub").err(), Some(FsError::IsADirectory));
+        assert_eq!(fs.stat("/missing"), Err(FsError::NotFound));
+    }
 
-    if let NodeType::Dir { children } = &mut parent.node_type {
-        let before = children.len();
-        children.retain(|n| n.name != name);
-        return before != children.len();
+    #[test_case]
+    fn failures_say_what_went_wrong() {
+        let fs = RamFs::new();
+        fs.mkdir("/dir").unwrap();
+        fs.create_file("/dir/file", b"").unwrap();
+
+        assert_eq!(fs.mkdir("/dir/file"), Err(FsError::AlreadyExists));
+        assert_eq!(fs.mkdir("/dir/missing/dir"), Err(FsError::NotFound));
+        assert_eq!(fs.create_file("/dir/file/inner", b""), Err(FsError::NotADirectory));
+        assert_eq!(fs.list_dir("/dir/file"), Err(FsError::NotADirectory));
+        assert_eq!(fs.delete("/dir"), Err(FsError::DirectoryNotEmpty));
+        assert_eq!(fs.delete("/dir/missing"), Err(FsError::NotFound));
+        assert_eq!(fs.delete("/"), Err(FsError::InvalidName));
+
+        fs.delete("/dir/file").unwrap();
+        fs.delete("/dir").unwrap();
+        assert_eq!(fs.list_dir("/"), Ok(Vec::new()));
     }
-    false
-}
 
-/// List directory
-pub fn list_dir(current_directory: &str, path: &str) -> Option<Vec<String>> {
-    let path = resolve_path(current_directory, path);
-    let parts = split_path(&path);
-    let mut root = RAMFS_ROOT.lock();
+    #[test_case]
+    fn renames_check_the_new_name() {
+        let fs = RamFs::new();
+        fs.mkdir("/dir").unwrap();
+        fs.create_file("/file", b"").unwrap();
 
-    let dir = find_dir_mut(&mut root, &parts)?;
-    match &dir.node_type {
-        NodeType::Dir { children } => Some(children.iter().map(|n| n.name.clone()).collect()),
-        _ => None,
+        assert_eq!(fs.rename("/file", "dir"), Err(FsError::AlreadyExists));
+        assert_eq!(fs.rename("/file", "a/b"), Err(FsError::InvalidName));
+        assert_eq!(fs.rename("/missing", "x"), Err(FsError::NotFound));
+        fs.rename("/file", "renamed").unwrap();
+        fs.rename("/dir", "folder").unwrap();
+
+        let entries = fs.list_dir("/").unwrap();
+        assert!(entries.iter().map(|entry| entry.name.as_str()).eq(["folder", "renamed"]));
+    }
+
+    #[test_case]
+    fn nodes_carry_metadata() {
+        let fs = RamFs::new();
+        fs.mkdir("/dir").unwrap();
+        fs.create_file("/dir/file", b"hello").unwrap();
+
+        let dir = fs.stat("/dir").unwrap();
+        let file = fs.stat("/dir/file").unwrap();
+        assert_eq!((dir.file_type, dir.size, dir.mode), (FileType::Dir, 1, DEFAULT_DIR_MODE));
+        assert_eq!((file.file_type, file.size, file.mode), (FileType::File, 5, DEFAULT_FILE_MODE));
+        assert_eq!(file.owner, ROOT_OWNER);
+        assert!(file.inode > dir.inode);
+        assert!(file.created >= dir.created);
+        assert_eq!(fs.stat("/").unwrap().inode, ROOT_INODE);
+
+        fs.open("/dir/file").unwrap().append(b" world").unwrap();
+        let updated = fs.stat("/dir/file").unwrap();
+        assert_eq!((updated.inode, updated.size, updated.created), (file.inode, 11, file.created));
+        assert!(updated.modified >= file.modified);
+
+        let entries = fs.list_dir("/dir").unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].metadata, updated);
+    }
+
+    #[test_case]
+    fn files_are_read_and_written_in_place() {
+        let fs = RamFs::new();
+        fs.create_file("/file", b"hello").unwrap();
+        let file = fs.open("/file").unwrap();
+
+        let mut buf = [0; 3];
+        assert_eq!(file.read_at(3, &mut buf), Ok(2));
+        assert_eq!(&buf[..2], b"lo");
+        assert_eq!(file.read_at(10, &mut buf), Ok(0));
+
+        file.write_at(1, b"EL").unwrap();
+        file.write_at(7, b"!").unwrap();
+        assert_eq!(read(&*file), b"hELlo\0\0!");
+        assert_eq!(file.append(b"?"), Ok(9));
+
+        file.truncate(2).unwrap();
+        assert_eq!(read(&*fs.open("/file").unwrap()), b"hE");
+        file.truncate(4).unwrap();
+        assert_eq!(read(&*file), b"hE\0\0");
     }
-}
\ No newline at end of file
+
+    #[test_case]
+    fn open_files_outlive_their_node() {
+        let fs = RamFs::new();
+        fs.create_file("/file", b"kept").unwrap();
+        let file = fs.open("/file").unwrap();
+        fs.delete("/file").unwrap();
+
+        assert_eq!(read(&*file), b"kept");
+        assert_eq!(fs.open("/file").err(), Some(FsError::NotFound));
+    }
+}
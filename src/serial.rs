@@ -1,3 +1,20 @@
+use lazy_static::lazy_static;
+use spin::Mutex;
+use crate::drivers::uart::{SerialPort, COM1};
+
+lazy_static! {
+    pub static ref SERIAL1: Mutex<SerialPort> = {
+        let mut serial_port = unsafe { SerialPort::new(COM1) };
+        serial_port.init();
+        Mutex::new(serial_port)
+    };
+}
+
+/// Sets up COM1 so input interrupts can arrive before the first print.
+pub fn init() {
+    lazy_static::initialize(&SERIAL1);
+}
+
 #[doc(hidden)]
 pub fn _print(args: ::core::fmt::Arguments) {
     use core::fmt::Write;
@@ -9,4 +26,4 @@ pub fn _print(args: ::core::fmt::Arguments) {
             .write_fmt(args)
             .expect("Printing to serial failed");
     });
-}
\ No newline at end of file
+}
@@ -91,14 +91,135 @@ impl Executor {
                 .entry(task_id)
                 .or_insert_with(|| TaskWaker::new(task_id, task_queue.clone()));
             let mut context = Context::from_waker(waker);
-            match task.poll(&mut context) {
+            TaskId::set_current(task_id);
+            let poll = task.poll(&mut context);
+            TaskId::set_current(TaskId::KERNEL);
+            match poll {
                 Poll::Ready(()) => {
-                    // task done -> remove it and its cached waker
+                    // task done -> remove it, its cached waker and its open files
                     tasks.remove(&task_id);
                     waker_cache.remove(&task_id);
+                    crate::vfs::file::close_all(task_id);
                 }
                 Poll::Pending => {}
             }
         }
     }
 }
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::future::Future;
+    use core::pin::Pin;
+    use core::sync::atomic::{AtomicUsize, Ordering};
+
+    /// Returns `Pending` once, waking itself so it gets polled again.
+    struct YieldNow(bool);
+
+    impl Future for YieldNow {
+        type Output = ();
+
+        fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+            if self.0 {
+                Poll::Ready(())
+            } else {
+                self.0 = true;
+                cx.waker().wake_by_ref();
+                Poll::Pending
+            }
+        }
+    }
+
+    #[test_case]
+    fn runs_spawned_tasks_to_completion() {
+        static DONE: AtomicUsize = AtomicUsize::new(0);
+
+        let mut executor = Executor::new();
+        for _ in 0..3 {
+            executor.spawn(Task::new(async {
+                DONE.fetch_add(1, Ordering::SeqCst);
+            }));
+        }
+        executor.run_ready_tasks();
+
+        assert_eq!(DONE.load(Ordering::SeqCst), 3);
+        assert!(executor.tasks.is_empty());
+        assert!(executor.waker_cache.is_empty());
+    }
+
+    #[test_case]
+    fn woken_tasks_are_polled_again() {
+        static STEPS: AtomicUsize = AtomicUsize::new(0);
+
+        let mut executor = Executor::new();
+        executor.spawn(Task::new(async {
+            STEPS.fetch_add(1, Ordering::SeqCst);
+            YieldNow(false).await;
+            STEPS.fetch_add(1, Ordering::SeqCst);
+        }));
+        executor.run_ready_tasks();
+
+        assert_eq!(STEPS.load(Ordering::SeqCst), 2);
+        assert!(executor.tasks.is_empty());
+    }
+
+    #[test_case]
+    fn pending_tasks_stay_until_woken() {
+        let mut executor = Executor::new();
+        executor.spawn(Task::new(core::future::pending()));
+        executor.run_ready_tasks();
+
+        assert_eq!(executor.tasks.len(), 1);
+        assert!(executor.task_queue.is_empty());
+    }
+
+    #[test_case]
+    fn tasks_see_their_own_id() {
+        static SEEN: AtomicUsize = AtomicUsize::new(usize::MAX);
+
+        let mut executor = Executor::new();
+        let task = Task::new(async {
+            SEEN.store(TaskId::current().0 as usize, Ordering::SeqCst);
+        });
+        let task_id = task.id;
+        executor.spawn(task);
+        executor.run_ready_tasks();
+
+        assert_eq!(SEEN.load(Ordering::SeqCst), task_id.0 as usize);
+        assert_eq!(TaskId::current(), TaskId::KERNEL);
+    }
+
+    #[test_case]
+    fn finished_tasks_lose_their_open_files() {
+        use crate::vfs::{self, file};
+
+        static OPENED: AtomicUsize = AtomicUsize::new(0);
+
+        vfs::mount_root_for_tests();
+        let _ = vfs::create_file("/", "/executor-test", b"data");
+
+        let mut executor = Executor::new();
+        let mut task_ids = [TaskId::KERNEL; 2];
+        for task_id in &mut task_ids {
+            let task = Task::new(async {
+                let fd = file::open("/", "/executor-test", file::OpenFlags::READ).unwrap();
+                // every task has its own table, so each gets the first descriptor
+                assert_eq!(alloc::format!("{}", fd), "0");
+                assert!(file::has_table(TaskId::current()));
+                OPENED.fetch_add(1, Ordering::SeqCst);
+                YieldNow(false).await;
+            });
+            *task_id = task.id;
+            executor.spawn(task);
+        }
+        executor.run_ready_tasks();
+
+        assert_eq!(OPENED.load(Ordering::SeqCst), 2);
+        assert!(executor.tasks.is_empty());
+        for task_id in task_ids {
+            assert!(!file::has_table(task_id));
+        }
+    }
+}
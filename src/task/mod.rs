@@ -5,16 +5,33 @@ use core::sync::atomic::{AtomicU64, Ordering};
 
 pub mod simple_executor;
 pub mod keyboard;
+pub mod serial;
 pub mod executor;
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
-struct TaskId(u64);
+pub struct TaskId(u64);
+
+/// The task the executor is polling, or `TaskId::KERNEL` outside of one.
+static CURRENT_TASK: AtomicU64 = AtomicU64::new(TaskId::KERNEL.0);
 
 impl TaskId {
+    /// Stands for code that doesn't run in a task, like `_start` or the
+    /// tests.
+    pub const KERNEL: TaskId = TaskId(u64::MAX);
+
     fn new() -> Self {
         static NEXT_ID: AtomicU64 = AtomicU64::new(0);
         TaskId(NEXT_ID.fetch_add(1, Ordering::Relaxed))
     }
+
+    /// The task running right now.
+    pub fn current() -> TaskId {
+        TaskId(CURRENT_TASK.load(Ordering::Relaxed))
+    }
+
+    fn set_current(task_id: TaskId) {
+        CURRENT_TASK.store(task_id.0, Ordering::Relaxed);
+    }
 }
 
 impl Task {
@@ -35,4 +52,8 @@ impl Task {
             future: Box::pin(future),
         }
     }
+
+    pub fn id(&self) -> TaskId {
+        self.id
+    }
 }
\ No newline at end of file
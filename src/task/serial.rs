@@ -0,0 +1,122 @@
+use conquer_once::spin::OnceCell;
+use crossbeam_queue::ArrayQueue;
+use core::{pin::Pin, task::{Context, Poll}};
+use futures_util::stream::{Stream, StreamExt};
+use futures_util::task::AtomicWaker;
+use pc_keyboard::KeyCode;
+use crate::cli::{self, CLI};
+
+static BYTE_QUEUE: OnceCell<ArrayQueue<u8>> = OnceCell::uninit();
+static WAKER: AtomicWaker = AtomicWaker::new();
+
+/// Called by the serial interrupt handler
+///
+/// Must not block or allocate. Bytes that don't fit are dropped silently,
+/// since warning about it on the console would only make things worse.
+pub(crate) fn add_byte(byte: u8) {
+    if let Ok(queue) = BYTE_QUEUE.try_get() {
+        if queue.push(byte).is_ok() {
+            WAKER.wake();
+        }
+    }
+}
+
+/// Stream of raw bytes received on COM1.
+pub struct SerialStream {
+    _private: (),
+}
+
+impl SerialStream {
+    pub fn new() -> Self {
+        let _ = BYTE_QUEUE.try_init_once(|| ArrayQueue::new(256));
+        SerialStream { _private: () }
+    }
+}
+
+impl Default for SerialStream {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Stream for SerialStream {
+    type Item = u8;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<u8>> {
+        let queue = BYTE_QUEUE
+            .try_get()
+            .expect("serial queue not initialized");
+
+        // fast path
+        if let Some(byte) = queue.pop() {
+            return Poll::Ready(Some(byte));
+        }
+
+        WAKER.register(cx.waker());
+        match queue.pop() {
+            Some(byte) => {
+                WAKER.take();
+                Poll::Ready(Some(byte))
+            }
+            None => Poll::Pending,
+        }
+    }
+}
+
+/// Where we are inside a terminal escape sequence.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Escape {
+    None,
+    Start,
+    Csi,
+}
+
+/// Turns bytes from a host terminal into shell input. Enter arrives as
+/// `\r`, `\n` or `\r\n`, backspace as DEL or BS, and the left/right arrows
+/// as `ESC [ D` / `ESC [ C`.
+pub async fn handle_serial_input() {
+    let mut bytes = SerialStream::new();
+    let mut escape = Escape::None;
+    let mut last_was_cr = false;
+
+    while let Some(byte) = bytes.next().await {
+        let after_cr = core::mem::replace(&mut last_was_cr, byte == b'\r');
+
+        escape = match (escape, byte) {
+            (Escape::None, 0x1b) => Escape::Start,
+            (Escape::Start, b'[') => Escape::Csi,
+            (Escape::Csi, b'C') => {
+                CLI.lock().handle_special_key(KeyCode::ArrowRight);
+                Escape::None
+            }
+            (Escape::Csi, b'D') => {
+                CLI.lock().handle_special_key(KeyCode::ArrowLeft);
+                Escape::None
+            }
+            // parameters of a sequence we don't care about
+            (Escape::Csi, b'0'..=b'9' | b';') => Escape::Csi,
+            (Escape::Start | Escape::Csi, _) => Escape::None,
+            (Escape::None, b'\r') => {
+                CLI.lock().handle_input('\n');
+                cli::run_power_action().await;
+                Escape::None
+            }
+            (Escape::None, b'\n') => {
+                if !after_cr {
+                    CLI.lock().handle_input('\n');
+                    cli::run_power_action().await;
+                }
+                Escape::None
+            }
+            (Escape::None, 0x7f | 0x08) => {
+                CLI.lock().handle_input('\x08');
+                Escape::None
+            }
+            (Escape::None, b' '..=b'~') => {
+                CLI.lock().handle_input(byte as char);
+                Escape::None
+            }
+            (Escape::None, _) => Escape::None,
+        };
+    }
+}
@@ -0,0 +1,53 @@
+use core::panic::PanicInfo;
+use x86_64::instructions::port::Port;
+use crate::{hlt_loop, serial_print, serial_println};
+
+/// I/O port of QEMU's `isa-debug-exit` device, see `test-args` in Cargo.toml.
+const ISA_DEBUG_EXIT_PORT: u16 = 0xf4;
+
+/// Values written to `isa-debug-exit`. QEMU exits with `(value << 1) | 1`,
+/// so `Success` becomes exit code 33, which bootimage treats as a pass.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u32)]
+pub enum QemuExitCode {
+    Success = 0x10,
+    Failed = 0x11,
+}
+
+/// Exits QEMU with `exit_code`. Returns only when there is no
+/// `isa-debug-exit` device, i.e. outside of `cargo test`.
+pub fn exit_qemu(exit_code: QemuExitCode) {
+    let mut port = Port::new(ISA_DEBUG_EXIT_PORT);
+    unsafe { port.write(exit_code as u32) };
+}
+
+/// Something the test runner can run and report on.
+pub trait Testable {
+    fn run(&self);
+}
+
+impl<T: Fn()> Testable for T {
+    fn run(&self) {
+        serial_print!("{}...\t", core::any::type_name::<T>());
+        self();
+        serial_println!("[ok]");
+    }
+}
+
+/// Runs every `#[test_case]` and exits QEMU. A failing test panics, which
+/// ends up in `test_panic_handler`.
+pub fn test_runner(tests: &[&dyn Testable]) {
+    serial_println!("Running {} tests", tests.len());
+    for test in tests {
+        test.run();
+    }
+    exit_qemu(QemuExitCode::Success);
+}
+
+/// Panic handler used in test mode: reports the failure and exits QEMU.
+pub fn test_panic_handler(info: &PanicInfo) -> ! {
+    serial_println!("[failed]\n");
+    serial_println!("Error: {}\n", info);
+    exit_qemu(QemuExitCode::Failed);
+    hlt_loop();
+}
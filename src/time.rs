@@ -0,0 +1,358 @@
+use core::future::Future;
+use core::ops::{Add, Sub};
+use core::pin::Pin;
+use core::sync::atomic::{AtomicU64, Ordering};
+use core::task::{Context, Poll, Waker};
+use core::time::Duration;
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+/// Rate the timer interrupt is asked to advance the tick counter at. The
+/// rate actually set is only close to it, see [`tick_picos`].
+pub const TIMER_FREQUENCY_HZ: u64 = 1000;
+const PICOS_PER_SEC: u64 = 1_000_000_000_000;
+const NOMINAL_TICK_PICOS: u64 = PICOS_PER_SEC / TIMER_FREQUENCY_HZ;
+
+/// Maximum number of `Sleep` futures that can wait at the same time.
+const MAX_SLEEPERS: usize = 32;
+
+static TICKS: AtomicU64 = AtomicU64::new(0);
+/// Length of a tick in picoseconds, as programmed by `init`.
+static TICK_PICOS: AtomicU64 = AtomicU64::new(NOMINAL_TICK_PICOS);
+
+struct Sleeper {
+    deadline: u64,
+    waker: Option<Waker>,
+}
+
+static SLEEPERS: Mutex<[Option<Sleeper>; MAX_SLEEPERS]> = {
+    const EMPTY: Option<Sleeper> = None;
+    Mutex::new([EMPTY; MAX_SLEEPERS])
+};
+
+/// Programs the PIT to [`TIMER_FREQUENCY_HZ`] and keeps the tick length it
+/// ended up with.
+pub fn init() {
+    let picos = crate::drivers::pit::set_frequency(TIMER_FREQUENCY_HZ as u32);
+    TICK_PICOS.store(picos, Ordering::SeqCst);
+}
+
+/// Length of one timer tick in picoseconds.
+pub fn tick_picos() -> u64 {
+    TICK_PICOS.load(Ordering::SeqCst)
+}
+
+/// Actual timer rate in millihertz.
+pub fn frequency_millihertz() -> u64 {
+    (PICOS_PER_SEC * 1000) / tick_picos()
+}
+
+/// Called from the timer interrupt. Advances the clock and wakes every task
+/// whose sleep deadline has passed.
+pub(crate) fn tick() {
+    let now = TICKS.fetch_add(1, Ordering::SeqCst) + 1;
+
+    // sleepers register with interrupts disabled, so this only fails if we
+    // interrupted nothing relevant; just try again on the next tick
+    if let Some(mut sleepers) = SLEEPERS.try_lock() {
+        for sleeper in sleepers.iter_mut().flatten() {
+            if sleeper.deadline <= now {
+                if let Some(waker) = sleeper.waker.take() {
+                    waker.wake();
+                }
+            }
+        }
+    }
+}
+
+/// Number of timer ticks since boot.
+pub fn ticks() -> u64 {
+    TICKS.load(Ordering::SeqCst)
+}
+
+/// Time elapsed since the timer was started.
+pub fn uptime() -> Duration {
+    ticks_to_duration(ticks(), tick_picos())
+}
+
+/// Converts a tick count to the time it spans. Ticks are `tick_picos` long.
+fn ticks_to_duration(ticks: u64, tick_picos: u64) -> Duration {
+    let nanos = ticks as u128 * tick_picos as u128 / 1000;
+    Duration::from_nanos(nanos.min(u64::MAX as u128) as u64)
+}
+
+/// Converts a duration to ticks, rounding up so that waits are never short.
+fn duration_to_ticks(duration: Duration, tick_picos: u64) -> u64 {
+    let ticks = duration.as_nanos().saturating_mul(1000).div_ceil(tick_picos as u128);
+    ticks.min(u64::MAX as u128) as u64
+}
+
+/// A point on the monotonic tick clock, like `std::time::Instant`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Instant(u64);
+
+impl Instant {
+    pub fn now() -> Self {
+        Instant(ticks())
+    }
+
+    /// Time that has passed since this instant.
+    pub fn elapsed(&self) -> Duration {
+        Self::now() - *self
+    }
+
+    /// Time from `earlier` to `self`, or zero if `earlier` is later.
+    pub fn duration_since(&self, earlier: Instant) -> Duration {
+        ticks_to_duration(self.0.saturating_sub(earlier.0), tick_picos())
+    }
+
+    pub fn checked_add(&self, duration: Duration) -> Option<Instant> {
+        self.0.checked_add(duration_to_ticks(duration, tick_picos())).map(Instant)
+    }
+
+    /// Raw tick count of this instant.
+    pub fn ticks(&self) -> u64 {
+        self.0
+    }
+}
+
+impl Add<Duration> for Instant {
+    type Output = Instant;
+
+    fn add(self, duration: Duration) -> Instant {
+        self.checked_add(duration).expect("overflow when adding duration to instant")
+    }
+}
+
+impl Sub<Instant> for Instant {
+    type Output = Duration;
+
+    fn sub(self, earlier: Instant) -> Duration {
+        self.duration_since(earlier)
+    }
+}
+
+/// Future that completes once its deadline has passed. Created by [`sleep`].
+pub struct Sleep {
+    deadline: Instant,
+    slot: Option<usize>,
+}
+
+/// Returns a future that completes after `duration`.
+pub fn sleep(duration: Duration) -> Sleep {
+    sleep_until(Instant::now() + duration)
+}
+
+/// Returns a future that completes at `deadline`.
+pub fn sleep_until(deadline: Instant) -> Sleep {
+    Sleep { deadline, slot: None }
+}
+
+impl Sleep {
+    fn register(&mut self, waker: &Waker) -> bool {
+        interrupts::without_interrupts(|| {
+            let mut sleepers = SLEEPERS.lock();
+            let slot = match self.slot {
+                Some(slot) => slot,
+                None => match sleepers.iter().position(|s| s.is_none()) {
+                    Some(slot) => slot,
+                    None => return false,
+                },
+            };
+            sleepers[slot] = Some(Sleeper {
+                deadline: self.deadline.0,
+                waker: Some(waker.clone()),
+            });
+            self.slot = Some(slot);
+            true
+        })
+    }
+
+    fn unregister(&mut self) {
+        if let Some(slot) = self.slot.take() {
+            interrupts::without_interrupts(|| {
+                SLEEPERS.lock()[slot] = None;
+            });
+        }
+    }
+}
+
+impl Future for Sleep {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+        if Instant::now() >= self.deadline {
+            self.unregister();
+            return Poll::Ready(());
+        }
+
+        if !self.register(cx.waker()) {
+            // no free slot, fall back to polling on every executor pass
+            cx.waker().wake_by_ref();
+        }
+
+        // the deadline may have passed while registering
+        if Instant::now() >= self.deadline {
+            self.unregister();
+            Poll::Ready(())
+        } else {
+            Poll::Pending
+        }
+    }
+}
+
+impl Drop for Sleep {
+    fn drop(&mut self) {
+        self.unregister();
+    }
+}
+
+/// Halts the CPU until `duration` has passed, for code that can't `.await`.
+///
+/// Interrupts are enabled while waiting because the clock only moves forward
+/// from the timer interrupt; the previous state is restored afterwards.
+pub fn sleep_blocking(duration: Duration) {
+    let deadline = Instant::now() + duration;
+    let were_enabled = interrupts::are_enabled();
+
+    while Instant::now() < deadline {
+        interrupts::enable_and_hlt();
+    }
+
+    if !were_enabled {
+        interrupts::disable();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::sync::Arc;
+    use alloc::task::Wake;
+    use alloc::vec::Vec;
+    use core::sync::atomic::AtomicBool;
+
+    /// Remembers whether it was woken.
+    struct Flag(AtomicBool);
+
+    impl Wake for Flag {
+        fn wake(self: Arc<Self>) {
+            self.0.store(true, Ordering::SeqCst);
+        }
+    }
+
+    fn flag() -> (Arc<Flag>, Waker) {
+        let flag = Arc::new(Flag(AtomicBool::new(false)));
+        (flag.clone(), Waker::from(flag))
+    }
+
+    fn poll(sleep: &mut Sleep, waker: &Waker) -> Poll<()> {
+        Pin::new(sleep).poll(&mut Context::from_waker(waker))
+    }
+
+    fn free_slots() -> usize {
+        interrupts::without_interrupts(|| SLEEPERS.lock().iter().filter(|s| s.is_none()).count())
+    }
+
+    #[test_case]
+    fn durations_round_up_to_whole_ticks() {
+        let picos = NOMINAL_TICK_PICOS;
+        assert_eq!(duration_to_ticks(Duration::ZERO, picos), 0);
+        assert_eq!(duration_to_ticks(Duration::from_nanos(1), picos), 1);
+        assert_eq!(duration_to_ticks(Duration::from_millis(3), picos), 3);
+        assert_eq!(duration_to_ticks(Duration::MAX, picos), u64::MAX);
+        assert_eq!(ticks_to_duration(1500, picos), Duration::from_millis(1500));
+    }
+
+    #[test_case]
+    fn ticks_use_the_programmed_length() {
+        // a divisor of 1193 makes the PIT run at about 1000.15 Hz
+        let picos = tick_picos();
+        assert_eq!(picos, 999_847_467);
+        assert_eq!(ticks_to_duration(1_000_000, picos), Duration::from_nanos(999_847_467_000));
+        assert_eq!(duration_to_ticks(Duration::from_secs(1), picos), 1001);
+        assert_eq!(frequency_millihertz(), 1_000_152);
+    }
+
+    #[test_case]
+    fn instants_add_and_subtract() {
+        let start = Instant(100);
+        let twenty_ticks = ticks_to_duration(20, tick_picos());
+        assert_eq!(start + twenty_ticks, Instant(120));
+        assert_eq!(Instant(120) - start, twenty_ticks);
+        assert_eq!(start.duration_since(Instant(120)), Duration::ZERO);
+        assert_eq!(Instant(u64::MAX).checked_add(Duration::from_millis(1)), None);
+    }
+
+    #[test_case]
+    fn uptime_follows_the_timer() {
+        let start = Instant::now();
+        let before = uptime();
+        while Instant::now() == start {
+            x86_64::instructions::hlt();
+        }
+        assert!(uptime() > before);
+        assert!(start.elapsed() >= ticks_to_duration(1, tick_picos()));
+    }
+
+    #[test_case]
+    fn past_deadlines_are_ready_at_once() {
+        let (flag, waker) = flag();
+        let free = free_slots();
+        let mut sleep = sleep_until(Instant(0));
+        assert_eq!(poll(&mut sleep, &waker), Poll::Ready(()));
+        assert_eq!(sleep.slot, None);
+        assert_eq!(free_slots(), free);
+        assert!(!flag.0.load(Ordering::SeqCst));
+    }
+
+    #[test_case]
+    fn sleepers_are_woken_after_the_deadline() {
+        let (flag, waker) = flag();
+        let free = free_slots();
+        let mut sleep = sleep(Duration::from_millis(5));
+        assert_eq!(poll(&mut sleep, &waker), Poll::Pending);
+        assert!(sleep.slot.is_some());
+        assert_eq!(free_slots(), free - 1);
+
+        while !flag.0.load(Ordering::SeqCst) {
+            x86_64::instructions::hlt();
+        }
+        assert!(Instant::now() >= sleep.deadline);
+        assert_eq!(poll(&mut sleep, &waker), Poll::Ready(()));
+        assert_eq!(sleep.slot, None);
+        assert_eq!(free_slots(), free);
+    }
+
+    #[test_case]
+    fn dropped_sleepers_free_their_slot() {
+        let (_, waker) = flag();
+        let free = free_slots();
+        let mut sleep = sleep(Duration::from_secs(60));
+        assert_eq!(poll(&mut sleep, &waker), Poll::Pending);
+        assert_eq!(free_slots(), free - 1);
+        drop(sleep);
+        assert_eq!(free_slots(), free);
+    }
+
+    #[test_case]
+    fn sleepers_without_a_slot_poll_again() {
+        let (_, waker) = flag();
+        let free = free_slots();
+        let mut sleeps: Vec<Sleep> = (0..free).map(|_| sleep(Duration::from_secs(60))).collect();
+        for sleep in &mut sleeps {
+            assert_eq!(poll(sleep, &waker), Poll::Pending);
+        }
+        assert_eq!(free_slots(), 0);
+
+        let (flag, extra_waker) = flag();
+        let mut extra = sleep(Duration::from_secs(60));
+        assert_eq!(poll(&mut extra, &extra_waker), Poll::Pending);
+        assert_eq!(extra.slot, None);
+        // woken straight away so the executor polls it again
+        assert!(flag.0.load(Ordering::SeqCst));
+
+        drop(sleeps);
+        assert_eq!(free_slots(), free);
+    }
+}
@@ -0,0 +1,508 @@
+//! The virtual filesystem: one tree of paths made of the filesystems in the
+//! mount table.
+//!
+//! Paths given to the functions here are relative to a current directory
+//! and go through the mount table; the [`FileSystem`] behind a mount only
+//! ever sees absolute, normalized paths inside itself.
+
+use alloc::{
+    alloc::AllocError,
+    collections::TryReserveError,
+    string::String,
+    sync::Arc,
+    vec::Vec,
+};
+use core::fmt;
+use core::time::Duration;
+use spin::Mutex;
+use crate::ramfs::RamFs;
+
+pub mod file;
+
+/// Why a filesystem operation failed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FsError {
+    NotFound,
+    AlreadyExists,
+    /// A file was used where a directory was needed.
+    NotADirectory,
+    /// A directory was used where a file was needed.
+    IsADirectory,
+    DirectoryNotEmpty,
+    /// Empty, `.`, `..`, contains a `/`, or names the root.
+    InvalidName,
+    OutOfMemory,
+    /// Something is mounted there or below it.
+    Busy,
+    NotAMountPoint,
+    UnknownFileSystem,
+    /// Not an open file, or not opened for this kind of access.
+    BadDescriptor,
+    TooManyOpenFiles,
+    /// Like seeking before the start of a file, or bad open flags.
+    InvalidArgument,
+}
+
+impl fmt::Display for FsError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let message = match self {
+            FsError::NotFound => "no such file or directory",
+            FsError::AlreadyExists => "already exists",
+            FsError::NotADirectory => "not a directory",
+            FsError::IsADirectory => "is a directory",
+            FsError::DirectoryNotEmpty => "directory not empty",
+            FsError::InvalidName => "invalid name",
+            FsError::OutOfMemory => "out of memory",
+            FsError::Busy => "mount point busy",
+            FsError::NotAMountPoint => "not a mount point",
+            FsError::UnknownFileSystem => "unknown filesystem type",
+            FsError::BadDescriptor => "bad file descriptor",
+            FsError::TooManyOpenFiles => "too many open files",
+            FsError::InvalidArgument => "invalid argument",
+        };
+        f.write_str(message)
+    }
+}
+
+impl From<TryReserveError> for FsError {
+    fn from(_: TryReserveError) -> Self {
+        FsError::OutOfMemory
+    }
+}
+
+impl From<AllocError> for FsError {
+    fn from(_: AllocError) -> Self {
+        FsError::OutOfMemory
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileType {
+    File,
+    Dir,
+}
+
+/// What `stat` knows about a node.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Metadata {
+    pub inode: u64,
+    pub file_type: FileType,
+    /// Bytes for a file, entries for a directory.
+    pub size: u64,
+    pub mode: u16,
+    pub owner: u32,
+    pub created: Duration,
+    pub modified: Duration,
+}
+
+/// A directory entry, as returned by [`list_dir`].
+#[derive(Debug, PartialEq, Eq)]
+pub struct DirEntry {
+    pub name: String,
+    pub metadata: Metadata,
+}
+
+/// The contents of an open file. Stays usable after the file is deleted or
+/// renamed, like on Unix.
+pub trait File: Send + Sync {
+    fn size(&self) -> u64;
+    /// Copies bytes from `offset` on into `buf` and returns how many. Reads
+    /// at or past the end return 0.
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> Result<usize, FsError>;
+    /// Writes `data` at `offset`, filling any gap before it with zeros.
+    /// Nothing is written if the file can't grow enough.
+    fn write_at(&self, offset: u64, data: &[u8]) -> Result<usize, FsError>;
+    /// Writes `data` at the end in one go and returns the new size.
+    fn append(&self, data: &[u8]) -> Result<u64, FsError>;
+    /// Cuts the file to `size` bytes or extends it with zeros.
+    fn truncate(&self, size: u64) -> Result<(), FsError>;
+}
+
+/// A tree of files and directories that can be mounted somewhere. Paths
+/// are absolute inside the filesystem and already normalized, so `/` is
+/// its root and there are no `.` or `..` components.
+pub trait FileSystem: Send + Sync {
+    /// Type name, as given to `mount`.
+    fn name(&self) -> &'static str;
+    fn stat(&self, path: &str) -> Result<Metadata, FsError>;
+    fn list_dir(&self, path: &str) -> Result<Vec<DirEntry>, FsError>;
+    fn mkdir(&self, path: &str) -> Result<(), FsError>;
+    fn create_file(&self, path: &str, data: &[u8]) -> Result<(), FsError>;
+    /// Gets at the contents of a file, without copying them.
+    fn open(&self, path: &str) -> Result<Arc<dyn File>, FsError>;
+    /// Renames a node within its directory.
+    fn rename(&self, path: &str, new_name: &str) -> Result<(), FsError>;
+    /// Deletes a file or an empty directory.
+    fn delete(&self, path: &str) -> Result<(), FsError>;
+}
+
+/// Filesystem types `mount` can create.
+pub const FILESYSTEMS: [&str; 1] = ["ramfs"];
+
+/// Creates an empty filesystem of type `name`.
+pub fn new_filesystem(name: &str) -> Result<Arc<dyn FileSystem>, FsError> {
+    match name {
+        "ramfs" => Ok(Arc::try_new(RamFs::new())?),
+        _ => Err(FsError::UnknownFileSystem),
+    }
+}
+
+pub fn split_path(path: &str) -> impl DoubleEndedIterator<Item = &str> {
+    path.split('/').filter(|p| !p.is_empty())
+}
+
+/// Resolve relative or absolute path, dropping `.` and `..` components
+pub fn resolve_path(current_directory: &str, target: &str) -> Result<String, TryReserveError> {
+    let mut parts: Vec<&str> = Vec::new();
+    // relative paths start from the current directory
+    if !target.starts_with('/') {
+        for p in split_path(current_directory) {
+            parts.try_reserve(1)?;
+            parts.push(p);
+        }
+    }
+    for p in split_path(target) {
+        match p {
+            "." => {}
+            ".." => { parts.pop(); }
+            _ => {
+                parts.try_reserve(1)?;
+                parts.push(p);
+            }
+        }
+    }
+
+    // Build path manually
+    let mut path = String::new();
+    path.try_reserve_exact(parts.iter().map(|part| part.len() + 1).sum::<usize>().max(1))?;
+    path.push('/');
+    let mut first = true;
+    for part in parts {
+        if !first {
+            path.push('/');
+        }
+        first = false;
+        path.push_str(part);
+    }
+    Ok(path)
+}
+
+/// A filesystem attached at `path`.
+pub struct Mount {
+    pub path: String,
+    pub fs: Arc<dyn FileSystem>,
+}
+
+/// Where every filesystem is mounted. Paths are resolved against the
+/// longest mount point they are in.
+pub struct MountTable {
+    mounts: Vec<Mount>,
+}
+
+impl MountTable {
+    pub const fn new() -> Self {
+        MountTable { mounts: Vec::new() }
+    }
+
+    pub fn mounts(&self) -> &[Mount] {
+        &self.mounts
+    }
+
+    /// Whether normalized `path` is `mount_point` or below it.
+    fn is_below(path: &str, mount_point: &str) -> bool {
+        mount_point == "/"
+            || path.strip_prefix(mount_point).is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
+    }
+
+    /// The filesystem normalized `path` is on, and the path inside it.
+    pub fn find<'p>(&self, path: &'p str) -> Result<(Arc<dyn FileSystem>, &'p str), FsError> {
+        let mount = self
+            .mounts
+            .iter()
+            .filter(|mount| Self::is_below(path, &mount.path))
+            .max_by_key(|mount| mount.path.len())
+            .ok_or(FsError::NotFound)?;
+        let inner = match &path[mount.path.len()..] {
+            _ if mount.path == "/" => path,
+            "" => "/",
+            inner => inner,
+        };
+        Ok((mount.fs.clone(), inner))
+    }
+
+    pub fn is_mount_point(&self, path: &str) -> bool {
+        self.mounts.iter().any(|mount| mount.path == path)
+    }
+
+    /// The mounts at normalized `path` or below it.
+    pub fn mounts_below<'a>(&'a self, path: &'a str) -> impl Iterator<Item = &'a Mount> {
+        self.mounts.iter().filter(move |mount| Self::is_below(&mount.path, path))
+    }
+
+    /// Attaches `fs` at normalized `path`. Whether there is a directory to
+    /// mount it on is up to the caller.
+    pub fn mount(&mut self, path: String, fs: Arc<dyn FileSystem>) -> Result<(), FsError> {
+        if self.is_mount_point(&path) {
+            return Err(FsError::Busy);
+        }
+        self.mounts.try_reserve(1)?;
+        self.mounts.push(Mount { path, fs });
+        Ok(())
+    }
+
+    /// Detaches what is mounted at normalized `path`, unless something else
+    /// is mounted below it.
+    pub fn umount(&mut self, path: &str) -> Result<Arc<dyn FileSystem>, FsError> {
+        let index = self.mounts.iter().position(|mount| mount.path == path).ok_or(FsError::NotAMountPoint)?;
+        if self.mounts_below(path).any(|mount| mount.path != path) {
+            return Err(FsError::Busy);
+        }
+        Ok(self.mounts.remove(index).fs)
+    }
+}
+
+impl Default for MountTable {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// The kernel's mount table.
+pub static MOUNTS: Mutex<MountTable> = Mutex::new(MountTable::new());
+
+/// Mounts an empty ramfs as the root. Needs the heap.
+pub fn init() {
+    let root = new_filesystem("ramfs").expect("ramfs is always available");
+    MOUNTS.lock().mount(String::from("/"), root).expect("root is already mounted");
+}
+
+/// Mounts the root like [`init`] unless something already did, for tests
+/// that go through the mount table.
+#[cfg(test)]
+pub fn mount_root_for_tests() {
+    let unmounted = MOUNTS.lock().mounts().is_empty();
+    if unmounted {
+        init();
+    }
+}
+
+/// Resolves `path` and runs `f` on the filesystem it is on, with the mount
+/// table unlocked.
+fn with_fs<T>(
+    current_directory: &str,
+    path: &str,
+    f: impl FnOnce(&dyn FileSystem, &str) -> Result<T, FsError>,
+) -> Result<T, FsError> {
+    let path = resolve_path(current_directory, path)?;
+    let (fs, inner) = MOUNTS.lock().find(&path)?;
+    f(&*fs, inner)
+}
+
+/// Like [`with_fs`], but refuses mount points and anything with a mount
+/// below it, which can't be changed through the filesystem they are on.
+fn with_fs_unmounted<T>(
+    current_directory: &str,
+    path: &str,
+    f: impl FnOnce(&dyn FileSystem, &str) -> Result<T, FsError>,
+) -> Result<T, FsError> {
+    let path = resolve_path(current_directory, path)?;
+    let (fs, inner) = {
+        let mounts = MOUNTS.lock();
+        if mounts.mounts_below(&path).next().is_some() {
+            return Err(FsError::Busy);
+        }
+        mounts.find(&path)?
+    };
+    f(&*fs, inner)
+}
+
+/// Change current directory
+pub fn change_directory(current_directory: &str, to_directory: &str) -> Result<String, FsError> {
+    let path = resolve_path(current_directory, to_directory)?;
+    match stat("/", &path)?.file_type {
+        FileType::Dir => Ok(path),
+        FileType::File => Err(FsError::NotADirectory),
+    }
+}
+
+/// Metadata of the node at `path`
+pub fn stat(current_directory: &str, path: &str) -> Result<Metadata, FsError> {
+    with_fs(current_directory, path, |fs, path| fs.stat(path))
+}
+
+/// List directory
+pub fn list_dir(current_directory: &str, path: &str) -> Result<Vec<DirEntry>, FsError> {
+    with_fs(current_directory, path, |fs, path| fs.list_dir(path))
+}
+
+/// Create directory
+pub fn mkdir(current_directory: &str, path: &str) -> Result<(), FsError> {
+    with_fs(current_directory, path, |fs, path| fs.mkdir(path))
+}
+
+/// Create file
+pub fn create_file(current_directory: &str, path: &str, data: &[u8]) -> Result<(), FsError> {
+    with_fs(current_directory, path, |fs, path| fs.create_file(path, data))
+}
+
+/// The contents of the file at `path`, created empty first if `create` is
+/// set and there is none.
+pub fn open_file(current_directory: &str, path: &str, create: bool) -> Result<Arc<dyn File>, FsError> {
+    with_fs(current_directory, path, |fs, path| match fs.open(path) {
+        Err(FsError::NotFound) if create => {
+            fs.create_file(path, &[])?;
+            fs.open(path)
+        }
+        result => result,
+    })
+}
+
+/// Reads a whole file. Use [`file::open`] and [`file::read`] for large
+/// ones.
+pub fn read_file(current_directory: &str, path: &str) -> Result<Vec<u8>, FsError> {
+    let file = open_file(current_directory, path, false)?;
+    let mut data = Vec::new();
+    data.try_reserve_exact(file.size() as usize)?;
+    data.resize(file.size() as usize, 0);
+    let read = file.read_at(0, &mut data)?;
+    data.truncate(read);
+    Ok(data)
+}
+
+/// Replaces the contents of a file. The old contents stay if the new ones
+/// don't fit.
+pub fn update_file(current_directory: &str, path: &str, data: &[u8]) -> Result<(), FsError> {
+    let file = open_file(current_directory, path, false)?;
+    file.write_at(0, data)?;
+    file.truncate(data.len() as u64)
+}
+
+/// Renames a file or directory within its directory.
+pub fn rename(current_directory: &str, path: &str, new_name: &str) -> Result<(), FsError> {
+    with_fs_unmounted(current_directory, path, |fs, path| fs.rename(path, new_name))
+}
+
+/// Deletes a file or an empty directory.
+pub fn delete(current_directory: &str, path: &str) -> Result<(), FsError> {
+    with_fs_unmounted(current_directory, path, |fs, path| fs.delete(path))
+}
+
+/// Mounts a new filesystem of type `fs_type` on the directory at `path`.
+pub fn mount(current_directory: &str, fs_type: &str, path: &str) -> Result<(), FsError> {
+    let path = resolve_path(current_directory, path)?;
+    let fs = new_filesystem(fs_type)?;
+    if stat("/", &path)?.file_type != FileType::Dir {
+        return Err(FsError::NotADirectory);
+    }
+    MOUNTS.lock().mount(path, fs)
+}
+
+/// Unmounts whatever is mounted at `path`. Its files are gone with it.
+pub fn umount(current_directory: &str, path: &str) -> Result<(), FsError> {
+    let path = resolve_path(current_directory, path)?;
+    if path == "/" {
+        return Err(FsError::Busy);
+    }
+    let fs = MOUNTS.lock().umount(&path)?;
+    // dropped with the table unlocked, in case that takes a while
+    drop(fs);
+    Ok(())
+}
+
+/// Calls `f` with the path and type of every mount, in mount order. Runs
+/// with the mount table locked.
+pub fn for_each_mount(mut f: impl FnMut(&str, &'static str)) {
+    for mount in MOUNTS.lock().mounts() {
+        f(&mount.path, mount.fs.name());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn absolute_path_is_kept() {
+        assert_eq!(resolve_path("/home", "/etc/motd").unwrap(), "/etc/motd");
+    }
+
+    #[test_case]
+    fn relative_path_joins_current_directory() {
+        assert_eq!(resolve_path("/", "home").unwrap(), "/home");
+        assert_eq!(resolve_path("/home", "docs/todo.txt").unwrap(), "/home/docs/todo.txt");
+        assert_eq!(resolve_path("/home/", "docs//todo.txt").unwrap(), "/home/docs/todo.txt");
+    }
+
+    #[test_case]
+    fn dot_and_dot_dot_are_resolved() {
+        assert_eq!(resolve_path("/home/docs", ".").unwrap(), "/home/docs");
+        assert_eq!(resolve_path("/home/docs", "..").unwrap(), "/home");
+        assert_eq!(resolve_path("/home/docs", "../music/./a.mp3").unwrap(), "/home/music/a.mp3");
+    }
+
+    #[test_case]
+    fn dot_dot_stops_at_root() {
+        assert_eq!(resolve_path("/home", "../../..").unwrap(), "/");
+        assert_eq!(resolve_path("/", "..").unwrap(), "/");
+    }
+
+    #[test_case]
+    fn absolute_paths_are_normalized() {
+        assert_eq!(resolve_path("/home", "/etc/../home/./docs/").unwrap(), "/home/docs");
+        assert_eq!(resolve_path("/home", "/..").unwrap(), "/");
+    }
+
+    fn table() -> (MountTable, Arc<dyn FileSystem>, Arc<dyn FileSystem>) {
+        let root = new_filesystem("ramfs").unwrap();
+        let mnt = new_filesystem("ramfs").unwrap();
+        let mut table = MountTable::new();
+        table.mount(String::from("/"), root.clone()).unwrap();
+        table.mount(String::from("/mnt"), mnt.clone()).unwrap();
+        (table, root, mnt)
+    }
+
+    #[test_case]
+    fn paths_resolve_to_the_longest_mount_point() {
+        let (table, root, mnt) = table();
+        let on = |path, fs: &Arc<dyn FileSystem>, inner| {
+            let (found, found_inner) = table.find(path).unwrap();
+            assert!(Arc::ptr_eq(&found, fs));
+            assert_eq!(found_inner, inner);
+        };
+
+        on("/", &root, "/");
+        on("/home/a", &root, "/home/a");
+        on("/mnt", &mnt, "/");
+        on("/mnt/a/b", &mnt, "/a/b");
+        on("/mntx", &root, "/mntx");
+    }
+
+    #[test_case]
+    fn mount_points_are_unique_and_unmount_innermost_first() {
+        let (mut table, _, _) = table();
+        let nested = new_filesystem("ramfs").unwrap();
+
+        assert_eq!(table.mount(String::from("/mnt"), nested.clone()).err(), Some(FsError::Busy));
+        table.mount(String::from("/mnt/inner"), nested).unwrap();
+        assert_eq!(table.umount("/mnt").err(), Some(FsError::Busy));
+        assert_eq!(table.umount("/home").err(), Some(FsError::NotAMountPoint));
+
+        let below = |path| table.mounts_below(path).map(|mount| mount.path.as_str()).collect::<Vec<_>>();
+        assert_eq!(below("/mnt"), ["/mnt", "/mnt/inner"]);
+        assert_eq!(below("/mnt/inner"), ["/mnt/inner"]);
+        assert_eq!(below("/home"), [] as [&str; 0]);
+
+        table.umount("/mnt/inner").unwrap();
+        table.umount("/mnt").unwrap();
+        assert!(!table.is_mount_point("/mnt"));
+        assert_eq!(table.mounts().len(), 1);
+    }
+
+    #[test_case]
+    fn unknown_filesystems_cant_be_created() {
+        for name in FILESYSTEMS {
+            assert_eq!(new_filesystem(name).unwrap().name(), name);
+        }
+        assert_eq!(new_filesystem("ext2").err(), Some(FsError::UnknownFileSystem));
+    }
+}
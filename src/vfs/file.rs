@@ -0,0 +1,390 @@
+//! Open files, reached through small integer descriptors.
+//!
+//! Every task has its own descriptor table, which goes away with the task.
+//! Reads and writes go straight to the file's contents at the descriptor's
+//! offset, so nothing gets copied beyond what the caller asks for.
+
+use alloc::{sync::Arc, vec::Vec};
+use core::fmt;
+use core::ops::BitOr;
+use spin::Mutex;
+use crate::task::TaskId;
+use super::{open_file, File, FsError};
+
+/// Files a task can have open at once.
+pub const MAX_OPEN_FILES: usize = 64;
+
+/// How to open a file. Combine with `|`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct OpenFlags(u8);
+
+impl OpenFlags {
+    pub const READ: OpenFlags = OpenFlags(1 << 0);
+    pub const WRITE: OpenFlags = OpenFlags(1 << 1);
+    /// Every write goes to the end of the file. Implies `WRITE`.
+    pub const APPEND: OpenFlags = OpenFlags(1 << 2);
+    /// Create the file if it doesn't exist.
+    pub const CREATE: OpenFlags = OpenFlags(1 << 3);
+    /// Empty the file when opening it. Needs `WRITE` or `APPEND`.
+    pub const TRUNCATE: OpenFlags = OpenFlags(1 << 4);
+
+    pub fn contains(self, other: OpenFlags) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    fn readable(self) -> bool {
+        self.contains(OpenFlags::READ)
+    }
+
+    fn writable(self) -> bool {
+        self.contains(OpenFlags::WRITE) || self.contains(OpenFlags::APPEND)
+    }
+}
+
+impl BitOr for OpenFlags {
+    type Output = OpenFlags;
+
+    fn bitor(self, other: OpenFlags) -> OpenFlags {
+        OpenFlags(self.0 | other.0)
+    }
+}
+
+/// Where [`seek`] moves to, like `std::io::SeekFrom`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SeekFrom {
+    Start(u64),
+    Current(i64),
+    End(i64),
+}
+
+/// An open file of the current task.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Fd(usize);
+
+impl fmt::Display for Fd {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+/// A file opened through a descriptor, with its own offset.
+struct OpenFile {
+    file: Arc<dyn File>,
+    flags: OpenFlags,
+    offset: u64,
+}
+
+impl OpenFile {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, FsError> {
+        if !self.flags.readable() {
+            return Err(FsError::BadDescriptor);
+        }
+        let read = self.file.read_at(self.offset, buf)?;
+        self.offset += read as u64;
+        Ok(read)
+    }
+
+    fn write(&mut self, data: &[u8]) -> Result<usize, FsError> {
+        if !self.flags.writable() {
+            return Err(FsError::BadDescriptor);
+        }
+        if self.flags.contains(OpenFlags::APPEND) {
+            self.offset = self.file.append(data)?;
+        } else {
+            self.file.write_at(self.offset, data)?;
+            self.offset += data.len() as u64;
+        }
+        Ok(data.len())
+    }
+
+    fn seek(&mut self, pos: SeekFrom) -> Result<u64, FsError> {
+        let offset = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::Current(delta) => self.offset.checked_add_signed(delta),
+            SeekFrom::End(delta) => self.file.size().checked_add_signed(delta),
+        };
+        self.offset = offset.ok_or(FsError::InvalidArgument)?;
+        Ok(self.offset)
+    }
+
+    fn truncate(&mut self, size: u64) -> Result<(), FsError> {
+        if !self.flags.writable() {
+            return Err(FsError::BadDescriptor);
+        }
+        self.file.truncate(size)
+    }
+}
+
+/// The open files of one task, indexed by descriptor. Closed descriptors
+/// are reused lowest first.
+#[derive(Default)]
+struct DescriptorTable {
+    files: Vec<Option<OpenFile>>,
+}
+
+impl DescriptorTable {
+    /// The descriptor the next file gets, with room made for it so that
+    /// [`fill`](Self::fill) can't fail.
+    fn reserve(&mut self) -> Result<Fd, FsError> {
+        if let Some(index) = self.files.iter().position(Option::is_none) {
+            return Ok(Fd(index));
+        }
+        if self.files.len() >= MAX_OPEN_FILES {
+            return Err(FsError::TooManyOpenFiles);
+        }
+        self.files.try_reserve(1)?;
+        Ok(Fd(self.files.len()))
+    }
+
+    /// Puts `file` at `fd`, which [`reserve`](Self::reserve) just returned.
+    fn fill(&mut self, fd: Fd, file: OpenFile) {
+        if fd.0 == self.files.len() {
+            self.files.push(Some(file));
+        } else {
+            self.files[fd.0] = Some(file);
+        }
+    }
+
+    fn get_mut(&mut self, fd: Fd) -> Result<&mut OpenFile, FsError> {
+        self.files.get_mut(fd.0).and_then(Option::as_mut).ok_or(FsError::BadDescriptor)
+    }
+
+    fn remove(&mut self, fd: Fd) -> Result<OpenFile, FsError> {
+        let file = self.files.get_mut(fd.0).and_then(Option::take).ok_or(FsError::BadDescriptor)?;
+        while let Some(None) = self.files.last() {
+            self.files.pop();
+        }
+        Ok(file)
+    }
+
+    fn is_empty(&self) -> bool {
+        self.files.is_empty()
+    }
+}
+
+/// Descriptor tables of the tasks that have files open. A `Vec` rather
+/// than a map, so adding a table can fail instead of aborting.
+static TABLES: Mutex<Vec<(TaskId, DescriptorTable)>> = Mutex::new(Vec::new());
+
+fn table_index(tables: &[(TaskId, DescriptorTable)], task: TaskId) -> Option<usize> {
+    tables.iter().position(|(id, _)| *id == task)
+}
+
+/// Runs `f` on the current task's open file `fd`.
+fn with_open_file<T>(fd: Fd, f: impl FnOnce(&mut OpenFile) -> Result<T, FsError>) -> Result<T, FsError> {
+    let mut tables = TABLES.lock();
+    let index = table_index(&tables, TaskId::current()).ok_or(FsError::BadDescriptor)?;
+    f(tables[index].1.get_mut(fd)?)
+}
+
+/// Opens the file at `path` for the current task, at offset 0.
+pub fn open(current_directory: &str, path: &str, flags: OpenFlags) -> Result<Fd, FsError> {
+    if !flags.readable() && !flags.writable() {
+        return Err(FsError::InvalidArgument);
+    }
+    if flags.contains(OpenFlags::TRUNCATE) && !flags.writable() {
+        return Err(FsError::InvalidArgument);
+    }
+
+    let task = TaskId::current();
+    let mut tables = TABLES.lock();
+    let index = match table_index(&tables, task) {
+        Some(index) => index,
+        None => {
+            tables.try_reserve(1)?;
+            tables.push((task, DescriptorTable::default()));
+            tables.len() - 1
+        }
+    };
+    let result = open_into(&mut tables[index].1, current_directory, path, flags);
+    if tables[index].1.is_empty() {
+        tables.swap_remove(index);
+    }
+    result
+}
+
+/// Opens `path` under a free descriptor of `table`. The descriptor is
+/// secured first, so running out of descriptors or memory leaves the file
+/// as it was instead of created or emptied.
+fn open_into(table: &mut DescriptorTable, current_directory: &str, path: &str, flags: OpenFlags)
+             -> Result<Fd, FsError>
+{
+    let fd = table.reserve()?;
+    let file = open_file(current_directory, path, flags.contains(OpenFlags::CREATE))?;
+    if flags.contains(OpenFlags::TRUNCATE) {
+        file.truncate(0)?;
+    }
+    table.fill(fd, OpenFile { file, flags, offset: 0 });
+    Ok(fd)
+}
+
+/// Reads from the offset on into `buf` and moves the offset past what was
+/// read. Returns 0 at the end of the file.
+pub fn read(fd: Fd, buf: &mut [u8]) -> Result<usize, FsError> {
+    with_open_file(fd, |file| file.read(buf))
+}
+
+/// Writes `data` at the offset, or at the end with `APPEND`, and moves the
+/// offset past it.
+pub fn write(fd: Fd, data: &[u8]) -> Result<usize, FsError> {
+    with_open_file(fd, |file| file.write(data))
+}
+
+/// Moves the offset and returns the new one. Seeking past the end is fine;
+/// a write there fills the gap with zeros.
+pub fn seek(fd: Fd, pos: SeekFrom) -> Result<u64, FsError> {
+    with_open_file(fd, |file| file.seek(pos))
+}
+
+/// Cuts the file to `size` bytes or extends it with zeros. The offset
+/// stays where it is.
+pub fn truncate(fd: Fd, size: u64) -> Result<(), FsError> {
+    with_open_file(fd, |file| file.truncate(size))
+}
+
+pub fn close(fd: Fd) -> Result<(), FsError> {
+    let task = TaskId::current();
+    let mut tables = TABLES.lock();
+    let index = table_index(&tables, task).ok_or(FsError::BadDescriptor)?;
+    let file = tables[index].1.remove(fd)?;
+    if tables[index].1.is_empty() {
+        tables.swap_remove(index);
+    }
+    drop(tables);
+    drop(file);
+    Ok(())
+}
+
+/// Whether the descriptor tables are locked, so closing files now would
+/// deadlock.
+pub fn is_locked() -> bool {
+    TABLES.try_lock().is_none()
+}
+
+/// Closes every file `task` has open. The executor calls this when a task
+/// finishes.
+pub fn close_all(task: TaskId) {
+    let mut tables = TABLES.lock();
+    let table = table_index(&tables, task).map(|index| tables.swap_remove(index));
+    drop(tables);
+    // the files are closed with the table unlocked
+    drop(table);
+}
+
+/// Whether `task` has a descriptor table, i.e. files open.
+#[cfg(test)]
+pub fn has_table(task: TaskId) -> bool {
+    table_index(&TABLES.lock(), task).is_some()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ramfs::RamFs;
+    use crate::vfs::FileSystem;
+
+    fn open_file(flags: OpenFlags, data: &[u8]) -> OpenFile {
+        let fs = RamFs::new();
+        fs.create_file("/file", data).unwrap();
+        OpenFile { file: fs.open("/file").unwrap(), flags, offset: 0 }
+    }
+
+    fn insert(table: &mut DescriptorTable, file: OpenFile) -> Result<Fd, FsError> {
+        let fd = table.reserve()?;
+        table.fill(fd, file);
+        Ok(fd)
+    }
+
+    #[test_case]
+    fn reads_and_writes_move_the_offset() {
+        let mut file = open_file(OpenFlags::READ | OpenFlags::WRITE, b"hello world");
+        let mut buf = [0; 5];
+
+        assert_eq!(file.read(&mut buf), Ok(5));
+        assert_eq!(&buf, b"hello");
+        assert_eq!(file.write(b"_"), Ok(1));
+        assert_eq!(file.read(&mut buf), Ok(5));
+        assert_eq!(&buf, b"world");
+        assert_eq!(file.read(&mut buf), Ok(0));
+
+        assert_eq!(file.seek(SeekFrom::Start(0)), Ok(0));
+        assert_eq!(file.read(&mut buf), Ok(5));
+        assert_eq!(file.seek(SeekFrom::Current(1)), Ok(6));
+        assert_eq!(file.seek(SeekFrom::End(-1)), Ok(10));
+        assert_eq!(file.seek(SeekFrom::Current(-11)), Err(FsError::InvalidArgument));
+        assert_eq!(file.file.size(), 11);
+    }
+
+    #[test_case]
+    fn appends_go_to_the_end() {
+        let mut file = open_file(OpenFlags::APPEND, b"log:");
+        file.write(b" one").unwrap();
+        file.seek(SeekFrom::Start(0)).unwrap();
+        file.write(b" two").unwrap();
+
+        let mut buf = [0; 12];
+        assert_eq!(file.file.read_at(0, &mut buf), Ok(12));
+        assert_eq!(&buf, b"log: one two");
+        assert_eq!(file.offset, 12);
+    }
+
+    #[test_case]
+    fn access_is_checked_against_the_flags() {
+        let mut read_only = open_file(OpenFlags::READ, b"data");
+        assert_eq!(read_only.write(b"x"), Err(FsError::BadDescriptor));
+        assert_eq!(read_only.truncate(0), Err(FsError::BadDescriptor));
+
+        let mut write_only = open_file(OpenFlags::WRITE, b"data");
+        assert_eq!(write_only.read(&mut [0; 4]), Err(FsError::BadDescriptor));
+        write_only.truncate(1).unwrap();
+        assert_eq!(write_only.file.size(), 1);
+    }
+
+    #[test_case]
+    fn descriptors_are_reused_lowest_first() {
+        let mut table = DescriptorTable::default();
+        let fds: Vec<Fd> = (0..3).map(|_| insert(&mut table, open_file(OpenFlags::READ, b"")).unwrap()).collect();
+        assert_eq!(fds, [Fd(0), Fd(1), Fd(2)]);
+
+        table.remove(Fd(1)).unwrap();
+        assert!(table.get_mut(Fd(1)).is_err());
+        assert_eq!(table.remove(Fd(1)).err(), Some(FsError::BadDescriptor));
+        assert_eq!(insert(&mut table, open_file(OpenFlags::READ, b"")), Ok(Fd(1)));
+
+        for fd in fds {
+            table.remove(fd).unwrap();
+        }
+        assert!(table.is_empty());
+    }
+
+    #[test_case]
+    fn tables_are_limited() {
+        let mut table = DescriptorTable::default();
+        for _ in 0..MAX_OPEN_FILES {
+            insert(&mut table, open_file(OpenFlags::READ, b"")).unwrap();
+        }
+        assert_eq!(insert(&mut table, open_file(OpenFlags::READ, b"")).err(), Some(FsError::TooManyOpenFiles));
+    }
+
+    #[test_case]
+    fn full_tables_leave_files_alone() {
+        use crate::vfs;
+
+        vfs::mount_root_for_tests();
+        vfs::create_file("/", "/full-table", b"keep").unwrap();
+        let fds: Vec<Fd> = (0..MAX_OPEN_FILES).map(|_| open("/", "/full-table", OpenFlags::READ).unwrap()).collect();
+
+        let truncate = open("/", "/full-table", OpenFlags::WRITE | OpenFlags::TRUNCATE);
+        assert_eq!(truncate.err(), Some(FsError::TooManyOpenFiles));
+        let create = open("/", "/full-table-new", OpenFlags::WRITE | OpenFlags::CREATE);
+        assert_eq!(create.err(), Some(FsError::TooManyOpenFiles));
+        assert_eq!(vfs::read_file("/", "/full-table").unwrap(), b"keep");
+        assert_eq!(vfs::stat("/", "/full-table-new").err(), Some(FsError::NotFound));
+
+        for fd in fds {
+            close(fd).unwrap();
+        }
+        assert!(!has_table(TaskId::current()));
+        vfs::delete("/", "/full-table").unwrap();
+    }
+}
@@ -0,0 +1,93 @@
+# embed-symbols.ps1
+#
+# Fills the kernel's `.ksyms` section with its own symbol table so the panic
+# screen can print function names in backtraces. Run it after `cargo build`
+# and before `cargo bootimage`. See src/ksyms.rs for the table layout.
+#
+# Needs the llvm-tools component: rustup component add llvm-tools-preview
+
+param(
+    [string]$Kernel = "$PSScriptRoot\..\target\x86_64-why_os\debug\why_os"
+)
+
+$ErrorActionPreference = "Stop"
+
+# Must match KSYMS_SIZE in src/ksyms.rs
+$TableSize = 512KB
+# Longer (mostly generic) names get cut so the table fits
+$MaxNameLength = 96
+
+# Locate llvm-nm and llvm-objcopy in the active toolchain
+$sysroot = (& rustc --print sysroot).Trim()
+$hostTriple = ((& rustc -vV) | Where-Object { $_ -like "host:*" }).Split(" ")[1]
+$exe = if ($env:OS -eq "Windows_NT") { ".exe" } else { "" }
+$toolDir = Join-Path $sysroot "lib/rustlib/$hostTriple/bin"
+$nm = Join-Path $toolDir "llvm-nm$exe"
+$objcopy = Join-Path $toolDir "llvm-objcopy$exe"
+
+if (-not (Test-Path $nm) -or -not (Test-Path $objcopy)) {
+    Write-Host "llvm-nm/llvm-objcopy not found, run: rustup component add llvm-tools-preview"
+    exit 1
+}
+
+# Collect function symbols as "address size type name"
+$symbols = @()
+foreach ($line in (& $nm --defined-only --demangle --numeric-sort --print-size $Kernel)) {
+    if ($line -cmatch '^([0-9a-f]+) ([0-9a-f]+) [tTwW] (.+)$') {
+        $name = $Matches[3]
+        if ($name.Length -gt $MaxNameLength) {
+            $name = $name.Substring(0, $MaxNameLength)
+        }
+        $symbols += [pscustomobject]@{
+            Address = [Convert]::ToUInt64($Matches[1], 16)
+            Size    = [Math]::Min([Convert]::ToUInt64($Matches[2], 16), [UInt32]::MaxValue)
+            Name    = $name
+        }
+    }
+}
+$symbols = @($symbols | Sort-Object -Property Address)
+
+# header, entries, then the string table
+$table = New-Object System.IO.MemoryStream
+$writer = New-Object System.IO.BinaryWriter($table)
+$strings = New-Object System.IO.MemoryStream
+
+$writer.Write([System.Text.Encoding]::ASCII.GetBytes("KSYM"))
+$writer.Write([UInt32]$symbols.Count)
+$writer.Write([UInt32](16 + 16 * $symbols.Count))
+$writer.Write([UInt32]0)
+
+foreach ($symbol in $symbols) {
+    $writer.Write([UInt64]$symbol.Address)
+    $writer.Write([UInt32]$symbol.Size)
+    $writer.Write([UInt32]$strings.Length)
+    $bytes = [System.Text.Encoding]::UTF8.GetBytes($symbol.Name)
+    $strings.Write($bytes, 0, $bytes.Length)
+    $strings.WriteByte(0)
+}
+$writer.Write($strings.ToArray())
+$writer.Flush()
+
+if ($table.Length -gt $TableSize) {
+    Write-Host "Symbol table is $($table.Length) bytes, raise KSYMS_SIZE and `$TableSize."
+    exit 1
+}
+$writer.Write((New-Object byte[] ($TableSize - $table.Length)))
+$writer.Flush()
+
+$blob = [System.IO.Path]::GetTempFileName()
+$patched = [System.IO.Path]::GetTempFileName()
+[System.IO.File]::WriteAllBytes($blob, $table.ToArray())
+
+& $objcopy --update-section ".ksyms=$blob" $Kernel $patched
+if ($LASTEXITCODE -ne 0) {
+    Write-Host "llvm-objcopy failed with exit code $LASTEXITCODE"
+    exit $LASTEXITCODE
+}
+
+# Write the result back in place: cargo hard-links the kernel to its copy in
+# deps/, and replacing the file would let the next build undo the patch.
+[System.IO.File]::WriteAllBytes((Resolve-Path $Kernel), [System.IO.File]::ReadAllBytes($patched))
+Remove-Item $blob, $patched
+
+Write-Host "Embedded $($symbols.Count) symbols into $Kernel"